@@ -0,0 +1,759 @@
+use crate::error::{LexError, LexResult};
+use crate::quota::{self, Resource};
+use crate::render;
+use crate::templates::{self, RenderMode, TemplateField, TemplateMetadata, TemplateRevision};
+use crate::{
+    managed_template, roles, save_template, template_history, validate_key, validate_template, KeyString,
+    LegalTemplate, TemplateScope, TEMPLATES,
+};
+use candid::{CandidType, Principal};
+use ic_cdk::api::{canister_self, time};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+// Template bundles
+//
+// A bundle is a JSON document that carries templates from one canister to
+// another, for example from staging to production. Each template travels at
+// its current version with its fields and metadata, its earlier revisions
+// and the clauses it includes. The importing canister replays the revisions
+// in order, so a new template arrives with its history; a template that
+// already exists there only gets the current version as a new revision.
+
+const BUNDLE_FORMAT: &str = "lexai-template-bundle";
+// Version 1 bundles carry no revision history.
+const BUNDLE_FORMAT_VERSION: u32 = 2;
+
+// Most templates one bundle may carry.
+const MAX_BUNDLE_TEMPLATES: usize = 100;
+
+// Most earlier revisions one bundle may carry, over all its templates.
+const MAX_BUNDLE_REVISIONS: usize = 1_000;
+
+// Highest suffix tried when looking for a free id to rename to.
+const MAX_RENAME_SUFFIX: u32 = 1_000;
+
+#[derive(Serialize, Deserialize)]
+struct Bundle {
+    format: String,
+    format_version: u32,
+    // Canister the bundle was exported from.
+    source: String,
+    exported_at: u64,
+    templates: Vec<BundledTemplate>,
+    // Clauses the templates include, directly or through other clauses.
+    clauses: Vec<BundledClause>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundledTemplate {
+    id: String,
+    name: String,
+    template_text: String,
+    fields: Vec<TemplateField>,
+    render_mode: RenderMode,
+    metadata: TemplateMetadata,
+    // Version in the exporting canister.
+    version: u64,
+    scope: TemplateScope,
+    // Earlier revisions, oldest first.
+    #[serde(default)]
+    history: Vec<BundledRevision>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundledRevision {
+    // Version in the exporting canister.
+    version: u64,
+    name: String,
+    template_text: String,
+    fields: Vec<TemplateField>,
+    render_mode: RenderMode,
+    metadata: TemplateMetadata,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BundledClause {
+    id: String,
+    text: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, CandidType, Deserialize)]
+pub enum ConflictStrategy {
+    // Keep the existing template.
+    Skip,
+    // Save the bundled template as a new revision of the existing one.
+    Overwrite,
+    // Import the bundled template under the next free `<id>_<n>`.
+    Rename,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum ImportOutcome {
+    Created { version: u64 },
+    Updated { version: u64 },
+    Renamed { id: String, version: u64 },
+    // The existing template is identical to the bundled one.
+    Unchanged,
+    Skipped { reason: String },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ImportedTemplate {
+    // Id in the bundle.
+    pub id: String,
+    pub outcome: ImportOutcome,
+}
+
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct ImportReport {
+    pub templates: Vec<ImportedTemplate>,
+    pub clauses_created: Vec<String>,
+    pub clauses_updated: Vec<String>,
+    // Bundled clauses that differ from an existing clause that was kept, or
+    // that only an admin could have created.
+    pub clauses_skipped: Vec<String>,
+}
+
+// Exports the given templates, or without ids every template the caller
+// manages, as a JSON bundle.
+pub fn export(principal: Principal, ids: Vec<String>) -> LexResult<String> {
+    let selected: Vec<LegalTemplate> = if ids.is_empty() {
+        TEMPLATES.with(|templates| {
+            templates
+                .borrow()
+                .iter()
+                .map(|(_, template)| template)
+                .filter(|template| template.is_managed_by(principal))
+                .collect()
+        })
+    } else {
+        let ids: BTreeSet<String> = ids.into_iter().collect();
+        ids.iter()
+            .map(|id| managed_template(id, principal))
+            .collect::<LexResult<_>>()?
+    };
+    if selected.len() > MAX_BUNDLE_TEMPLATES {
+        return Err(LexError::validation(format!(
+            "a bundle holds at most {} templates",
+            MAX_BUNDLE_TEMPLATES
+        )));
+    }
+    let mut histories = Vec::new();
+    for template in &selected {
+        let history: Vec<BundledRevision> = template_history(&template.id, principal)?
+            .into_iter()
+            .filter(|revision| revision.version < template.version)
+            .map(BundledRevision::from)
+            .collect();
+        histories.push(history);
+    }
+    if histories.iter().map(Vec::len).sum::<usize>() > MAX_BUNDLE_REVISIONS {
+        return Err(LexError::validation(format!(
+            "a bundle holds at most {} earlier revisions; export fewer templates",
+            MAX_BUNDLE_REVISIONS
+        )));
+    }
+    let clauses = included_clauses(selected.iter().map(|template| template.template_text.as_str()));
+    let bundle = Bundle::new(canister_self().to_text(), time(), selected.into_iter().zip(histories), clauses);
+    serde_json::to_string_pretty(&bundle).map_err(|e| LexError::Internal { message: e.to_string() })
+}
+
+impl Bundle {
+    fn new(
+        source: String,
+        exported_at: u64,
+        templates: impl Iterator<Item = (LegalTemplate, Vec<BundledRevision>)>,
+        clauses: Vec<BundledClause>,
+    ) -> Self {
+        Bundle {
+            format: BUNDLE_FORMAT.to_string(),
+            format_version: BUNDLE_FORMAT_VERSION,
+            source,
+            exported_at,
+            clauses,
+            templates: templates
+                .map(|(template, history)| BundledTemplate {
+                    scope: template.scope(),
+                    id: template.id,
+                    name: template.name,
+                    template_text: template.template_text,
+                    fields: template.fields,
+                    render_mode: template.render_mode,
+                    metadata: template.metadata,
+                    version: template.version,
+                    history,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<TemplateRevision> for BundledRevision {
+    fn from(revision: TemplateRevision) -> Self {
+        BundledRevision {
+            version: revision.version,
+            name: revision.name,
+            template_text: revision.template_text,
+            fields: revision.fields,
+            render_mode: revision.render_mode,
+            metadata: revision.metadata,
+        }
+    }
+}
+
+fn includes(text: &str) -> Vec<String> {
+    render::parse(text).map(|parsed| parsed.includes()).unwrap_or_default()
+}
+
+fn included_clauses<'a>(texts: impl Iterator<Item = &'a str>) -> Vec<BundledClause> {
+    let mut pending: Vec<String> = texts.flat_map(includes).collect();
+    let mut clauses: Vec<BundledClause> = Vec::new();
+    while let Some(id) = pending.pop() {
+        if clauses.iter().any(|clause| clause.id == id) {
+            continue;
+        }
+        if let Some(clause) = templates::clause(&id) {
+            pending.extend(includes(&clause.text));
+            clauses.push(BundledClause {
+                id: clause.id,
+                text: clause.text,
+            });
+        }
+    }
+    clauses.sort_by(|a, b| a.id.cmp(&b.id));
+    clauses
+}
+
+// A template to import, after its earlier revisions, oldest first.
+struct Incoming {
+    template: LegalTemplate,
+    history: Vec<LegalTemplate>,
+}
+
+enum Action {
+    Create(Incoming),
+    // With the length of the text it replaces. The history is not replayed
+    // onto an existing template.
+    Update(LegalTemplate, u64),
+    Rename(Incoming),
+    Unchanged,
+    Skip(&'static str),
+}
+
+// What this canister holds under the id of a template to import.
+enum Existing {
+    // Nothing, or only history that belongs to the template's owner.
+    Free,
+    // A deleted template whose history belongs to another owner.
+    OtherOwner,
+    // A template, and whether the caller may manage it.
+    Template { template: Box<LegalTemplate>, managed: bool },
+}
+
+// Imports a bundle. Templates go to `scope`, or to the scope they had in the
+// bundle; system templates need an admin. Nothing is changed unless the
+// whole bundle is valid and fits the caller's quota.
+pub fn import(
+    principal: Principal,
+    json: &str,
+    strategy: ConflictStrategy,
+    scope: Option<TemplateScope>,
+) -> LexResult<ImportReport> {
+    if principal == Principal::anonymous() {
+        return Err(LexError::Unauthorized);
+    }
+    let bundle = read_bundle(json)?;
+    let admin = roles::is_admin(principal);
+    let mut taken = BTreeSet::new();
+    let incoming = incoming_templates(bundle.templates, scope, principal, admin, &mut taken)?;
+    for clause in &bundle.clauses {
+        templates::validate_clause(&clause.id, &clause.text)?;
+    }
+
+    let mut plan = Vec::new();
+    for incoming in incoming {
+        let bundled_id = incoming.template.id.clone();
+        let existing = existing_template(&incoming.template, principal);
+        let action = plan_action(incoming, existing, strategy, |id| free_id(id, &mut taken, template_id_in_use))?;
+        plan.push((bundled_id, action));
+    }
+
+    // Private templates are charged to the caller up front, so the import
+    // either fits the quota as a whole or changes nothing.
+    let (mut new_templates, mut added_bytes, mut freed_bytes) = (0, 0, 0);
+    for (_, action) in &plan {
+        match action {
+            Action::Create(Incoming { template, .. }) | Action::Rename(Incoming { template, .. })
+                if template.owner.is_some() =>
+            {
+                new_templates += 1;
+                added_bytes += template.template_text.len() as u64;
+            }
+            Action::Update(template, old) if template.owner.is_some() => {
+                added_bytes += (template.template_text.len() as u64).saturating_sub(*old);
+                freed_bytes += old.saturating_sub(template.template_text.len() as u64);
+            }
+            _ => {}
+        }
+    }
+    quota::consume(principal, Resource::StoredBytes, added_bytes)?;
+    if let Err(e) = quota::consume(principal, Resource::Templates, new_templates) {
+        quota::release(principal, Resource::StoredBytes, added_bytes);
+        return Err(e);
+    }
+    quota::release(principal, Resource::StoredBytes, freed_bytes);
+
+    let mut report = ImportReport::default();
+    for (id, action) in plan {
+        let outcome = match action {
+            Action::Create(incoming) => ImportOutcome::Created {
+                version: save_with_history(incoming, principal),
+            },
+            Action::Update(template, _) => ImportOutcome::Updated {
+                version: save_template(template, Some(principal)),
+            },
+            Action::Rename(incoming) => ImportOutcome::Renamed {
+                id: incoming.template.id.clone(),
+                version: save_with_history(incoming, principal),
+            },
+            Action::Unchanged => ImportOutcome::Unchanged,
+            Action::Skip(reason) => ImportOutcome::Skipped {
+                reason: reason.to_string(),
+            },
+        };
+        report.templates.push(ImportedTemplate { id, outcome });
+    }
+
+    // Clauses are shared by every template, so only admins import them, and
+    // only `Overwrite` replaces an existing one: renaming a clause would
+    // break the templates that include it.
+    for bundled in bundle.clauses {
+        let list = match templates::clause(&bundled.id) {
+            Some(existing) if existing.text == bundled.text => continue,
+            None if admin => &mut report.clauses_created,
+            Some(_) if admin && strategy == ConflictStrategy::Overwrite => &mut report.clauses_updated,
+            _ => {
+                report.clauses_skipped.push(bundled.id);
+                continue;
+            }
+        };
+        list.push(bundled.id.clone());
+        templates::set_clause(bundled.id, bundled.text, time(), principal);
+    }
+    Ok(report)
+}
+
+// Parses a bundle and checks its format and size.
+fn read_bundle(json: &str) -> LexResult<Bundle> {
+    let bundle: Bundle =
+        serde_json::from_str(json).map_err(|e| LexError::validation(format!("not a template bundle: {}", e)))?;
+    if bundle.format != BUNDLE_FORMAT || !(1..=BUNDLE_FORMAT_VERSION).contains(&bundle.format_version) {
+        return Err(LexError::validation(format!(
+            "unsupported bundle format {} version {}",
+            bundle.format, bundle.format_version
+        )));
+    }
+    if bundle.templates.len() > MAX_BUNDLE_TEMPLATES {
+        return Err(LexError::validation(format!(
+            "a bundle holds at most {} templates",
+            MAX_BUNDLE_TEMPLATES
+        )));
+    }
+    if bundle.templates.iter().map(|bundled| bundled.history.len()).sum::<usize>() > MAX_BUNDLE_REVISIONS {
+        return Err(LexError::validation(format!(
+            "a bundle holds at most {} earlier revisions",
+            MAX_BUNDLE_REVISIONS
+        )));
+    }
+    Ok(bundle)
+}
+
+// Checks every bundled template and revision and gives them their owner.
+// Fails on the first invalid one. The bundled ids are added to `taken`.
+fn incoming_templates(
+    bundled_templates: Vec<BundledTemplate>,
+    scope: Option<TemplateScope>,
+    principal: Principal,
+    admin: bool,
+    taken: &mut BTreeSet<String>,
+) -> LexResult<Vec<Incoming>> {
+    let mut incoming = Vec::new();
+    for bundled in bundled_templates {
+        let in_template = |e: LexError| match e {
+            LexError::Validation { message } => LexError::validation(format!("template {}: {}", bundled.id, message)),
+            e => e,
+        };
+        if !taken.insert(bundled.id.clone()) {
+            return Err(LexError::validation(format!("template {} appears more than once", bundled.id)));
+        }
+        let owner = match scope.unwrap_or(bundled.scope) {
+            TemplateScope::System if !admin => return Err(LexError::Unauthorized),
+            TemplateScope::System => None,
+            TemplateScope::Private => Some(principal),
+        };
+        let fields = validate_template(&bundled.id, &bundled.template_text, Some(bundled.fields)).map_err(in_template)?;
+        let metadata = bundled.metadata.normalized().map_err(in_template)?;
+        let mut history = Vec::new();
+        for revision in bundled.history {
+            let in_revision = |e: LexError| match e {
+                LexError::Validation { message } => LexError::validation(format!(
+                    "template {} revision {}: {}",
+                    bundled.id, revision.version, message
+                )),
+                e => e,
+            };
+            history.push(LegalTemplate {
+                id: bundled.id.clone(),
+                fields: validate_template(&bundled.id, &revision.template_text, Some(revision.fields))
+                    .map_err(in_revision)?,
+                metadata: revision.metadata.normalized().map_err(in_revision)?,
+                name: revision.name,
+                template_text: revision.template_text,
+                owner,
+                version: 0,
+                render_mode: revision.render_mode,
+            });
+        }
+        incoming.push(Incoming {
+            template: LegalTemplate {
+                id: bundled.id,
+                name: bundled.name,
+                template_text: bundled.template_text,
+                fields,
+                owner,
+                version: 0,
+                render_mode: bundled.render_mode,
+                metadata,
+            },
+            history,
+        });
+    }
+    Ok(incoming)
+}
+
+fn existing_template(template: &LegalTemplate, principal: Principal) -> Existing {
+    match TEMPLATES.with(|templates| templates.borrow().get(&KeyString(template.id.clone()))) {
+        Some(existing) => Existing::Template {
+            managed: existing.is_managed_by(principal),
+            template: Box::new(existing),
+        },
+        None if templates::history_owned_by(&template.id, template.owner) => Existing::Free,
+        None => Existing::OtherOwner,
+    }
+}
+
+// Decides what importing `incoming` does to what is `existing` under its id.
+// `rename` picks the id a renamed template gets.
+fn plan_action(
+    mut incoming: Incoming,
+    existing: Existing,
+    strategy: ConflictStrategy,
+    rename: impl FnOnce(&str) -> LexResult<String>,
+) -> LexResult<Action> {
+    let template = &incoming.template;
+    Ok(match existing {
+        Existing::Free => Action::Create(incoming),
+        Existing::OtherOwner => match strategy {
+            ConflictStrategy::Skip | ConflictStrategy::Overwrite => Action::Skip("the id belongs to another owner"),
+            ConflictStrategy::Rename => {
+                incoming.template.id = rename(&incoming.template.id)?;
+                Action::Rename(incoming)
+            }
+        },
+        Existing::Template { template: existing, .. }
+            if existing.owner == template.owner && existing.same_content(template) =>
+        {
+            Action::Unchanged
+        }
+        Existing::Template {
+            template: existing,
+            managed,
+        } => match strategy {
+            ConflictStrategy::Skip => Action::Skip("a template with this id exists"),
+            ConflictStrategy::Overwrite if !managed => {
+                Action::Skip("the existing template cannot be overwritten by the caller")
+            }
+            ConflictStrategy::Overwrite if existing.owner != template.owner => {
+                Action::Skip("the existing template has a different scope")
+            }
+            ConflictStrategy::Overwrite => Action::Update(incoming.template, existing.template_text.len() as u64),
+            ConflictStrategy::Rename => {
+                incoming.template.id = rename(&incoming.template.id)?;
+                Action::Rename(incoming)
+            }
+        },
+    })
+}
+
+// Saves the bundled revisions, then the template, and returns the template's
+// version. The revisions are only replayed onto an id without history, so a
+// template that was deleted here keeps the history it had.
+fn save_with_history(incoming: Incoming, principal: Principal) -> u64 {
+    let Incoming { template, history } = incoming;
+    if templates::latest_version(&template.id).is_none() {
+        for revision in history {
+            save_template(
+                LegalTemplate {
+                    id: template.id.clone(),
+                    ..revision
+                },
+                Some(principal),
+            );
+        }
+    }
+    save_template(template, Some(principal))
+}
+
+// The first `<id>_<n>` that is not `in_use` and that no other template of
+// the import uses.
+fn free_id(id: &str, taken: &mut BTreeSet<String>, in_use: impl Fn(&str) -> bool) -> LexResult<String> {
+    for n in 2..=MAX_RENAME_SUFFIX {
+        let candidate = format!("{}_{}", id, n);
+        validate_key("renamed template id", &candidate)?;
+        if !taken.contains(&candidate) && !in_use(&candidate) {
+            taken.insert(candidate.clone());
+            return Ok(candidate);
+        }
+    }
+    Err(LexError::validation(format!("no free id to rename template {} to", id)))
+}
+
+// Whether a template, past or present, has the id.
+fn template_id_in_use(id: &str) -> bool {
+    TEMPLATES.with(|templates| templates.borrow().contains_key(&KeyString(id.to_string())))
+        || templates::latest_version(id).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::templates::FieldType;
+
+    fn user() -> Principal {
+        Principal::from_slice(&[1; 29])
+    }
+
+    fn exporter() -> Principal {
+        Principal::from_slice(&[2; 29])
+    }
+
+    fn template(id: &str, text: &str, owner: Option<Principal>) -> LegalTemplate {
+        LegalTemplate {
+            id: id.to_string(),
+            name: "Agreement".to_string(),
+            template_text: text.to_string(),
+            fields: vec![TemplateField::required("party", "Party", FieldType::Party)],
+            owner,
+            version: 3,
+            render_mode: RenderMode::Deterministic,
+            metadata: TemplateMetadata::default(),
+        }
+    }
+
+    fn revision(version: u64, text: &str) -> BundledRevision {
+        BundledRevision {
+            version,
+            name: "Agreement".to_string(),
+            template_text: text.to_string(),
+            fields: vec![TemplateField::required("party", "Party", FieldType::Party)],
+            render_mode: RenderMode::Deterministic,
+            metadata: TemplateMetadata::default(),
+        }
+    }
+
+    fn json(templates: Vec<(LegalTemplate, Vec<BundledRevision>)>) -> String {
+        serde_json::to_string(&Bundle::new("aaaaa-aa".to_string(), 0, templates.into_iter(), vec![])).unwrap()
+    }
+
+    fn read(templates: Vec<(LegalTemplate, Vec<BundledRevision>)>) -> LexResult<Vec<Incoming>> {
+        let bundle = read_bundle(&json(templates))?;
+        incoming_templates(bundle.templates, None, user(), false, &mut BTreeSet::new())
+    }
+
+    fn validation_message<T>(result: LexResult<T>) -> String {
+        match result {
+            Err(LexError::Validation { message }) => message,
+            Err(e) => panic!("expected a validation error, got {:?}", e),
+            Ok(_) => panic!("expected a validation error"),
+        }
+    }
+
+    fn incoming(text: &str) -> Incoming {
+        Incoming {
+            template: LegalTemplate {
+                version: 0,
+                ..template("nda", text, Some(user()))
+            },
+            history: vec![],
+        }
+    }
+
+    fn existing(text: &str, owner: Option<Principal>, managed: bool) -> Existing {
+        Existing::Template {
+            template: Box::new(template("nda", text, owner)),
+            managed,
+        }
+    }
+
+    fn plan(existing: Existing, strategy: ConflictStrategy) -> Action {
+        plan_action(incoming("NDA with {party}."), existing, strategy, |id| Ok(format!("{}_2", id))).unwrap()
+    }
+
+    fn skip_reason(action: Action) -> &'static str {
+        match action {
+            Action::Skip(reason) => reason,
+            _ => panic!("expected the template to be skipped"),
+        }
+    }
+
+    const STRATEGIES: [ConflictStrategy; 3] =
+        [ConflictStrategy::Skip, ConflictStrategy::Overwrite, ConflictStrategy::Rename];
+
+    #[test]
+    fn exported_templates_import_with_their_history_and_clauses() {
+        let exported = template("nda", "NDA with {party}.", Some(exporter()));
+        let history = vec![revision(1, "Draft NDA with {party}."), revision(2, "Second draft with {party}.")];
+        let clauses = vec![BundledClause {
+            id: "governing_law".to_string(),
+            text: "This agreement is governed by the laws of England.".to_string(),
+        }];
+        let bundle = Bundle::new(
+            "aaaaa-aa".to_string(),
+            7,
+            vec![(exported.clone(), history)].into_iter(),
+            clauses,
+        );
+        let bundle = read_bundle(&serde_json::to_string_pretty(&bundle).unwrap()).unwrap();
+        assert_eq!(bundle.exported_at, 7);
+        assert_eq!(bundle.clauses.len(), 1);
+        assert_eq!(bundle.clauses[0].id, "governing_law");
+
+        let mut taken = BTreeSet::new();
+        let incoming = incoming_templates(bundle.templates, None, user(), false, &mut taken).unwrap();
+        assert_eq!(incoming.len(), 1);
+        let imported = &incoming[0];
+        assert_eq!(imported.template.id, "nda");
+        assert!(imported.template.same_content(&exported));
+        // A private template belongs to whoever imports it.
+        assert_eq!(imported.template.owner, Some(user()));
+        let texts: Vec<&str> = imported.history.iter().map(|revision| revision.template_text.as_str()).collect();
+        assert_eq!(texts, ["Draft NDA with {party}.", "Second draft with {party}."]);
+        assert!(imported.history.iter().all(|revision| revision.owner == Some(user())));
+        assert!(taken.contains("nda"));
+    }
+
+    #[test]
+    fn system_templates_need_an_admin() {
+        let bundle = || read_bundle(&json(vec![(template("nda", "NDA with {party}.", None), vec![])])).unwrap();
+        let refused = incoming_templates(bundle().templates, None, user(), false, &mut BTreeSet::new());
+        assert!(matches!(refused, Err(LexError::Unauthorized)));
+        let imported = incoming_templates(bundle().templates, None, user(), true, &mut BTreeSet::new()).unwrap();
+        assert_eq!(imported[0].template.owner, None);
+        let private =
+            incoming_templates(bundle().templates, Some(TemplateScope::Private), user(), false, &mut BTreeSet::new());
+        assert_eq!(private.unwrap()[0].template.owner, Some(user()));
+    }
+
+    #[test]
+    fn one_invalid_template_fails_the_whole_bundle() {
+        let valid = || (template("a", "A with {party}.", Some(user())), vec![]);
+        let empty = (template("b", " ", Some(user())), vec![]);
+        let message = validation_message(read(vec![valid(), empty]));
+        assert_eq!(message, "template b: template text must not be empty");
+
+        let bad_revision = (template("b", "B with {party}.", Some(user())), vec![revision(1, "B with {missing}.")]);
+        let message = validation_message(read(vec![valid(), bad_revision]));
+        assert!(message.starts_with("template b revision 1: "), "{}", message);
+
+        let message = validation_message(read(vec![valid(), valid()]));
+        assert_eq!(message, "template a appears more than once");
+    }
+
+    #[test]
+    fn bundles_of_another_format_or_size_are_refused() {
+        assert!(validation_message(read_bundle("{}")).starts_with("not a template bundle"));
+        let other_format = json(vec![]).replace(BUNDLE_FORMAT, "another-bundle");
+        assert!(validation_message(read_bundle(&other_format)).starts_with("unsupported bundle format"));
+        let future = json(vec![]).replace("\"format_version\":2", "\"format_version\":3");
+        assert!(validation_message(read_bundle(&future)).starts_with("unsupported bundle format"));
+
+        let too_many = (0..=MAX_BUNDLE_TEMPLATES)
+            .map(|n| (template(&format!("t{}", n), "{party}", Some(user())), vec![]))
+            .collect();
+        assert!(validation_message(read_bundle(&json(too_many))).contains("at most 100 templates"));
+    }
+
+    #[test]
+    fn new_ids_are_created_whatever_the_strategy() {
+        for strategy in STRATEGIES {
+            assert!(matches!(plan(Existing::Free, strategy), Action::Create(_)));
+        }
+    }
+
+    #[test]
+    fn identical_templates_are_left_unchanged_whatever_the_strategy() {
+        for strategy in STRATEGIES {
+            let same = existing("NDA with {party}.", Some(user()), true);
+            assert!(matches!(plan(same, strategy), Action::Unchanged));
+        }
+    }
+
+    #[test]
+    fn skip_keeps_the_existing_template() {
+        let action = plan(existing("Old NDA with {party}.", Some(user()), true), ConflictStrategy::Skip);
+        assert_eq!(skip_reason(action), "a template with this id exists");
+    }
+
+    #[test]
+    fn overwrite_updates_a_template_the_caller_manages_in_the_same_scope() {
+        let old = "Old NDA with {party}.";
+        match plan(existing(old, Some(user()), true), ConflictStrategy::Overwrite) {
+            Action::Update(template, replaced) => {
+                assert_eq!(template.template_text, "NDA with {party}.");
+                assert_eq!(replaced, old.len() as u64);
+            }
+            _ => panic!("expected an update"),
+        }
+        let unmanaged = plan(existing(old, Some(exporter()), false), ConflictStrategy::Overwrite);
+        assert_eq!(skip_reason(unmanaged), "the existing template cannot be overwritten by the caller");
+        let system = plan(existing(old, None, true), ConflictStrategy::Overwrite);
+        assert_eq!(skip_reason(system), "the existing template has a different scope");
+        let deleted = plan(Existing::OtherOwner, ConflictStrategy::Overwrite);
+        assert_eq!(skip_reason(deleted), "the id belongs to another owner");
+    }
+
+    #[test]
+    fn rename_imports_under_a_free_id() {
+        for existing in [existing("Old NDA with {party}.", Some(exporter()), false), Existing::OtherOwner] {
+            match plan(existing, ConflictStrategy::Rename) {
+                Action::Rename(incoming) => assert_eq!(incoming.template.id, "nda_2"),
+                _ => panic!("expected a rename"),
+            }
+        }
+        let no_free_id = plan_action(
+            incoming("NDA with {party}."),
+            Existing::OtherOwner,
+            ConflictStrategy::Rename,
+            |_| Err(LexError::validation("no free id")),
+        );
+        assert_eq!(validation_message(no_free_id), "no free id");
+    }
+
+    #[test]
+    fn free_id_skips_ids_in_use_and_taken_by_the_import() {
+        let mut taken: BTreeSet<String> = ["nda".to_string(), "nda_2".to_string()].into();
+        let in_use = |id: &str| id == "nda_3";
+        assert_eq!(free_id("nda", &mut taken, in_use).unwrap(), "nda_4");
+        assert!(taken.contains("nda_4"));
+        assert_eq!(free_id("nda", &mut taken, in_use).unwrap(), "nda_5");
+    }
+
+    #[test]
+    fn free_id_gives_up_without_a_valid_free_id() {
+        let message = validation_message(free_id("nda", &mut BTreeSet::new(), |_| true));
+        assert_eq!(message, "no free id to rename template nda to");
+        let long = "x".repeat(99);
+        assert!(validation_message(free_id(&long, &mut BTreeSet::new(), |_| false)).starts_with("renamed template id"));
+    }
+}
@@ -0,0 +1,288 @@
+use crate::context::ContextConfig;
+use crate::error::{LexError, LexResult};
+use crate::jobs::RetryPolicy;
+use crate::llm::{GenerationConfig, LlmProviderKind, ProviderEndpoint};
+use crate::migrations::{decode_versioned, encode_versioned, Versioned};
+use crate::{CanisterMemory, MEMORY_MANAGER};
+use candid::CandidType;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    storable::Bound,
+    StableCell, Storable,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// Controller-managed settings for the LLM integration. `base_url`, `model`,
+// `context` and `retry` fall back to defaults when unset.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct LlmConfig {
+    pub provider: LlmProviderKind,
+    pub api_key: String,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    pub generation: GenerationConfig,
+    pub context: Option<ContextConfig>,
+    pub retry: Option<RetryPolicy>,
+    // Tried when the primary provider fails with a retryable error.
+    pub fallback: Option<FallbackConfig>,
+}
+
+// Secondary provider. An empty `api_key` reuses the primary key when both
+// use the same provider kind.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct FallbackConfig {
+    pub provider: LlmProviderKind,
+    pub api_key: String,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum FallbackUpdate {
+    Set(FallbackConfig),
+    Clear,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig {
+            provider: LlmProviderKind::Gemini,
+            api_key: String::new(),
+            base_url: None,
+            model: None,
+            generation: GenerationConfig::default(),
+            context: None,
+            retry: None,
+            fallback: None,
+        }
+    }
+}
+
+// Partial update applied by `set_llm_config`; `None` leaves a setting as is.
+// An empty `base_url` or `model` resets it to the provider default.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct LlmConfigUpdate {
+    pub provider: Option<LlmProviderKind>,
+    pub api_key: Option<String>,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    pub generation: Option<GenerationConfig>,
+    pub context: Option<ContextConfig>,
+    pub retry: Option<RetryPolicy>,
+    pub fallback: Option<FallbackUpdate>,
+}
+
+// `LlmConfig` as returned to controllers, with the API key redacted.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct LlmConfigView {
+    pub provider: LlmProviderKind,
+    pub api_key_set: bool,
+    pub api_key_hint: Option<String>,
+    pub base_url: String,
+    pub model: String,
+    pub generation: GenerationConfig,
+    pub context: ContextConfig,
+    pub retry: RetryPolicy,
+    pub fallback: Option<FallbackView>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct FallbackView {
+    pub provider: LlmProviderKind,
+    pub api_key_set: bool,
+    pub api_key_hint: Option<String>,
+    pub base_url: String,
+    pub model: String,
+}
+
+// Layout of `LlmConfig` before the endpoint settings were added.
+#[derive(CandidType, Deserialize)]
+struct LlmConfigV1 {
+    provider: LlmProviderKind,
+}
+
+impl Storable for LlmConfig {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for LlmConfig {
+    const VERSION: u16 = 2;
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        let legacy: LlmConfigV1 = crate::migrations::decode_candid(version, bytes);
+        LlmConfig {
+            provider: legacy.provider,
+            ..LlmConfig::default()
+        }
+    }
+}
+
+thread_local! {
+    static LLM_CONFIG: RefCell<StableCell<LlmConfig, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(8)));
+        StableCell::init(memory, LlmConfig::default()).expect("failed to initialize LLM config")
+    });
+}
+
+pub fn llm_config() -> LlmConfig {
+    LLM_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+fn set_llm_config(config: LlmConfig) {
+    LLM_CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .expect("failed to persist LLM config");
+    });
+}
+
+impl LlmConfig {
+    // Endpoint of the selected provider with the configured overrides applied.
+    pub fn endpoint(&self) -> ProviderEndpoint {
+        let defaults = self.provider.default_endpoint();
+        ProviderEndpoint {
+            base_url: self.base_url.clone().unwrap_or(defaults.base_url),
+            api_key: self.api_key.clone(),
+            model: self.model.clone().unwrap_or(defaults.model),
+        }
+    }
+
+    pub fn view(&self) -> LlmConfigView {
+        let endpoint = self.endpoint();
+        LlmConfigView {
+            provider: self.provider,
+            api_key_set: !self.api_key.is_empty(),
+            api_key_hint: redact(&self.api_key),
+            base_url: endpoint.base_url,
+            model: endpoint.model,
+            generation: self.generation.clone(),
+            context: self.context.clone().unwrap_or_default(),
+            retry: self.retry.clone().unwrap_or_default(),
+            fallback: self.fallback_endpoint().map(|(provider, endpoint)| FallbackView {
+                provider,
+                api_key_set: !endpoint.api_key.is_empty(),
+                api_key_hint: redact(&endpoint.api_key),
+                base_url: endpoint.base_url,
+                model: endpoint.model,
+            }),
+        }
+    }
+
+    // Kind and endpoint of the fallback provider, if one is configured.
+    pub fn fallback_endpoint(&self) -> Option<(LlmProviderKind, ProviderEndpoint)> {
+        let fallback = self.fallback.as_ref()?;
+        let defaults = fallback.provider.default_endpoint();
+        let api_key = if fallback.api_key.is_empty() && fallback.provider == self.provider {
+            self.api_key.clone()
+        } else {
+            fallback.api_key.clone()
+        };
+        let endpoint = ProviderEndpoint {
+            base_url: fallback.base_url.clone().unwrap_or(defaults.base_url),
+            api_key,
+            model: fallback.model.clone().unwrap_or(defaults.model),
+        };
+        Some((fallback.provider, endpoint))
+    }
+}
+
+// Shows only the last four characters of a secret, and only when it is long
+// enough that doing so does not reveal most of it.
+fn redact(secret: &str) -> Option<String> {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() < 12 {
+        return None;
+    }
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    Some(format!("****{}", tail))
+}
+
+pub fn update_llm_config(update: LlmConfigUpdate) -> LexResult<LlmConfigView> {
+    let mut config = llm_config();
+    if let Some(provider) = update.provider {
+        config.provider = provider;
+    }
+    if let Some(api_key) = update.api_key {
+        config.api_key = api_key.trim().to_string();
+    }
+    if let Some(base_url) = update.base_url {
+        config.base_url = normalize_base_url(&base_url)?;
+    }
+    if let Some(model) = update.model {
+        config.model = normalize_model(&model);
+    }
+    if let Some(generation) = update.generation {
+        validate_generation(&generation)?;
+        config.generation = generation;
+    }
+    if let Some(context) = update.context {
+        if context.max_context_tokens < 1_000 || context.summary_max_tokens == 0 {
+            return Err(LexError::validation(
+                "max_context_tokens must be at least 1000 and summary_max_tokens positive",
+            ));
+        }
+        config.context = Some(context);
+    }
+    if let Some(retry) = update.retry {
+        if !(1..=10).contains(&retry.max_attempts)
+            || retry.initial_backoff_ms == 0
+            || retry.max_backoff_ms < retry.initial_backoff_ms
+        {
+            return Err(LexError::validation(
+                "max_attempts must be between 1 and 10 and backoffs positive, with max_backoff_ms >= initial_backoff_ms",
+            ));
+        }
+        config.retry = Some(retry);
+    }
+    match update.fallback {
+        Some(FallbackUpdate::Set(fallback)) => {
+            config.fallback = Some(FallbackConfig {
+                provider: fallback.provider,
+                api_key: fallback.api_key.trim().to_string(),
+                base_url: fallback.base_url.as_deref().map(normalize_base_url).transpose()?.flatten(),
+                model: fallback.model.as_deref().and_then(normalize_model),
+            });
+        }
+        Some(FallbackUpdate::Clear) => config.fallback = None,
+        None => {}
+    }
+    set_llm_config(config.clone());
+    Ok(config.view())
+}
+
+// An empty URL means "use the provider default".
+fn normalize_base_url(base_url: &str) -> LexResult<Option<String>> {
+    let base_url = base_url.trim().trim_end_matches('/').to_string();
+    if base_url.is_empty() {
+        Ok(None)
+    } else if base_url.starts_with("https://") || base_url.starts_with("http://") {
+        Ok(Some(base_url))
+    } else {
+        Err(LexError::validation("base_url must start with https:// or http://"))
+    }
+}
+
+fn normalize_model(model: &str) -> Option<String> {
+    let model = model.trim().to_string();
+    (!model.is_empty()).then_some(model)
+}
+
+fn validate_generation(generation: &GenerationConfig) -> LexResult<()> {
+    if !(0.0..=2.0).contains(&generation.temperature) {
+        return Err(LexError::validation("temperature must be between 0 and 2"));
+    }
+    if generation.top_p.is_some_and(|top_p| !(0.0..=1.0).contains(&top_p)) {
+        return Err(LexError::validation("top_p must be between 0 and 1"));
+    }
+    if generation.max_output_tokens == 0 {
+        return Err(LexError::validation("max_output_tokens must be positive"));
+    }
+    Ok(())
+}
@@ -0,0 +1,241 @@
+use crate::ChatMessage;
+use candid::CandidType;
+use serde::Deserialize;
+
+// Rough size of a token for the models we target. Estimates only need to be
+// conservative enough to keep prompts clear of the provider's context window
+// and the outcall request size limit.
+const CHARS_PER_TOKEN: usize = 4;
+
+// Per-message cost of role markers and turn separators.
+const MESSAGE_OVERHEAD_TOKENS: u32 = 4;
+
+// Controller-tunable limits on how much conversation goes into a prompt.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ContextConfig {
+    // Budget for the system prompt, summary, history and new input combined.
+    pub max_context_tokens: u32,
+    // Output limit for the call that condenses older turns into the summary.
+    pub summary_max_tokens: u32,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            max_context_tokens: 16_000,
+            summary_max_tokens: 1_024,
+        }
+    }
+}
+
+pub fn estimate_text_tokens(text: &str) -> u32 {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN) as u32
+}
+
+pub fn estimate_message_tokens(msg: &ChatMessage) -> u32 {
+    estimate_text_tokens(&msg.content) + MESSAGE_OVERHEAD_TOKENS
+}
+
+// How the unsummarized part of a conversation is split for the next prompt.
+pub struct ContextPlan {
+    // Every message older than `recent`, to fold into the session summary
+    // before replying.
+    pub to_summarize: Vec<ChatMessage>,
+    // Sequence number just past the last message in `to_summarize`.
+    pub summarize_until: u64,
+    // Most recent messages, sent verbatim.
+    pub recent: Vec<ChatMessage>,
+}
+
+// Plans the prompt for `history`, the messages after the current summary
+// starting at sequence number `first_seq`. `reserved` is the estimated size of
+// everything else in the prompt (system prompt, summary and new input).
+//
+// If the whole history fits it is sent as is. Otherwise the newest messages
+// filling half of the remaining budget are kept verbatim and all the rest are
+// scheduled for summarization, so no turn is left out of the prompt. The
+// summarization prompt trims each message to its share of one call (see
+// `summary_cap`), so each reply costs at most one extra outcall.
+pub fn plan_context(history: Vec<ChatMessage>, first_seq: u64, reserved: u32, config: &ContextConfig) -> ContextPlan {
+    let available = config.max_context_tokens.saturating_sub(reserved);
+    let total: u32 = history.iter().map(estimate_message_tokens).sum();
+    if total <= available {
+        return ContextPlan {
+            to_summarize: vec![],
+            summarize_until: first_seq,
+            recent: history,
+        };
+    }
+
+    let recent_budget = available / 2;
+    let mut recent_tokens = 0;
+    let mut recent_start = history.len();
+    while recent_start > 0 {
+        let tokens = estimate_message_tokens(&history[recent_start - 1]);
+        if recent_tokens + tokens > recent_budget {
+            break;
+        }
+        recent_tokens += tokens;
+        recent_start -= 1;
+    }
+
+    let mut to_summarize = history;
+    let recent = to_summarize.split_off(recent_start);
+    ContextPlan {
+        summarize_until: first_seq + to_summarize.len() as u64,
+        to_summarize,
+        recent,
+    }
+}
+
+// Most tokens of text each of `messages` may keep so that all of them fit in
+// `budget` tokens, overhead included. Messages under the cap keep their whole
+// text and leave the rest of their share to longer ones. `None` when every
+// message fits as is.
+pub fn summary_cap(messages: &[ChatMessage], budget: u32) -> Option<u32> {
+    let overhead = MESSAGE_OVERHEAD_TOKENS.saturating_mul(messages.len() as u32);
+    let mut remaining = budget.saturating_sub(overhead);
+    let mut sizes: Vec<u32> = messages.iter().map(|msg| estimate_text_tokens(&msg.content)).collect();
+    sizes.sort_unstable();
+    for (i, size) in sizes.iter().enumerate() {
+        let share = remaining / (sizes.len() - i) as u32;
+        if *size > share {
+            return Some(share);
+        }
+        remaining -= size;
+    }
+    None
+}
+
+// Cuts `text` down to roughly `tokens` tokens.
+pub fn truncate_to_tokens(text: &str, tokens: u32) -> &str {
+    let max_chars = tokens as usize * CHARS_PER_TOKEN;
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A message estimated at `tokens` tokens, overhead included.
+    fn message(n: usize, tokens: u32) -> ChatMessage {
+        let chars = (tokens - MESSAGE_OVERHEAD_TOKENS) as usize * CHARS_PER_TOKEN;
+        let content = format!("{:<width$}", n, width = chars);
+        ChatMessage {
+            role: "user".to_string(),
+            content,
+        }
+    }
+
+    fn history(count: usize, tokens: u32) -> Vec<ChatMessage> {
+        (0..count).map(|n| message(n, tokens)).collect()
+    }
+
+    fn ids(messages: &[ChatMessage]) -> Vec<usize> {
+        messages.iter().map(|msg| msg.content.trim().parse().unwrap()).collect()
+    }
+
+    fn config(max_context_tokens: u32) -> ContextConfig {
+        ContextConfig {
+            max_context_tokens,
+            ..ContextConfig::default()
+        }
+    }
+
+    #[test]
+    fn estimates_round_up_and_count_characters() {
+        assert_eq!(estimate_text_tokens(""), 0);
+        assert_eq!(estimate_text_tokens("abcd"), 1);
+        assert_eq!(estimate_text_tokens("abcde"), 2);
+        assert_eq!(estimate_text_tokens("éééé"), 1);
+        assert_eq!(estimate_message_tokens(&message(0, 10)), 10);
+    }
+
+    #[test]
+    fn history_that_fits_is_sent_as_is() {
+        // 10 messages of 10 tokens fill the budget exactly.
+        let plan = plan_context(history(10, 10), 7, 20, &config(120));
+        assert!(plan.to_summarize.is_empty());
+        assert_eq!(plan.summarize_until, 7);
+        assert_eq!(ids(&plan.recent), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reserved_tokens_shrink_the_budget() {
+        let plan = plan_context(history(10, 10), 0, 21, &config(120));
+        assert!(!plan.to_summarize.is_empty());
+    }
+
+    #[test]
+    fn over_budget_keeps_the_newest_half_and_summarizes_the_rest() {
+        // 20 messages of 8 tokens against a budget of 100: the newest 6 fill
+        // half of it, and the other 14 are all summarized.
+        let plan = plan_context(history(20, 8), 100, 0, &config(100));
+        assert_eq!(ids(&plan.recent), (14..20).collect::<Vec<_>>());
+        assert_eq!(ids(&plan.to_summarize), (0..14).collect::<Vec<_>>());
+        assert_eq!(plan.summarize_until, 114);
+    }
+
+    #[test]
+    fn no_message_is_left_out_of_both_summary_and_prompt() {
+        for count in [11, 30, 200] {
+            let plan = plan_context(history(count, 8), 40, 10, &config(100));
+            let mut sent = ids(&plan.to_summarize);
+            sent.extend(ids(&plan.recent));
+            assert_eq!(sent, (0..count).collect::<Vec<_>>());
+            assert_eq!(plan.summarize_until, 40 + plan.to_summarize.len() as u64);
+        }
+    }
+
+    #[test]
+    fn summary_cap_is_none_when_everything_fits() {
+        // 5 messages of 10 tokens, overhead included.
+        assert_eq!(summary_cap(&history(5, 10), 50), None);
+        assert_eq!(summary_cap(&[], 0), None);
+    }
+
+    #[test]
+    fn summary_cap_leaves_short_messages_whole() {
+        // 100 tokens minus 3 x 4 of overhead leaves 88: the two 6-token
+        // messages keep their text and the long one gets the other 76.
+        let messages = vec![message(0, 10), message(1, 1_000), message(2, 10)];
+        assert_eq!(summary_cap(&messages, 100), Some(76));
+    }
+
+    #[test]
+    fn capped_messages_fit_the_budget() {
+        let messages: Vec<ChatMessage> = (0..40).map(|n| message(n, 5 + 37 * n as u32)).collect();
+        let budget = 2_000;
+        let cap = summary_cap(&messages, budget).unwrap();
+        let total: u32 = messages
+            .iter()
+            .map(|msg| estimate_text_tokens(truncate_to_tokens(&msg.content, cap)) + MESSAGE_OVERHEAD_TOKENS)
+            .sum();
+        assert!(total <= budget, "{} tokens over a budget of {}", total, budget);
+    }
+
+    #[test]
+    fn an_oversized_message_is_still_summarized() {
+        let plan = plan_context(vec![message(0, 1_000), message(1, 8)], 0, 0, &config(100));
+        assert_eq!(ids(&plan.to_summarize), vec![0]);
+        assert_eq!(plan.summarize_until, 1);
+        assert_eq!(ids(&plan.recent), vec![1]);
+    }
+
+    #[test]
+    fn a_budget_used_up_by_reserved_tokens_sends_no_history() {
+        let plan = plan_context(history(3, 8), 0, 500, &config(100));
+        assert!(plan.recent.is_empty());
+        assert_eq!(ids(&plan.to_summarize), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn truncation_counts_characters() {
+        assert_eq!(truncate_to_tokens("abcdefghij", 2), "abcdefgh");
+        assert_eq!(truncate_to_tokens("abc", 2), "abc");
+        assert_eq!(truncate_to_tokens("ééééé", 1), "éééé");
+    }
+}
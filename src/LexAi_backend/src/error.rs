@@ -0,0 +1,71 @@
+use candid::CandidType;
+use serde::Deserialize;
+use std::fmt;
+
+// Error returned by every public endpoint. Clients should match on the
+// variant rather than on the rendered message.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum LexError {
+    NotFound { resource: String, id: String },
+    // The resource is already being worked on; retry once it is done.
+    Busy { resource: String, id: String },
+    Unauthorized,
+    Validation { message: String },
+    UpstreamLlm { status: Option<u16>, reason: String },
+    QuotaExceeded { resource: String },
+    // Too many calls in the current window; try again after the given delay.
+    RateLimited { retry_after_secs: u64 },
+    // The token ledger refused or failed a transfer.
+    PaymentFailed { reason: String },
+    Internal { message: String },
+}
+
+pub type LexResult<T> = Result<T, LexError>;
+
+impl LexError {
+    pub fn not_found(resource: &str, id: &str) -> Self {
+        LexError::NotFound {
+            resource: resource.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    pub fn busy(resource: &str, id: &str) -> Self {
+        LexError::Busy {
+            resource: resource.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        LexError::Validation { message: message.into() }
+    }
+
+    pub fn upstream(status: Option<u16>, reason: impl Into<String>) -> Self {
+        LexError::UpstreamLlm {
+            status,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::NotFound { resource, id } => write!(f, "{} not found: {}", resource, id),
+            LexError::Busy { resource, id } => write!(f, "{} is busy: {}", resource, id),
+            LexError::Unauthorized => write!(f, "Unauthorized"),
+            LexError::Validation { message } => write!(f, "Invalid request: {}", message),
+            LexError::UpstreamLlm { status: Some(status), reason } => {
+                write!(f, "LLM request failed with status {}: {}", status, reason)
+            }
+            LexError::UpstreamLlm { status: None, reason } => write!(f, "LLM request failed: {}", reason),
+            LexError::QuotaExceeded { resource } => write!(f, "Quota exceeded: {}", resource),
+            LexError::RateLimited { retry_after_secs } => {
+                write!(f, "Rate limit exceeded, retry in {} seconds", retry_after_secs)
+            }
+            LexError::PaymentFailed { reason } => write!(f, "Payment failed: {}", reason),
+            LexError::Internal { message } => write!(f, "Internal error: {}", message),
+        }
+    }
+}
@@ -0,0 +1,446 @@
+use crate::error::LexError;
+use crate::llm::{self, OutcallCost, TokenUsage};
+use crate::migrations::{decode_versioned, encode_versioned, Versioned};
+use crate::{CanisterMemory, MEMORY_MANAGER};
+use candid::{CandidType, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    storable::Bound,
+    StableBTreeMap, Storable,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+use ic_cdk_timers::TimerId;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+// Upper bound on outcalls the worker keeps in flight at once.
+const MAX_RUNNING_JOBS: usize = 8;
+
+// How long a finished job is kept after it last changed, for `get_job_status`
+// and diagnostics.
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// Most expired jobs one worker run deletes.
+const MAX_PRUNED_PER_RUN: usize = 100;
+
+// How often and how patiently a job is retried after a transient failure.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RetryPolicy {
+    // Runs per job, including the first one.
+    pub max_attempts: u32,
+    // Wait before the second run; doubled for every further run.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 2_000,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Wait before the run following run number `attempt` (1-based).
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+        Duration::from_millis(self.initial_backoff_ms.saturating_mul(factor).min(self.max_backoff_ms))
+    }
+}
+
+// Work that needs an LLM outcall, recorded before the outcall is made so a
+// failure leaves a trace and the input is never lost.
+#[derive(Clone, CandidType, Deserialize)]
+pub enum JobKind {
+    Chat { session_id: String, input: String },
+    Document {
+        template_id: String,
+        fields: Vec<(String, String)>,
+        // Template revision to render; `None` for jobs queued before
+        // templates were versioned.
+        template_version: Option<u64>,
+    },
+}
+
+// Where a finished job put its output.
+#[derive(Clone, CandidType, Deserialize)]
+pub enum JobResult {
+    // The assistant message at `seq` in the session.
+    ChatReply { session_id: String, seq: u64 },
+    Document { document_id: String },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Succeeded { result: JobResult },
+    Failed { error: LexError },
+}
+
+// One LLM call made while running a job.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Outcall {
+    pub provider: String,
+    pub model: String,
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub error: Option<LexError>,
+    // Optional so that runs recorded before costs were tracked still decode.
+    pub cost: Option<OutcallCost>,
+    pub usage: Option<TokenUsage>,
+}
+
+// One run of a job, kept for diagnostics.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct JobRun {
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub outcalls: Vec<Outcall>,
+    pub error: Option<LexError>,
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub owner: Principal,
+    pub kind: JobKind,
+    pub status: JobStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub runs: Vec<JobRun>,
+    // Runs since the job was last (re)queued; bounded by the retry policy.
+    pub attempts: u32,
+    // A pending job is not started before this time.
+    pub retry_at: Option<u64>,
+    // Credits spent because the plan quota was used up, returned if the job
+    // fails. Optional so that jobs queued before payments existed decode.
+    pub credits_charged: Option<u64>,
+    // Month the job was last charged in, as months since January 1970; a
+    // failed job gives its plan quota back to that month.
+    pub charged_period: u64,
+}
+
+// Layout of `Job` before runs were recorded.
+#[derive(CandidType, Deserialize)]
+struct JobV1 {
+    id: u64,
+    owner: Principal,
+    kind: JobKind,
+    status: JobStatus,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// Layout of `Job` version 2, before the charge month was recorded.
+#[derive(CandidType, Deserialize)]
+struct JobV2 {
+    id: u64,
+    owner: Principal,
+    kind: JobKind,
+    status: JobStatus,
+    created_at: u64,
+    updated_at: u64,
+    runs: Vec<JobRun>,
+    attempts: u32,
+    retry_at: Option<u64>,
+    credits_charged: Option<u64>,
+}
+
+impl Storable for Job {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for Job {
+    const VERSION: u16 = 3;
+    // Older jobs are taken to have been charged in the month they were
+    // created, which is right unless they were retried in a later month.
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        let legacy = match version {
+            0 | 1 => {
+                let legacy: JobV1 = crate::migrations::decode_candid(version, bytes);
+                JobV2 {
+                    id: legacy.id,
+                    owner: legacy.owner,
+                    kind: legacy.kind,
+                    status: legacy.status,
+                    created_at: legacy.created_at,
+                    updated_at: legacy.updated_at,
+                    runs: vec![],
+                    attempts: 0,
+                    retry_at: None,
+                    credits_charged: None,
+                }
+            }
+            _ => crate::migrations::decode_candid(version, bytes),
+        };
+        Job {
+            id: legacy.id,
+            owner: legacy.owner,
+            kind: legacy.kind,
+            status: legacy.status,
+            created_at: legacy.created_at,
+            updated_at: legacy.updated_at,
+            runs: legacy.runs,
+            attempts: legacy.attempts,
+            retry_at: legacy.retry_at,
+            credits_charged: legacy.credits_charged,
+            charged_period: crate::quota::month_index(legacy.created_at),
+        }
+    }
+}
+
+thread_local! {
+    static JOBS: RefCell<StableBTreeMap<u64, Job, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(9)));
+        StableBTreeMap::init(memory)
+    });
+
+    // Ids of jobs that have not finished yet, oldest first.
+    static JOB_QUEUE: RefCell<StableBTreeMap<u64, (), VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(10)));
+        StableBTreeMap::init(memory)
+    });
+
+    // Queued jobs whose outcall is in flight in this canister instance. Kept
+    // on the heap: after an upgrade nothing is in flight, so every queued job,
+    // including one that was running, is picked up again.
+    static RUNNING: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+
+    // The worker's one armed timer and when it is due. Timers do not survive
+    // an upgrade, so `post_upgrade` arms it again.
+    static WORKER_TIMER: Cell<Option<(TimerId, u64)>> = const { Cell::new(None) };
+}
+
+pub fn job(id: u64) -> Option<Job> {
+    JOBS.with(|jobs| jobs.borrow().get(&id))
+}
+
+// Id the next enqueued job will get, so it can be charged for up front.
+pub fn next_id() -> u64 {
+    JOBS.with(|jobs| jobs.borrow().last_key_value().map_or(1, |(id, _)| id + 1))
+}
+
+// Records a pending job and wakes the worker. Returns the job id. The job
+// is charged for in the same call, so in the current month.
+pub fn enqueue(owner: Principal, kind: JobKind, credits_charged: Option<u64>) -> u64 {
+    let now = time();
+    let id = next_id();
+    JOBS.with(|jobs| {
+        jobs.borrow_mut().insert(
+            id,
+            Job {
+                id,
+                owner,
+                kind,
+                status: JobStatus::Pending,
+                created_at: now,
+                updated_at: now,
+                runs: vec![],
+                attempts: 0,
+                retry_at: None,
+                credits_charged,
+                charged_period: crate::quota::current_period(),
+            },
+        );
+    });
+    JOB_QUEUE.with(|queue| queue.borrow_mut().insert(id, ()));
+    schedule_worker();
+    id
+}
+
+// Puts a failed job back in the queue with a fresh set of attempts, charged
+// for again in the current month.
+pub fn requeue(id: u64, credits_charged: Option<u64>) {
+    update_job(id, |job| {
+        job.status = JobStatus::Pending;
+        job.attempts = 0;
+        job.retry_at = None;
+        job.credits_charged = credits_charged;
+        job.charged_period = crate::quota::current_period();
+    });
+    JOB_QUEUE.with(|queue| queue.borrow_mut().insert(id, ()));
+    schedule_worker();
+}
+
+// Runs the worker as soon as possible.
+pub fn schedule_worker() {
+    arm_worker(time());
+}
+
+// Makes sure the worker runs no later than `at`. There is only ever one
+// worker timer: an earlier one is kept, a later one is replaced.
+fn arm_worker(at: u64) {
+    if let Some((timer, due)) = WORKER_TIMER.get() {
+        if due <= at {
+            return;
+        }
+        ic_cdk_timers::clear_timer(timer);
+    }
+    let timer = ic_cdk_timers::set_timer(Duration::from_nanos(at.saturating_sub(time())), run_worker);
+    WORKER_TIMER.set(Some((timer, at)));
+}
+
+fn update_job(id: u64, f: impl FnOnce(&mut Job)) {
+    JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        if let Some(mut job) = jobs.get(&id) {
+            f(&mut job);
+            job.updated_at = time();
+            jobs.insert(id, job);
+        }
+    });
+}
+
+// Starts queued jobs that are due until `MAX_RUNNING_JOBS` are in flight,
+// and arms a timer for the earliest job still waiting out its backoff.
+fn run_worker() {
+    WORKER_TIMER.set(None);
+    let now = time();
+    prune_finished_jobs(now);
+    let free = MAX_RUNNING_JOBS.saturating_sub(RUNNING.with(|running| running.borrow().len()));
+    let mut claimed = Vec::new();
+    let mut next_retry: Option<u64> = None;
+    let waiting: Vec<u64> = JOB_QUEUE.with(|queue| {
+        RUNNING.with(|running| {
+            let running = running.borrow();
+            queue.borrow().iter().map(|(id, _)| id).filter(|id| !running.contains(id)).collect()
+        })
+    });
+    for id in waiting {
+        match job(id) {
+            Some(job) => match job.retry_at {
+                Some(at) if at > now => next_retry = Some(next_retry.map_or(at, |next| next.min(at))),
+                _ if claimed.len() < free => claimed.push(job),
+                _ => {}
+            },
+            None => {
+                JOB_QUEUE.with(|queue| queue.borrow_mut().remove(&id));
+            }
+        }
+    }
+    if let Some(at) = next_retry {
+        arm_worker(at);
+    }
+
+    let policy = crate::config::llm_config().retry.unwrap_or_default();
+    for job in claimed {
+        // Only a run that trapped or was cut short by an upgrade gets here
+        // with its attempts used up.
+        if job.attempts >= policy.max_attempts {
+            update_job(job.id, |job| {
+                job.status = JobStatus::Failed {
+                    error: LexError::Internal {
+                        message: "job was interrupted".to_string(),
+                    },
+                };
+            });
+            JOB_QUEUE.with(|queue| queue.borrow_mut().remove(&job.id));
+            crate::finish_job(&job, false);
+            continue;
+        }
+        let slot = RunningSlot::claim(job.id);
+        update_job(job.id, |job| {
+            job.status = JobStatus::Running;
+            job.attempts += 1;
+        });
+        ic_cdk::futures::spawn(async move {
+            let started_at = time();
+            let mut outcalls = Vec::new();
+            let outcome = crate::run_job(&job, &mut outcalls).await;
+            let attempt = job.attempts + 1;
+            let policy = crate::config::llm_config().retry.unwrap_or_default();
+            let retry = match &outcome {
+                Err(error) if llm::is_retryable(error) && attempt < policy.max_attempts => {
+                    Some(policy.backoff(attempt))
+                }
+                _ => None,
+            };
+            crate::usage::record_job_usage(&job, &outcalls);
+            let succeeded = outcome.is_ok();
+            let run = JobRun {
+                started_at,
+                finished_at: time(),
+                outcalls,
+                error: outcome.as_ref().err().cloned(),
+            };
+            update_job(job.id, |job| {
+                job.runs.push(run);
+                match (outcome, retry) {
+                    (Ok(result), _) => job.status = JobStatus::Succeeded { result },
+                    (Err(error), Some(backoff)) => {
+                        ic_cdk::println!("Job {} attempt {} failed, retrying in {:?}: {}", job.id, attempt, backoff, error);
+                        job.status = JobStatus::Pending;
+                        job.retry_at = Some(time() + backoff.as_nanos() as u64);
+                    }
+                    (Err(error), None) => {
+                        ic_cdk::println!("Job {} failed: {}", job.id, error);
+                        job.status = JobStatus::Failed { error };
+                    }
+                }
+            });
+            if retry.is_none() {
+                JOB_QUEUE.with(|queue| queue.borrow_mut().remove(&job.id));
+                crate::finish_job(&job, succeeded);
+            }
+            drop(slot);
+            if JOB_QUEUE.with(|queue| !queue.borrow().is_empty()) {
+                schedule_worker();
+            }
+        });
+    }
+}
+
+// Deletes jobs that finished more than `FINISHED_JOB_TTL` ago, oldest first.
+// The newest job is always kept, as `next_id` counts on from it.
+fn prune_finished_jobs(now: u64) {
+    let cutoff = now.saturating_sub(FINISHED_JOB_TTL.as_nanos() as u64);
+    JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        let Some((newest, _)) = jobs.last_key_value() else {
+            return;
+        };
+        // Ids grow with creation time, so no later job can have expired.
+        let expired: Vec<u64> = jobs
+            .iter()
+            .take_while(|(id, job)| *id != newest && job.created_at < cutoff)
+            .filter(|(_, job)| {
+                matches!(job.status, JobStatus::Succeeded { .. } | JobStatus::Failed { .. }) && job.updated_at < cutoff
+            })
+            .map(|(id, _)| id)
+            .take(MAX_PRUNED_PER_RUN)
+            .collect();
+        for id in expired {
+            jobs.remove(&id);
+        }
+    });
+}
+
+// Marks a job as in flight for as long as it is alive, so the slot is freed
+// even if the job's callback traps.
+struct RunningSlot(u64);
+
+impl RunningSlot {
+    fn claim(id: u64) -> Self {
+        RUNNING.with(|running| running.borrow_mut().insert(id));
+        RunningSlot(id)
+    }
+}
+
+impl Drop for RunningSlot {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.borrow_mut().remove(&self.0));
+    }
+}
@@ -1,19 +1,53 @@
+// The package name is fixed by dfx.json, which expects `LexAi_backend.wasm`.
+#![allow(non_snake_case)]
+
 use ic_cdk::{
-    api::{canister_self, msg_caller, time},
+    api::{msg_caller, time},
     stable::{stable_size, stable_grow, stable_read, stable_write},
-    management_canister::{http_request, HttpRequestArgs, HttpRequestResult, HttpMethod, HttpHeader, TransformContext, TransformFunc, TransformArgs},
+    management_canister::{HttpRequestResult, HttpHeader, TransformArgs},
 };
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     StableBTreeMap, Memory, Storable, storable::Bound,
 };
-use candid::{CandidType, Principal, Encode, Decode};
+use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use sha2::{Sha256, Digest};
-use hex;
-use serde_json::json;
 use std::borrow::Cow;
+use std::ops::Bound::{Excluded, Unbounded};
+
+mod bundles;
+mod config;
+mod context;
+mod error;
+mod jobs;
+mod limits;
+mod llm;
+mod migrations;
+mod payments;
+mod quota;
+mod render;
+mod roles;
+mod seed;
+mod templates;
+mod usage;
+
+use error::{LexError, LexResult};
+use config::{LlmConfigUpdate, LlmConfigView};
+use jobs::{Job, JobKind, JobResult, JobStatus, Outcall};
+use limits::{BlockEntry, RateLimit};
+use payments::{Payment, PaymentConfig};
+use quota::{Allowance, Plan, Resource};
+use roles::AdminEntry;
+use bundles::{ConflictStrategy, ImportReport};
+use templates::{
+    Clause, ClauseVersion, PlaceholderUse, RenderMode, Rendered, TemplateCategory, TemplateDiff, TemplateField,
+    TemplateMetadata, TemplateRevision, TextPosition,
+};
+use usage::{DailyUsage, UsageEntry, UsageTotals};
+use llm::{LlmMessage, LlmRequest, LlmRole, Projection};
+use migrations::{decode_versioned, encode_versioned, SchemaMeta, Versioned};
 
 // Custom memory type for stable storage
 struct CanisterMemory;
@@ -51,7 +85,7 @@ thread_local! {
 struct KeyString(String);
 impl Storable for KeyString {
     const BOUND: Bound = Bound::Bounded { max_size: 100, is_fixed_size: false };
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Borrowed(self.0.as_bytes())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -63,7 +97,7 @@ impl Storable for KeyString {
 struct KeyPrincipal(Principal);
 impl Storable for KeyPrincipal {
     const BOUND: Bound = Bound::Bounded { max_size: 29, is_fixed_size: false };
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Borrowed(self.0.as_slice())
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
@@ -71,14 +105,55 @@ impl Storable for KeyPrincipal {
     }
 }
 
-struct ValueString(String);
-impl Storable for ValueString {
-    const BOUND: Bound = Bound::Unbounded;
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Borrowed(self.0.as_bytes())
+// Secondary index key: (owner, item id). All items of one principal form a
+// contiguous range, so listing them never scans other users' data.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Clone)]
+struct OwnerKey {
+    owner: Principal,
+    id: String,
+}
+
+impl Storable for OwnerKey {
+    const BOUND: Bound = Bound::Bounded { max_size: 130, is_fixed_size: false };
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let owner = self.owner.as_slice();
+        let mut bytes = vec![owner.len() as u8];
+        bytes.extend_from_slice(owner);
+        bytes.extend_from_slice(self.id.as_bytes());
+        Cow::Owned(bytes)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (owner, id) = bytes[1..].split_at(bytes[0] as usize);
+        OwnerKey {
+            owner: Principal::from_slice(owner),
+            id: String::from_utf8(id.to_vec()).unwrap(),
+        }
+    }
+}
+
+// Per-principal log key: (principal, n) for the principal's n-th entry, so one
+// principal's entries form a contiguous range in the order they were added.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Clone)]
+struct PrincipalSeqKey {
+    principal: Principal,
+    seq: u64,
+}
+
+impl Storable for PrincipalSeqKey {
+    const BOUND: Bound = Bound::Bounded { max_size: 38, is_fixed_size: false };
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let principal = self.principal.as_slice();
+        let mut bytes = vec![principal.len() as u8];
+        bytes.extend_from_slice(principal);
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        Cow::Owned(bytes)
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        ValueString(String::from_utf8(bytes.to_vec()).unwrap())
+        let (principal, seq) = bytes[1..].split_at(bytes[0] as usize);
+        PrincipalSeqKey {
+            principal: Principal::from_slice(principal),
+            seq: u64::from_be_bytes(seq.try_into().unwrap()),
+        }
     }
 }
 
@@ -89,15 +164,47 @@ struct User {
     username: Option<String>,
     email: Option<String>,
     created_at: u64,
+    plan: Plan,
+    // Prepaid balance in ledger base units, spent once the plan quota is
+    // used up.
+    credits: u64,
+}
+
+// Layout of `User` versions 0 and 1. Version 1 records written before plans
+// and credits have neither.
+#[derive(CandidType, Deserialize)]
+struct UserV1 {
+    principal: Principal,
+    username: Option<String>,
+    email: Option<String>,
+    created_at: u64,
+    plan: Option<Plan>,
+    credits: Option<u64>,
 }
 
 impl Storable for User {
     const BOUND: Bound = Bound::Unbounded;
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(&bytes, Self).unwrap()
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for User {
+    const VERSION: u16 = 2;
+    // Users without a plan are on `Free`, and those without credits have none.
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        let legacy: UserV1 = migrations::decode_candid(version, bytes);
+        User {
+            principal: legacy.principal,
+            username: legacy.username,
+            email: legacy.email,
+            created_at: legacy.created_at,
+            plan: legacy.plan.unwrap_or_default(),
+            credits: legacy.credits.unwrap_or(0),
+        }
     }
 }
 
@@ -107,22 +214,140 @@ struct ChatMessage {
     content: String,
 }
 
+impl Storable for ChatMessage {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for ChatMessage {
+    const VERSION: u16 = 1;
+}
+
+// Stored roles are "user" and "assistant"; anything else is treated as user input.
+impl From<&ChatMessage> for LlmMessage {
+    fn from(msg: &ChatMessage) -> Self {
+        let role = if msg.role.eq_ignore_ascii_case("assistant") || msg.role.eq_ignore_ascii_case("model") {
+            LlmRole::Assistant
+        } else {
+            LlmRole::User
+        };
+        LlmMessage {
+            role,
+            content: msg.content.clone(),
+        }
+    }
+}
+
+// Messages are keyed by (session_id, seq) so a session's history is a
+// contiguous range and appending never touches earlier messages.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Clone)]
+struct MessageKey {
+    session_id: String,
+    seq: u64,
+}
+
+impl Storable for MessageKey {
+    const BOUND: Bound = Bound::Bounded { max_size: 108, is_fixed_size: false };
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.seq.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.session_id.as_bytes());
+        Cow::Owned(bytes)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (seq, session_id) = bytes.split_at(8);
+        MessageKey {
+            session_id: String::from_utf8(session_id.to_vec()).unwrap(),
+            seq: u64::from_be_bytes(seq.try_into().unwrap()),
+        }
+    }
+}
+
+// Session header; the messages themselves live in `MESSAGES`.
 #[derive(Clone, CandidType, Deserialize, Serialize)]
 struct Session {
     session_id: String,
     principal: Principal,
     title: Option<String>,
     created_at: u64,
-    messages: Vec<ChatMessage>,
+    message_count: u64,
+    summary: Option<SessionSummary>,
+    // Chat job currently answering this session, if any.
+    pending_job: Option<u64>,
+    // Messages a version 0 or 1 record embedded, until the v2 migration
+    // moves them into `MESSAGES` (see `migrations::migrate_session`); empty
+    // otherwise.
+    legacy_messages: Vec<ChatMessage>,
+}
+
+// Condensed form of a session's earlier turns, sent in place of the messages
+// before `covers_until` once the conversation outgrows the context budget.
+#[derive(Clone, CandidType, Deserialize, Serialize)]
+struct SessionSummary {
+    text: String,
+    covers_until: u64,
+    updated_at: u64,
+}
+
+// A chat turn that has been accepted but not answered yet.
+#[derive(Clone, CandidType, Deserialize)]
+struct PendingReply {
+    job_id: u64,
+    input: String,
+    started_at: u64,
 }
 
 impl Storable for Session {
     const BOUND: Bound = Bound::Unbounded;
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(&bytes, Self).unwrap()
+        decode_versioned(&bytes)
+    }
+}
+
+// Layout of `Session` versions 2 and 3. Records written before summaries
+// and chat jobs have neither field.
+#[derive(CandidType, Deserialize)]
+struct SessionV2 {
+    session_id: String,
+    principal: Principal,
+    title: Option<String>,
+    created_at: u64,
+    message_count: u64,
+    summary: Option<SessionSummary>,
+    pending_job: Option<u64>,
+}
+
+impl Versioned for Session {
+    const VERSION: u16 = 4;
+    // Versions 0 and 1 embedded the messages in the session record. They are
+    // kept in `legacy_messages` until the v2 migration moves them.
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        match version {
+            0 | 1 => {
+                let legacy: migrations::LegacySession = migrations::decode_candid(version, bytes);
+                legacy.into_session()
+            }
+            _ => {
+                let legacy: SessionV2 = migrations::decode_candid(version, bytes);
+                Session {
+                    session_id: legacy.session_id,
+                    principal: legacy.principal,
+                    title: legacy.title,
+                    created_at: legacy.created_at,
+                    message_count: legacy.message_count,
+                    summary: legacy.summary,
+                    pending_job: legacy.pending_job,
+                    legacy_messages: vec![],
+                }
+            }
+        }
     }
 }
 
@@ -131,15 +356,197 @@ struct LegalTemplate {
     id: String,
     name: String,
     template_text: String,
+    // Inputs the template expects, one per placeholder.
+    fields: Vec<TemplateField>,
+    // Creator of a private template; `None` for system templates, which every
+    // user can see and admins manage.
+    owner: Option<Principal>,
+    // Revision currently in effect; see `templates::TemplateRevision`.
+    version: u64,
+    render_mode: RenderMode,
+    metadata: TemplateMetadata,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+enum TemplateScope {
+    System,
+    Private,
+}
+
+// Layout of `LegalTemplate` before templates declared their fields.
+#[derive(CandidType, Deserialize)]
+struct LegalTemplateV1 {
+    id: String,
+    name: String,
+    template_text: String,
+}
+
+// Layout of `LegalTemplate` versions 2 to 4, before metadata. Version 2 had
+// no `version` and versions 2 and 3 no `render_mode`.
+#[derive(CandidType, Deserialize)]
+struct LegalTemplateV4 {
+    id: String,
+    name: String,
+    template_text: String,
+    fields: Vec<TemplateField>,
+    owner: Option<Principal>,
+    version: Option<u64>,
+    render_mode: Option<RenderMode>,
 }
 
 impl Storable for LegalTemplate {
     const BOUND: Bound = Bound::Unbounded;
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for LegalTemplate {
+    const VERSION: u16 = 5;
+    // Templates without declared fields get a required text field per
+    // placeholder. Unversioned templates are at revision 1, which the v5
+    // migration records. Templates that predate render modes were all drafted
+    // by the LLM, and those that predate metadata get the default.
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        let legacy = match version {
+            0 | 1 => {
+                let legacy: LegalTemplateV1 = migrations::decode_candid(version, bytes);
+                LegalTemplateV4 {
+                    fields: templates::fields_from_placeholders(&legacy.template_text).unwrap_or_default(),
+                    id: legacy.id,
+                    name: legacy.name,
+                    template_text: legacy.template_text,
+                    owner: None,
+                    version: None,
+                    render_mode: None,
+                }
+            }
+            _ => migrations::decode_candid(version, bytes),
+        };
+        LegalTemplate {
+            id: legacy.id,
+            name: legacy.name,
+            template_text: legacy.template_text,
+            fields: legacy.fields,
+            owner: legacy.owner,
+            version: legacy.version.unwrap_or(1),
+            render_mode: legacy.render_mode.unwrap_or(RenderMode::LlmAssisted),
+            metadata: TemplateMetadata::default(),
+        }
+    }
+}
+
+// A template together with what its text refers to.
+#[derive(Clone, CandidType, Deserialize)]
+struct TemplateDetails {
+    template: LegalTemplate,
+    placeholders: Vec<PlaceholderUse>,
+    unmatched_braces: Vec<TextPosition>,
+    syntax_error: Option<String>,
+}
+
+// Filters for `search_templates`; a template must match every one given.
+#[derive(Clone, CandidType, Deserialize)]
+struct TemplateQuery {
+    // Words that must all occur in the id, name, description or tags.
+    text: Option<String>,
+    category: Option<TemplateCategory>,
+    jurisdiction: Option<String>,
+    language: Option<String>,
+    // Tags the template must all have.
+    tags: Option<Vec<String>>,
+    scope: Option<TemplateScope>,
+}
+
+// A catalogue entry, without the template's text and fields.
+#[derive(Clone, CandidType, Deserialize)]
+struct TemplateSummary {
+    id: String,
+    name: String,
+    scope: TemplateScope,
+    version: u64,
+    render_mode: RenderMode,
+    metadata: TemplateMetadata,
+}
+
+// Maximum number of templates returned per search page.
+const MAX_TEMPLATES_PER_PAGE: u64 = 100;
+
+// Maximum number of unclaimed document ids returned per page.
+const MAX_DOCUMENTS_PER_PAGE: u64 = 100;
+
+#[derive(Clone, CandidType, Deserialize, Serialize)]
+struct DocumentRecord {
+    owner: Principal,
+    template_id: String,
+    fields: Vec<(String, String)>,
+    created_at: u64,
+    body: String,
+    // Template revision the document was generated from; `None` for
+    // documents that predate template revisions.
+    template_version: Option<u64>,
+    // Clause revisions the document included; empty for documents that
+    // predate clause revisions.
+    clauses: Vec<ClauseVersion>,
+}
+
+// Layout of `DocumentRecord` version 2, before clause versions were recorded.
+#[derive(CandidType, Deserialize)]
+struct DocumentRecordV2 {
+    owner: Principal,
+    template_id: String,
+    fields: Vec<(String, String)>,
+    created_at: u64,
+    body: String,
+    template_version: Option<u64>,
+}
+
+impl Storable for DocumentRecord {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
     }
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(&bytes, Self).unwrap()
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for DocumentRecord {
+    const VERSION: u16 = 3;
+    // Versions 0 (raw UTF-8) and 1 (Candid text) stored only the body. Such
+    // documents have no known owner and are assigned to the management
+    // canister, which can never be a caller. Only admins can read them, with
+    // `list_unclaimed_documents`, until `assign_document` gives them to their
+    // real owner.
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        let body = match version {
+            0 => String::from_utf8_lossy(bytes).into_owned(),
+            1 => migrations::decode_candid(version, bytes),
+            _ => {
+                let legacy: DocumentRecordV2 = migrations::decode_candid(version, bytes);
+                return DocumentRecord {
+                    owner: legacy.owner,
+                    template_id: legacy.template_id,
+                    fields: legacy.fields,
+                    created_at: legacy.created_at,
+                    body: legacy.body,
+                    template_version: legacy.template_version,
+                    clauses: vec![],
+                };
+            }
+        };
+        DocumentRecord {
+            owner: Principal::management_canister(),
+            template_id: String::new(),
+            fields: vec![],
+            created_at: 0,
+            body,
+            template_version: None,
+            clauses: vec![],
+        }
     }
 }
 
@@ -160,166 +567,201 @@ thread_local! {
         StableBTreeMap::init(memory)
     });
 
-    static DOCUMENTS: RefCell<StableBTreeMap<KeyString, ValueString, VirtualMemory<CanisterMemory>>> = RefCell::new({
+    static DOCUMENTS: RefCell<StableBTreeMap<KeyString, DocumentRecord, VirtualMemory<CanisterMemory>>> = RefCell::new({
         let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(3)));
         StableBTreeMap::init(memory)
     });
+
+    // MemoryId 4 holds the schema metadata cell (see `migrations`).
+
+    static MESSAGES: RefCell<StableBTreeMap<MessageKey, ChatMessage, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(5)));
+        StableBTreeMap::init(memory)
+    });
+
+    static SESSION_INDEX: RefCell<StableBTreeMap<OwnerKey, (), VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(6)));
+        StableBTreeMap::init(memory)
+    });
+
+    static DOCUMENT_INDEX: RefCell<StableBTreeMap<OwnerKey, (), VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(7)));
+        StableBTreeMap::init(memory)
+    });
+
+    // Ids of documents stored before documents had owners that no admin has
+    // assigned yet. Filled by the schema v4 migration.
+    static UNCLAIMED_DOCUMENTS: RefCell<StableBTreeMap<KeyString, (), VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(26)));
+        StableBTreeMap::init(memory)
+    });
+}
+
+// Owner Index Helpers
+fn index_insert(index: &RefCell<StableBTreeMap<OwnerKey, (), VirtualMemory<CanisterMemory>>>, owner: Principal, id: &str) {
+    index.borrow_mut().insert(OwnerKey { owner, id: id.to_string() }, ());
+}
+
+fn index_remove(index: &RefCell<StableBTreeMap<OwnerKey, (), VirtualMemory<CanisterMemory>>>, owner: Principal, id: &str) {
+    index.borrow_mut().remove(&OwnerKey { owner, id: id.to_string() });
+}
+
+fn index_ids(index: &RefCell<StableBTreeMap<OwnerKey, (), VirtualMemory<CanisterMemory>>>, owner: Principal) -> Vec<String> {
+    let start = OwnerKey { owner, id: String::new() };
+    index.borrow()
+        .range(start..)
+        .take_while(|(key, _)| key.owner == owner)
+        .map(|(key, _)| key.id)
+        .collect()
 }
 
 #[ic_cdk::init]
 fn init() {
-    TEMPLATES.with(|templates| {
-        let mut map = templates.borrow_mut();
-        map.insert(KeyString("NDA".to_string()), LegalTemplate {
-            id: "NDA".to_string(),
-            name: "Non-Disclosure Agreement".to_string(),
-            template_text: "Generate a non-disclosure agreement with the following details: Disclosing Party: {disclosingParty}, Receiving Party: {receivingParty}, Purpose: {purpose}, Duration: {duration}, Jurisdiction: {jurisdiction}, Effective Date: {effectiveDate}, Confidential Information: {confidentialInformation}, Non-Compete Clause: {nonCompete}, Remedies: {remedies}".to_string(),
-        });
-        map.insert(KeyString("Employment".to_string()), LegalTemplate {
-            id: "Employment".to_string(),
-            name: "Employment Agreement".to_string(),
-            template_text: "Generate an employment agreement with the following details: Employer: {employer}, Employee: {employee}, Position: {position}, Duration: {duration}, Jurisdiction: {jurisdiction}, Salary: {salary}, Start Date: {startDate}, Benefits: {benefits}, Termination Conditions: {termination}".to_string(),
-        });
-        map.insert(KeyString("Service".to_string()), LegalTemplate {
-            id: "Service".to_string(),
-            name: "Service Agreement".to_string(),
-            template_text: "Generate a service agreement with the following details: Service Provider: {serviceProvider}, Client: {client}, Service Description: {serviceDescription}, Duration: {duration}, Jurisdiction: {jurisdiction}, Payment Terms: {paymentTerms}, Start Date: {startDate}, Deliverables: {deliverables}, Termination Clause: {termination}".to_string(),
-        });
-        map.insert(KeyString("Partnership".to_string()), LegalTemplate {
-            id: "Partnership".to_string(),
-            name: "Partnership Agreement".to_string(),
-            template_text: "Generate a partnership agreement with the following details:\n\n**PARTNERSHIP AGREEMENT**\n\nThis Partnership Agreement (the \"Agreement\") is made and entered into on {effectiveDate} by and between {partner1} (\"Partner 1\") and {partner2} (\"Partner 2\"), collectively referred to as the \"Partners\".\n\n**1. PURPOSE**\n\nThe Partners agree to form a partnership for the purpose of {purpose}.\n\n**2. DURATION**\n\nThe term of this Agreement shall commence on {effectiveDate} and continue for {duration}, unless terminated earlier as provided herein.\n\n**3. CONTRIBUTIONS**\n\n(a) Partner 1 shall contribute expertise and resources as agreed.\n(b) Partner 2 shall contribute expertise and resources as agreed.\n\n**4. MANAGEMENT**\n\nThe management structure shall be as follows: {responsibilities}. Decisions shall be made by mutual agreement.\n\n**5. PROFITS AND LOSSES**\n\nThe net profits and losses shall be shared in the proportion of {profitSharing} (Partner 1 / Partner 2).\n\n**6. ACCOUNTING**\n\nThe Partners shall maintain accurate books and records. Financial statements shall be prepared annually. An independent auditor shall audit the accounts annually.\n\n**7. TERMINATION**\n\nThis Agreement may be terminated by mutual agreement or material breach. Upon termination, assets shall be distributed according to profit-sharing ratios.\n\n**8. GOVERNING LAW AND JURISDICTION**\n\nThis Agreement shall be governed by the laws of {jurisdiction}. Disputes shall be resolved by {disputeResolution} in {jurisdiction}.\n\n**9. ENTIRE AGREEMENT**\n\nThis Agreement constitutes the entire agreement between the Partners and supersedes all prior agreements.\n\n**IN WITNESS WHEREOF**, the Partners have executed this Agreement as of {effectiveDate}.\n\n_________________________\nPartner 1: {partner1}\n\n_________________________\nPartner 2: {partner2}\n\nSignature: _________________________\nSignature: _________________________\n\nPrinted Name: {partner1}\nPrinted Name: {partner2}".to_string(),
-        });
-        map.insert(KeyString("Rental".to_string()), LegalTemplate {
-            id: "Rental".to_string(),
-            name: "Rental Agreement".to_string(),
-            template_text: "Generate a rental agreement with the following details: Landlord: {landlord}, Tenant: {tenant}, Property Address: {propertyAddress}, Duration: {duration}, Jurisdiction: {jurisdiction}, Rent Amount: {rentAmount}, Start Date: {startDate}, Security Deposit: {securityDeposit}, Maintenance Terms: {maintenance}".to_string(),
-        });
-        map.insert(KeyString("Purchase".to_string()), LegalTemplate {
-            id: "Purchase".to_string(),
-            name: "Purchase Agreement".to_string(),
-            template_text: "Generate a purchase agreement with the following details: Seller: {seller}, Buyer: {buyer}, Item/Service: {itemService}, Duration: {duration}, Jurisdiction: {jurisdiction}, Purchase Price: {purchasePrice}, Delivery Date: {deliveryDate}, Payment Terms: {paymentTerms}, Warranties: {warranties}".to_string(),
-        });
-    });
+    migrations::init_schema();
+    seed::reconcile();
 }
 
+// System templates and the caller's own.
 #[ic_cdk::query]
-fn list_templates() -> Vec<(String, String)> {
+fn list_templates() -> LexResult<Vec<(String, String)>> {
+    let principal = msg_caller();
     TEMPLATES.with(|templates| {
         let map = templates.borrow();
-        let templates_list: Vec<(String, String)> = map.iter().map(|(id, t)| (id.0.clone(), t.name.clone())).collect();
-        ic_cdk::println!("Returning templates: {:?}", templates_list); // Debug log
-        templates_list
+        let templates_list: Vec<(String, String)> = map
+            .iter()
+            .filter(|(_, t)| t.is_visible_to(principal))
+            .map(|(id, t)| (id.0.clone(), t.name.clone()))
+            .collect();
+        Ok(templates_list)
     })
 }
 
+// Templates the caller can see that match `query`. With search text the
+// best matches come first, otherwise templates are ordered by name.
 #[ic_cdk::query]
-fn list_documents() -> Vec<String> {
+fn search_templates(query: TemplateQuery, start: u64, limit: Option<u64>) -> LexResult<Vec<TemplateSummary>> {
     let principal = msg_caller();
-    DOCUMENTS.with(|documents| {
-        let map = documents.borrow();
-        map.iter()
-            .filter(|(id, _)| {
-                id.0.starts_with("doc_") 
+    let limit = limit.unwrap_or(MAX_TEMPLATES_PER_PAGE).min(MAX_TEMPLATES_PER_PAGE) as usize;
+    let terms = query.text.as_deref().map(templates::search_terms).unwrap_or_default();
+    let tags: Vec<String> = query.tags.iter().flatten().map(|tag| tag.trim().to_lowercase()).collect();
+    let mut matches: Vec<(u32, LegalTemplate)> = TEMPLATES.with(|templates| {
+        templates
+            .borrow()
+            .iter()
+            .filter(|(_, t)| {
+                let metadata = &t.metadata;
+                t.is_visible_to(principal)
+                    && query.scope.is_none_or(|scope| scope == t.scope())
+                    && query.category.is_none_or(|category| category == metadata.category)
+                    && query.jurisdiction.as_deref().is_none_or(|j| metadata.covers_jurisdiction(j))
+                    && query.language.as_deref().is_none_or(|language| metadata.has_language(language))
+                    && tags.iter().all(|tag| metadata.tags.contains(tag))
             })
-            .map(|(id, _)| id.0.clone())
+            .filter_map(|(_, t)| Some((templates::relevance(&terms, &t.id, &t.name, &t.metadata)?, t)))
             .collect()
-    })
+    });
+    matches.sort_by(|(a_score, a), (b_score, b)| templates::by_relevance((*a_score, &a.name), (*b_score, &b.name)));
+    Ok(matches
+        .into_iter()
+        .skip(start as usize)
+        .take(limit)
+        .map(|(_, t)| t.summary())
+        .collect())
+}
+
+#[ic_cdk::query]
+fn list_documents() -> LexResult<Vec<String>> {
+    // Documents generated before the owner index existed were stored without
+    // an owner, so they are only listed once an admin assigns them.
+    Ok(DOCUMENT_INDEX.with(|index| index_ids(index, msg_caller())))
 }
 
 
 #[ic_cdk::query]
-fn get_templates_count() -> u64 {
+fn get_templates_count() -> LexResult<u64> {
+    let principal = msg_caller();
     TEMPLATES.with(|templates| {
         let map = templates.borrow();
-        map.len()
+        Ok(map.iter().filter(|(_, t)| t.is_visible_to(principal)).count() as u64)
     })
 }
 
+// Restores the built-in templates, overriding admin edits and deletions.
 #[ic_cdk::update]
-fn init_templates() {
-    TEMPLATES.with(|templates| {
-        let mut map = templates.borrow_mut();
-        map.insert(KeyString("NDA".to_string()), LegalTemplate {
-            id: "NDA".to_string(),
-            name: "Non-Disclosure Agreement".to_string(),
-            template_text: "Generate a non-disclosure agreement with the following details: Disclosing Party: {disclosingParty}, Receiving Party: {receivingParty}, Purpose: {purpose}, Duration: {duration}, Jurisdiction: {jurisdiction}, Effective Date: {effectiveDate}, Confidential Information: {confidentialInformation}, Non-Compete Clause: {nonCompete}, Remedies: {remedies}".to_string(),
-        });
-        map.insert(KeyString("Employment".to_string()), LegalTemplate {
-            id: "Employment".to_string(),
-            name: "Employment Agreement".to_string(),
-            template_text: "Generate an employment agreement with the following details: Employer: {employer}, Employee: {employee}, Position: {position}, Duration: {duration}, Jurisdiction: {jurisdiction}, Salary: {salary}, Start Date: {startDate}, Benefits: {benefits}, Termination Conditions: {termination}".to_string(),
-        });
-        map.insert(KeyString("Service".to_string()), LegalTemplate {
-            id: "Service".to_string(),
-            name: "Service Agreement".to_string(),
-            template_text: "Generate a service agreement with the following details: Service Provider: {serviceProvider}, Client: {client}, Service Description: {serviceDescription}, Duration: {duration}, Jurisdiction: {jurisdiction}, Payment Terms: {paymentTerms}, Start Date: {startDate}, Deliverables: {deliverables}, Termination Clause: {termination}".to_string(),
-        });
-        map.insert(KeyString("Partnership".to_string()), LegalTemplate {
-            id: "Partnership".to_string(),
-            name: "Partnership Agreement".to_string(),
-            template_text: "Generate a partnership agreement with the following details:\n\n**PARTNERSHIP AGREEMENT**\n\nThis Partnership Agreement (the \"Agreement\") is made and entered into on {effectiveDate} by and between {partner1} (\"Partner 1\") and {partner2} (\"Partner 2\"), collectively referred to as the \"Partners\".\n\n**1. PURPOSE**\n\nThe Partners agree to form a partnership for the purpose of {purpose}.\n\n**2. DURATION**\n\nThe term of this Agreement shall commence on {effectiveDate} and continue for {duration}, unless terminated earlier as provided herein.\n\n**3. CONTRIBUTIONS**\n\n(a) Partner 1 shall contribute expertise and resources as agreed.\n(b) Partner 2 shall contribute expertise and resources as agreed.\n\n**4. MANAGEMENT**\n\nThe management structure shall be as follows: {responsibilities}. Decisions shall be made by mutual agreement.\n\n**5. PROFITS AND LOSSES**\n\nThe net profits and losses shall be shared in the proportion of {profitSharing} (Partner 1 / Partner 2).\n\n**6. ACCOUNTING**\n\nThe Partners shall maintain accurate books and records. Financial statements shall be prepared annually. An independent auditor shall audit the accounts annually.\n\n**7. TERMINATION**\n\nThis Agreement may be terminated by mutual agreement or material breach. Upon termination, assets shall be distributed according to profit-sharing ratios.\n\n**8. GOVERNING LAW AND JURISDICTION**\n\nThis Agreement shall be governed by the laws of {jurisdiction}. Disputes shall be resolved by {disputeResolution} in {jurisdiction}.\n\n**9. ENTIRE AGREEMENT**\n\nThis Agreement constitutes the entire agreement between the Partners and supersedes all prior agreements.\n\n**IN WITNESS WHEREOF**, the Partners have executed this Agreement as of {effectiveDate}.\n\n_________________________\nPartner 1: {partner1}\n\n_________________________\nPartner 2: {partner2}\n\nSignature: _________________________\nSignature: _________________________\n\nPrinted Name: {partner1}\nPrinted Name: {partner2}".to_string(),
-        });
-        map.insert(KeyString("Rental".to_string()), LegalTemplate {
-            id: "Rental".to_string(),
-            name: "Rental Agreement".to_string(),
-            template_text: "Generate a rental agreement with the following details: Landlord: {landlord}, Tenant: {tenant}, Property Address: {propertyAddress}, Duration: {duration}, Jurisdiction: {jurisdiction}, Rent Amount: {rentAmount}, Start Date: {startDate}, Security Deposit: {securityDeposit}, Maintenance Terms: {maintenance}".to_string(),
-        });
-        map.insert(KeyString("Purchase".to_string()), LegalTemplate {
-            id: "Purchase".to_string(),
-            name: "Purchase Agreement".to_string(),
-            template_text: "Generate a purchase agreement with the following details: Seller: {seller}, Buyer: {buyer}, Item/Service: {itemService}, Duration: {duration}, Jurisdiction: {jurisdiction}, Purchase Price: {purchasePrice}, Delivery Date: {deliveryDate}, Payment Terms: {paymentTerms}, Warranties: {warranties}".to_string(),
-        });
-    });
+fn init_templates() -> LexResult<()> {
+    ensure_admin()?;
+    seed::reset();
+    Ok(())
+}
+
+
+// Upgrade Hooks
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    migrations::record_pre_upgrade();
 }
 
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrations::resume_migrations();
+    seed::reconcile();
+    // Jobs whose outcall was cut short by the upgrade are still queued.
+    jobs::schedule_worker();
+}
+
+#[ic_cdk::query]
+fn get_schema_status() -> LexResult<SchemaMeta> {
+    Ok(migrations::schema_meta())
+}
 
 // User Management Functions
 #[ic_cdk::update]
-fn get_or_register_user() -> User {
+fn get_or_register_user() -> LexResult<User> {
     let principal = msg_caller();
     let now = time();
 
     USERS.with(|users| {
         let mut map = users.borrow_mut();
         let key = KeyPrincipal(principal);
-        if let Some(user) = map.get(&key).map(|u| u.clone()) {
-            user
+        if let Some(user) = map.get(&key) {
+            Ok(user)
         } else {
             let new_user = User {
                 principal,
                 username: None,
                 email: None,
                 created_at: now,
+                plan: Plan::Free,
+                credits: 0,
             };
             map.insert(key, new_user.clone());
-            new_user
+            Ok(new_user)
         }
     })
 }
 
 #[ic_cdk::update]
-fn update_profile(username: Option<String>, email: Option<String>) {
+fn update_profile(username: Option<String>, email: Option<String>) -> LexResult<()> {
     let principal = msg_caller();
     USERS.with(|users| {
         let mut map = users.borrow_mut();
         let key = KeyPrincipal(principal);
-        if let Some(user) = map.get(&key).map(|u| u.clone()) {
-            let mut updated_user = user;
-            if let Some(un) = username {
-                updated_user.username = Some(un);
-            }
-            if let Some(em) = email {
-                updated_user.email = Some(em);
-            }
-            map.insert(key, updated_user);
+        let mut updated_user = map.get(&key)
+            .ok_or_else(|| LexError::not_found("User", &principal.to_text()))?;
+        if let Some(un) = username {
+            updated_user.username = Some(un);
         }
+        if let Some(em) = email {
+            updated_user.email = Some(em);
+        }
+        map.insert(key, updated_user);
+        Ok(())
     })
 }
 
 // Session Management Functions
 #[ic_cdk::update]
-fn start_session(title: Option<String>) -> String {
+fn start_session(title: Option<String>) -> LexResult<String> {
     let principal = msg_caller();
     let now = time();
     let mut hasher = Sha256::new();
@@ -333,340 +775,1151 @@ fn start_session(title: Option<String>) -> String {
         principal,
         title,
         created_at: now,
-        messages: vec![],
+        message_count: 0,
+        summary: None,
+        pending_job: None,
+        legacy_messages: vec![],
     };
 
     SESSIONS.with(|sessions| {
         let mut map = sessions.borrow_mut();
         map.insert(KeyString(session_id.clone()), session);
     });
+    SESSION_INDEX.with(|index| index_insert(index, principal, &session_id));
 
-    session_id
+    Ok(session_id)
 }
 
+// Queues a reply to `input` and returns the job id; poll `get_job_status`
+// for the outcome.
 #[ic_cdk::update]
-async fn chat_in_session(session_id: String, input: String) -> String {
+fn chat_in_session(session_id: String, input: String) -> LexResult<u64> {
     let principal = msg_caller();
-    let session_opt = SESSIONS.with(|sessions| {
-        let map = sessions.borrow();
-        map.get(&KeyString(session_id.clone())).map(|s| s.clone())
+    owned_session(&session_id, principal)?;
+    if input.trim().is_empty() {
+        return Err(LexError::validation("message must not be empty"));
+    }
+
+    let context_config = config::llm_config().context.unwrap_or_default();
+    if context::estimate_text_tokens(&input) > context_config.max_context_tokens / 2 {
+        return Err(LexError::validation("message is too long"));
+    }
+
+    migrations::migrate_session(&session_id);
+    let mut session = owned_session(&session_id, principal)?;
+    // One reply per session at a time; a double-submitted message is rejected
+    // instead of racing the first one.
+    if session.pending_job.is_some() {
+        return Err(LexError::busy("Session", &session_id));
+    }
+    quota::ensure_available(principal, Resource::StoredBytes)?;
+    limits::admit_paid_call(principal, "chat_in_session")?;
+    let credits_charged = payments::charge(principal, Resource::Messages, jobs::next_id())
+        .inspect_err(|_| limits::release_paid_call(principal, "chat_in_session"))?;
+    let job_id = jobs::enqueue(
+        principal,
+        JobKind::Chat { session_id: session_id.clone(), input },
+        credits_charged,
+    );
+    session.pending_job = Some(job_id);
+    SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(KeyString(session_id), session);
     });
+    Ok(job_id)
+}
 
-    if let Some(session) = session_opt {
-        if session.principal != principal {
-            return "Unauthorized".to_string();
-        }
-        let mut prompt = String::new();
-        for msg in &session.messages {
-            prompt.push_str(&format!("{}: {}\n", msg.role, msg.content));
-        }
-        prompt.push_str(&format!("User: {}\n", input));
-        let reply = query_gemini_api(&prompt).await;
-        let user_msg = ChatMessage {
-            role: "user".to_string(),
-            content: input,
-        };
-        let assistant_msg = ChatMessage {
-            role: "assistant".to_string(),
-            content: reply.clone(),
+async fn run_chat(
+    owner: Principal,
+    session_id: &str,
+    input: &str,
+    outcalls: &mut Vec<Outcall>,
+) -> LexResult<JobResult> {
+    let session = owned_session(session_id, owner)?;
+    let context_config = config::llm_config().context.unwrap_or_default();
+    let mut summary = session.summary.clone();
+    let first_seq = summary.as_ref().map_or(0, |s| s.covers_until);
+    let history = session_messages(session_id, first_seq, None);
+    let reserved = context::estimate_text_tokens(CHAT_SYSTEM_PROMPT)
+        + summary.as_ref().map_or(0, |s| context::estimate_text_tokens(&s.text))
+        + context::estimate_text_tokens(input);
+    let plan = context::plan_context(history, first_seq, reserved, &context_config);
+    if !plan.to_summarize.is_empty() {
+        let text = summarize_messages(summary.as_ref(), &plan.to_summarize, &context_config, outcalls).await?;
+        let updated = SessionSummary {
+            text,
+            covers_until: plan.summarize_until,
+            updated_at: time(),
         };
-        let mut updated_session = session;
-        updated_session.messages.push(user_msg);
-        updated_session.messages.push(assistant_msg);
-        SESSIONS.with(|sessions| {
-            let mut map = sessions.borrow_mut();
-            map.insert(KeyString(session_id), updated_session);
-        });
-        reply
-    } else {
-        "Session not found".to_string()
+        store_session_summary(session_id, updated.clone());
+        summary = Some(updated);
     }
+
+    let system_prompt = match &summary {
+        Some(summary) => format!("{}\n\nSummary of the earlier conversation:\n{}", CHAT_SYSTEM_PROMPT, summary.text),
+        None => CHAT_SYSTEM_PROMPT.to_string(),
+    };
+    let mut conversation: Vec<LlmMessage> = plan.recent.iter().map(LlmMessage::from).collect();
+    conversation.push(LlmMessage::user(input.to_string()));
+    let reply = query_llm(&system_prompt, conversation, None, Projection::ReplyWithUsage, outcalls).await?;
+    let user_msg = ChatMessage {
+        role: "user".to_string(),
+        content: input.to_string(),
+    };
+    let assistant_msg = ChatMessage {
+        role: "assistant".to_string(),
+        content: reply,
+    };
+    let stored_bytes = (user_msg.content.len() + assistant_msg.content.len()) as u64;
+    let message_count = append_messages(session_id, vec![user_msg, assistant_msg])
+        .ok_or_else(|| LexError::not_found("Session", session_id))?;
+    quota::record(owner, Resource::StoredBytes, stored_bytes);
+    Ok(JobResult::ChatReply {
+        session_id: session_id.to_string(),
+        seq: message_count - 1,
+    })
 }
 
-#[ic_cdk::query]
-fn list_sessions() -> Vec<(String, Option<String>, u64)> {
-    let principal = msg_caller();
+// Folds `messages` into the previous summary, if any, with one LLM call.
+async fn summarize_messages(
+    previous: Option<&SessionSummary>,
+    messages: &[ChatMessage],
+    context_config: &context::ContextConfig,
+    outcalls: &mut Vec<Outcall>,
+) -> LexResult<String> {
+    let mut transcript = String::new();
+    if let Some(previous) = previous {
+        transcript.push_str(&format!("Summary so far:\n{}\n\n", previous.text));
+    }
+    transcript.push_str("Conversation to add:\n");
+    // Every message gets a share of what is left of the budget, so the whole
+    // conversation fits one call.
+    let reserved = context::estimate_text_tokens(SUMMARY_SYSTEM_PROMPT) + context::estimate_text_tokens(&transcript);
+    let cap = context::summary_cap(messages, context_config.max_context_tokens.saturating_sub(reserved));
+    for msg in messages {
+        let content = cap.map_or(msg.content.as_str(), |cap| context::truncate_to_tokens(&msg.content, cap));
+        transcript.push_str(&format!("{}: {}\n", msg.role, content));
+    }
+    query_llm(
+        SUMMARY_SYSTEM_PROMPT,
+        vec![LlmMessage::user(transcript)],
+        Some(context_config.summary_max_tokens),
+        Projection::ReplyWithUsage,
+        outcalls,
+    )
+    .await
+}
+
+// Replaces a session's summary unless a newer one was stored in the meantime.
+fn store_session_summary(session_id: &str, summary: SessionSummary) {
+    let key = KeyString(session_id.to_string());
     SESSIONS.with(|sessions| {
-        let map = sessions.borrow();
-        map.iter()
-            .filter(|(_, s)| s.principal == principal)
-            .map(|(id, s)| (id.0.clone(), s.title.clone(), s.created_at))
-            .collect()
-    })
+        let mut map = sessions.borrow_mut();
+        if let Some(mut session) = map.get(&key) {
+            let current = session.summary.as_ref().map_or(0, |s| s.covers_until);
+            if summary.covers_until > current {
+                session.summary = Some(summary);
+                map.insert(key, session);
+            }
+        }
+    });
+}
+
+#[ic_cdk::query]
+fn get_session_summary(session_id: String) -> LexResult<Option<SessionSummary>> {
+    Ok(owned_session(&session_id, msg_caller())?.summary)
+}
+
+#[ic_cdk::query]
+fn get_pending_reply(session_id: String) -> LexResult<Option<PendingReply>> {
+    let session = owned_session(&session_id, msg_caller())?;
+    let pending = session.pending_job.and_then(jobs::job).and_then(|job| match job.kind {
+        JobKind::Chat { input, .. } => Some(PendingReply {
+            job_id: job.id,
+            input,
+            started_at: job.created_at,
+        }),
+        JobKind::Document { .. } => None,
+    });
+    Ok(pending)
+}
+
+// Loads a session header, checking that it belongs to `principal`.
+fn owned_session(session_id: &str, principal: Principal) -> LexResult<Session> {
+    let session = SESSIONS.with(|sessions| sessions.borrow().get(&KeyString(session_id.to_string())))
+        .ok_or_else(|| LexError::not_found("Session", session_id))?;
+    if session.principal != principal {
+        return Err(LexError::Unauthorized);
+    }
+    Ok(session)
 }
 
 #[ic_cdk::query]
-fn get_session_messages(session_id: String) -> Vec<ChatMessage> {
+fn list_sessions() -> LexResult<Vec<(String, Option<String>, u64)>> {
     let principal = msg_caller();
     SESSIONS.with(|sessions| {
         let map = sessions.borrow();
-        if let Some(session) = map.get(&KeyString(session_id)) {
-            if session.principal == principal {
-                session.messages.clone()
-            } else {
-                vec![]
-            }
-        } else {
-            vec![]
+        // Until the v3 backfill completes the index may be missing sessions.
+        if !migrations::schema_at_least(3) {
+            return Ok(map.iter()
+                .filter(|(_, s)| s.principal == principal)
+                .map(|(id, s)| (id.0.clone(), s.title.clone(), s.created_at))
+                .collect());
         }
+        Ok(SESSION_INDEX.with(|index| index_ids(index, principal))
+            .into_iter()
+            .filter_map(|id| map.get(&KeyString(id)))
+            .map(|s| (s.session_id.clone(), s.title.clone(), s.created_at))
+            .collect())
     })
 }
 
-#[ic_cdk::update]
-fn rename_session(session_id: String, new_title: String) -> bool {
-    let principal = msg_caller();
-    SESSIONS.with(|sessions| {
-        let mut map = sessions.borrow_mut();
-        if let Some(session) = map.get(&KeyString(session_id.clone())).map(|s| s.clone()) {
-            if session.principal != principal {
-                return false;
-            }
-            let mut updated_session = session;
-            updated_session.title = Some(new_title);
-            map.insert(KeyString(session_id), updated_session);
-            true
-        } else {
-            false
-        }
+#[ic_cdk::query]
+fn get_session_messages(session_id: String) -> LexResult<Vec<ChatMessage>> {
+    get_session_messages_page(session_id, 0, None)
+}
+
+#[ic_cdk::query]
+fn get_session_messages_page(session_id: String, start: u64, limit: Option<u64>) -> LexResult<Vec<ChatMessage>> {
+    owned_session(&session_id, msg_caller())?;
+    Ok(session_messages(&session_id, start, limit))
+}
+
+// Reads up to `limit` messages of a session starting at sequence number `start`.
+fn session_messages(session_id: &str, start: u64, limit: Option<u64>) -> Vec<ChatMessage> {
+    let limit = limit.unwrap_or(u64::MAX) as usize;
+    if let Some(messages) = migrations::legacy_session_messages(session_id) {
+        return messages.into_iter().skip(start as usize).take(limit).collect();
+    }
+    MESSAGES.with(|messages| {
+        let map = messages.borrow();
+        let from = MessageKey { session_id: session_id.to_string(), seq: start };
+        let to = MessageKey { session_id: session_id.to_string(), seq: u64::MAX };
+        map.range(from..=to).take(limit).map(|(_, msg)| msg).collect()
     })
 }
 
-#[ic_cdk::update]
-fn delete_session(session_id: String) -> bool {
-    let principal = msg_caller();
+// Appends messages after the session's current last message and bumps the
+// header's message count. Earlier messages are never rewritten. Returns the
+// new message count, or `None` if the session no longer exists.
+fn append_messages(session_id: &str, new_messages: Vec<ChatMessage>) -> Option<u64> {
+    let key = KeyString(session_id.to_string());
+    let mut session = SESSIONS.with(|sessions| sessions.borrow().get(&key))?;
+    MESSAGES.with(|messages| {
+        let mut map = messages.borrow_mut();
+        for msg in new_messages {
+            let seq = session.message_count;
+            map.insert(MessageKey { session_id: session_id.to_string(), seq }, msg);
+            session.message_count += 1;
+        }
+    });
+    let message_count = session.message_count;
     SESSIONS.with(|sessions| {
-        let mut map = sessions.borrow_mut();
-        if let Some(session) = map.get(&KeyString(session_id.clone())).map(|s| s.clone()) {
-            if session.principal != principal {
-                return false;
+        sessions.borrow_mut().insert(key, session);
+    });
+    Some(message_count)
+}
+
+// Deletes a session's messages and returns how many content bytes they held.
+fn remove_session_messages(session_id: &str) -> u64 {
+    MESSAGES.with(|messages| {
+        let mut map = messages.borrow_mut();
+        let from = MessageKey { session_id: session_id.to_string(), seq: 0 };
+        let to = MessageKey { session_id: session_id.to_string(), seq: u64::MAX };
+        let keys: Vec<MessageKey> = map.range(from..=to).map(|(key, _)| key).collect();
+        let mut bytes = 0;
+        for key in keys {
+            if let Some(msg) = map.remove(&key) {
+                bytes += msg.content.len() as u64;
             }
-            map.remove(&KeyString(session_id));
-            true
-        } else {
-            false
         }
+        bytes
     })
 }
 
-// Legal Template Management Functions
 #[ic_cdk::update]
-fn add_template(id: String, name: String, template_text: String) {
-    let id_clone = id.clone();
-    TEMPLATES.with(|templates| {
-        templates
-            .borrow_mut()
-            .insert(KeyString(id), LegalTemplate {
-                id: id_clone,
-                name,
-                template_text,
-            });
+fn rename_session(session_id: String, new_title: String) -> LexResult<()> {
+    owned_session(&session_id, msg_caller())?;
+    migrations::migrate_session(&session_id);
+    // Re-read the header: migrating may have rewritten it.
+    let mut updated_session = owned_session(&session_id, msg_caller())?;
+    updated_session.title = Some(new_title);
+    SESSIONS.with(|sessions| {
+        sessions.borrow_mut().insert(KeyString(session_id), updated_session);
     });
+    Ok(())
 }
 
-// Document Generation Functions
 #[ic_cdk::update]
-async fn generate_document(template_id: String, fields: Vec<(String, String)>) -> String {
-    let template_opt = TEMPLATES.with(|templates| {
-        let map = templates.borrow();
-        map.get(&KeyString(template_id.clone())).map(|t| t.clone())
+fn delete_session(session_id: String) -> LexResult<()> {
+    let principal = msg_caller();
+    owned_session(&session_id, principal)?;
+    SESSIONS.with(|sessions| {
+        sessions.borrow_mut().remove(&KeyString(session_id.clone()));
     });
+    let freed = remove_session_messages(&session_id);
+    quota::release(principal, Resource::StoredBytes, freed);
+    SESSION_INDEX.with(|index| index_remove(index, principal, &session_id));
+    Ok(())
+}
 
-    if let Some(template) = template_opt {
-        let mut prompt = template.template_text;
-        ic_cdk::println!("Initial prompt: {}", prompt);
-        for (key, value) in &fields {
-            let placeholder = format!("{{{}}}", key);
-            ic_cdk::println!("Replacing {} with {}", placeholder, value);
-            prompt = prompt.replace(&placeholder, value);
+// Legal Template Management Functions
+impl LegalTemplate {
+    fn revision(&self, created_by: Option<Principal>) -> TemplateRevision {
+        TemplateRevision {
+            template_id: self.id.clone(),
+            version: self.version,
+            name: self.name.clone(),
+            template_text: self.template_text.clone(),
+            fields: self.fields.clone(),
+            owner: self.owner,
+            created_at: time(),
+            created_by,
+            render_mode: self.render_mode,
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    // Everything but the id, scope and version.
+    fn same_content(&self, other: &LegalTemplate) -> bool {
+        self.name == other.name
+            && self.template_text == other.template_text
+            && self.fields == other.fields
+            && self.render_mode == other.render_mode
+            && self.metadata == other.metadata
+    }
+
+    fn scope(&self) -> TemplateScope {
+        match self.owner {
+            Some(_) => TemplateScope::Private,
+            None => TemplateScope::System,
+        }
+    }
+
+    fn summary(&self) -> TemplateSummary {
+        TemplateSummary {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            scope: self.scope(),
+            version: self.version,
+            render_mode: self.render_mode,
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    fn is_visible_to(&self, principal: Principal) -> bool {
+        self.owner.is_none_or(|owner| owner == principal)
+    }
+
+    fn is_managed_by(&self, principal: Principal) -> bool {
+        match self.owner {
+            Some(owner) => owner == principal,
+            None => roles::is_admin(principal),
+        }
+    }
+}
+
+// Checks a template's id and text and returns its schema. Without `fields`,
+// every field the text refers to becomes a required one.
+fn validate_template(
+    id: &str,
+    template_text: &str,
+    fields: Option<Vec<TemplateField>>,
+) -> LexResult<Vec<TemplateField>> {
+    validate_key("template id", id)?;
+    if template_text.trim().is_empty() {
+        return Err(LexError::validation("template text must not be empty"));
+    }
+    let fields = match fields {
+        Some(fields) => fields,
+        None => templates::fields_from_placeholders(template_text)?,
+    };
+    templates::validate_schema(template_text, &fields)?;
+    Ok(fields)
+}
+
+// Stores `template` as the next revision of its id and makes it current.
+// Its `version` is assigned here and returned.
+fn save_template(mut template: LegalTemplate, created_by: Option<Principal>) -> u64 {
+    let key = KeyString(template.id.clone());
+    // A template that predates revisions gets its revision 1 recorded first,
+    // unless the v5 migration already did.
+    if let Some(current) = TEMPLATES.with(|templates| templates.borrow().get(&key)) {
+        templates::record_revision(current.revision(None));
+    }
+    template.version = templates::latest_version(&template.id).map_or(1, |version| version + 1);
+    templates::record_revision(template.revision(created_by));
+    let version = template.version;
+    TEMPLATES.with(|templates| templates.borrow_mut().insert(key, template));
+    version
+}
+
+// Creates a template. Private templates (the default) belong to the caller
+// and count against their quota; system templates can only be added by
+// admins. Templates are drafted by the LLM unless another render mode is
+// given.
+#[ic_cdk::update]
+fn add_template(
+    id: String,
+    name: String,
+    template_text: String,
+    fields: Option<Vec<TemplateField>>,
+    scope: Option<TemplateScope>,
+    render_mode: Option<RenderMode>,
+    metadata: Option<TemplateMetadata>,
+) -> LexResult<()> {
+    let principal = msg_caller();
+    if principal == Principal::anonymous() {
+        return Err(LexError::Unauthorized);
+    }
+    let fields = validate_template(&id, &template_text, fields)?;
+    let metadata = metadata.unwrap_or_default().normalized()?;
+    if TEMPLATES.with(|templates| templates.borrow().contains_key(&KeyString(id.clone()))) {
+        return Err(LexError::validation(format!("template {} already exists; use update_template", id)));
+    }
+    let owner = match scope.unwrap_or(TemplateScope::Private) {
+        TemplateScope::System => {
+            ensure_admin()?;
+            None
+        }
+        TemplateScope::Private => Some(principal),
+    };
+    // The id's history would otherwise continue under a different owner.
+    if !templates::history_owned_by(&id, owner) {
+        return Err(LexError::validation(format!("template id {} is taken; choose another id", id)));
+    }
+    if owner.is_some() {
+        quota::ensure_available(principal, Resource::Templates)?;
+        quota::consume(principal, Resource::StoredBytes, template_text.len() as u64)?;
+        quota::consume(principal, Resource::Templates, 1)?;
+    }
+    let template = LegalTemplate {
+        id,
+        name,
+        template_text,
+        fields,
+        owner,
+        version: 0,
+        render_mode: render_mode.unwrap_or_default(),
+        metadata,
+    };
+    save_template(template, Some(principal));
+    Ok(())
+}
+
+// Saves a new revision of a template with the given name, text and fields.
+// The template keeps its scope, and its render mode and metadata unless new
+// ones are given.
+#[ic_cdk::update]
+fn update_template(
+    id: String,
+    name: String,
+    template_text: String,
+    fields: Option<Vec<TemplateField>>,
+    render_mode: Option<RenderMode>,
+    metadata: Option<TemplateMetadata>,
+) -> LexResult<()> {
+    let principal = msg_caller();
+    let fields = validate_template(&id, &template_text, fields)?;
+    let metadata = metadata.map(TemplateMetadata::normalized).transpose()?;
+    let existing = managed_template(&id, principal)?;
+    if let Some(owner) = existing.owner {
+        let (old, new) = (existing.template_text.len() as u64, template_text.len() as u64);
+        if new > old {
+            quota::consume(owner, Resource::StoredBytes, new - old)?;
+        } else {
+            quota::release(owner, Resource::StoredBytes, old - new);
         }
-        ic_cdk::println!("Final prompt sent to Gemini: {}", prompt);
-        let document_text = query_gemini_api_document(&prompt).await;
-        ic_cdk::println!("Generated document text: {}", document_text);
-        let principal = msg_caller();
-        let now = time();
-        let mut hasher = Sha256::new();
-        hasher.update(principal.as_slice());
-        hasher.update(now.to_be_bytes());
-        hasher.update(template_id.as_bytes());
-        let hash = hex::encode(hasher.finalize());
-        let document_id = format!("doc_{}", hash);
-        DOCUMENTS.with(|documents| {
-            let mut map = documents.borrow_mut();
-            map.insert(KeyString(document_id.clone()), ValueString(document_text.clone()));
-        });
-        document_id
-    } else {
-        ic_cdk::println!("Template not found: {}", template_id);
-        "Template not found".to_string()
     }
+    let template = LegalTemplate {
+        id,
+        name,
+        template_text,
+        fields,
+        owner: existing.owner,
+        version: 0,
+        render_mode: render_mode.unwrap_or(existing.render_mode),
+        metadata: metadata.unwrap_or(existing.metadata),
+    };
+    save_template(template, Some(principal));
+    Ok(())
 }
 
+// Documents already generated from the template and its revisions are kept,
+// and its id continues at the next version if it is reused.
+#[ic_cdk::update]
+fn delete_template(id: String) -> LexResult<()> {
+    let existing = managed_template(&id, msg_caller())?;
+    templates::record_revision(existing.revision(None));
+    TEMPLATES.with(|templates| templates.borrow_mut().remove(&KeyString(id)));
+    if let Some(owner) = existing.owner {
+        quota::release(owner, Resource::StoredBytes, existing.template_text.len() as u64);
+        quota::release(owner, Resource::Templates, 1);
+    }
+    Ok(())
+}
+
+// Exports templates the caller manages as a JSON bundle for
+// `import_templates`: system templates for admins, private ones for their
+// owner. Without ids, every template the caller manages is exported.
 #[ic_cdk::query]
-fn get_document(document_id: String) -> Option<String> {
-    DOCUMENTS.with(|documents| {
-        let map = documents.borrow();
-        let document = map.get(&KeyString(document_id.clone())).map(|v| v.0.clone());
-        ic_cdk::println!("Retrieved document for ID {}: {:?}", document_id, document);
-        document
+fn export_templates(ids: Vec<String>) -> LexResult<String> {
+    bundles::export(msg_caller(), ids)
+}
+
+// Imports a bundle made by `export_templates`, resolving id conflicts with
+// `strategy`. Templates keep their bundled scope unless `scope` is given.
+#[ic_cdk::update]
+fn import_templates(bundle: String, strategy: ConflictStrategy, scope: Option<TemplateScope>) -> LexResult<ImportReport> {
+    bundles::import(msg_caller(), &bundle, strategy, scope)
+}
+
+// A template `principal` can see. Other users' private templates are reported
+// as not found.
+fn visible_template(template_id: &str, principal: Principal) -> LexResult<LegalTemplate> {
+    TEMPLATES.with(|templates| templates.borrow().get(&KeyString(template_id.to_string())))
+        .filter(|template| template.is_visible_to(principal))
+        .ok_or_else(|| LexError::not_found("Template", template_id))
+}
+
+fn managed_template(template_id: &str, principal: Principal) -> LexResult<LegalTemplate> {
+    let template = visible_template(template_id, principal)?;
+    if !template.is_managed_by(principal) {
+        return Err(LexError::Unauthorized);
+    }
+    Ok(template)
+}
+
+#[ic_cdk::query]
+fn get_template(template_id: String) -> LexResult<TemplateDetails> {
+    let template = visible_template(&template_id, msg_caller())?;
+    let outline = templates::outline(&template.template_text);
+    Ok(TemplateDetails {
+        template,
+        placeholders: outline.placeholders,
+        unmatched_braces: outline.unmatched_braces,
+        syntax_error: outline.syntax_error,
     })
 }
 
-async fn query_gemini_api_document(prompt: &str) -> String {
-    let api_key = ""; // Replace with your actual Gemini API key
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-flash:generateContent?key={}",
-        api_key
-    );
+// The template rendered with these fields, without calling the LLM: the
+// document itself for `Deterministic` templates, otherwise the text the LLM
+// would be given. Fields are validated the same way as `generate_document`.
+#[ic_cdk::query]
+fn preview_template(template_id: String, fields: Vec<(String, String)>) -> LexResult<String> {
+    let template = visible_template(&template_id, msg_caller())?;
+    let fields = templates::validate_fields(&template.fields, &fields)?;
+    templates::render(&template.template_text, &template.fields, &fields).map(|rendered| rendered.text)
+}
 
-    let headers = vec![HttpHeader {
-        name: "Content-Type".to_string(),
-        value: "application/json".to_string(),
-    }];
+// Every revision of a template `principal` can see, oldest first. Deleted
+// templates keep their history; private revisions stay visible to their
+// owner only, whoever holds the id now.
+fn template_history(template_id: &str, principal: Principal) -> LexResult<Vec<TemplateRevision>> {
+    let mut history = templates::revisions(template_id);
+    // Until the v5 migration has run, a template may have no recorded revision.
+    if let Some(current) = TEMPLATES.with(|templates| templates.borrow().get(&KeyString(template_id.to_string()))) {
+        if history.last().is_none_or(|latest| latest.version < current.version) {
+            history.push(current.revision(None));
+        }
+    }
+    history.retain(|revision| revision.owner.is_none_or(|owner| owner == principal));
+    if history.is_empty() {
+        return Err(LexError::not_found("Template", template_id));
+    }
+    Ok(history)
+}
 
-    let full_prompt = format!(
-        "{}\n\nPlease generate a professional legal document based on the provided details. Include all specified fields in the document, ensuring proper formatting with numbered sections, clear headings, and no placeholders (e.g., [Specify]). Avoid including any disclaimers, introductions, or AI-related statements.",
-        prompt
-    );
+#[ic_cdk::query]
+fn list_template_revisions(template_id: String) -> LexResult<Vec<TemplateRevision>> {
+    template_history(&template_id, msg_caller())
+}
 
-    let json_body = json!({
-        "contents": [
-            {
-                "parts": [{ "text": full_prompt }]
-            }
-        ],
-        "generationConfig": {
-            "temperature": 0.3,
-            "topK": 40,
-            "topP": 0.95,
-            "maxOutputTokens": 2048,
-            "stopSequences": []
+#[ic_cdk::query]
+fn get_template_revision(template_id: String, version: u64) -> LexResult<TemplateRevision> {
+    template_history(&template_id, msg_caller())?
+        .into_iter()
+        .find(|revision| revision.version == version)
+        .ok_or_else(|| LexError::not_found("Template revision", &format!("{} v{}", template_id, version)))
+}
+
+#[ic_cdk::query]
+fn diff_template_revisions(template_id: String, from_version: u64, to_version: u64) -> LexResult<TemplateDiff> {
+    let history = template_history(&template_id, msg_caller())?;
+    let find = |version: u64| {
+        history
+            .iter()
+            .find(|revision| revision.version == version)
+            .ok_or_else(|| LexError::not_found("Template revision", &format!("{} v{}", template_id, version)))
+    };
+    templates::diff(find(from_version)?, find(to_version)?)
+}
+
+// Clauses are included into templates with `{> clause_id}` and are rendered
+// as they stand when a document is generated. Documents record the clause
+// versions they included.
+#[ic_cdk::query]
+fn list_clauses() -> LexResult<Vec<Clause>> {
+    Ok(templates::clauses())
+}
+
+// Saves `text` as the next version of the clause.
+#[ic_cdk::update]
+fn set_clause(id: String, text: String) -> LexResult<()> {
+    ensure_admin()?;
+    templates::validate_clause(&id, &text)?;
+    templates::set_clause(id, text, time(), msg_caller());
+    Ok(())
+}
+
+// Every revision of a clause, oldest first. Deleted clauses keep their
+// history.
+#[ic_cdk::query]
+fn list_clause_revisions(id: String) -> LexResult<Vec<Clause>> {
+    let revisions = templates::clause_revisions(&id);
+    if revisions.is_empty() {
+        // Until the v6 migration has run, a clause may have no recorded revision.
+        return templates::clause(&id)
+            .map(|clause| vec![clause])
+            .ok_or_else(|| LexError::not_found("Clause", &id));
+    }
+    Ok(revisions)
+}
+
+#[ic_cdk::query]
+fn get_clause_revision(id: String, version: u64) -> LexResult<Clause> {
+    templates::clause_revision(&id, version)
+        .or_else(|| templates::clause(&id).filter(|clause| clause.version == version))
+        .ok_or_else(|| LexError::not_found("Clause revision", &format!("{} v{}", id, version)))
+}
+
+#[ic_cdk::update]
+fn delete_clause(id: String) -> LexResult<()> {
+    ensure_admin()?;
+    if !templates::delete_clause(&id) {
+        return Err(LexError::not_found("Clause", &id));
+    }
+    Ok(())
+}
+
+// `KeyString` is bounded to 100 bytes; longer keys would trap on insert.
+fn validate_key(what: &str, key: &str) -> LexResult<()> {
+    if key.is_empty() || key.len() > 100 {
+        return Err(LexError::validation(format!("{} must be between 1 and 100 bytes", what)));
+    }
+    Ok(())
+}
+
+// Document Generation Functions
+// Queues generation of a document from a template and returns the job id;
+// poll `get_job_status` for the outcome.
+#[ic_cdk::update]
+fn generate_document(template_id: String, fields: Vec<(String, String)>) -> LexResult<u64> {
+    let principal = msg_caller();
+    let template = visible_template(&template_id, principal)?;
+    // Rejected before anything is charged or an outcall is made.
+    let fields = templates::validate_fields(&template.fields, &fields)?;
+    templates::render(&template.template_text, &template.fields, &fields)?;
+    quota::ensure_available(principal, Resource::StoredBytes)?;
+    limits::admit_paid_call(principal, "generate_document")?;
+    let credits_charged = payments::charge(principal, Resource::Documents, jobs::next_id())
+        .inspect_err(|_| limits::release_paid_call(principal, "generate_document"))?;
+    // The job renders this exact revision, even if the template changes
+    // before it runs.
+    templates::record_revision(template.revision(None));
+    let kind = JobKind::Document {
+        template_id,
+        fields,
+        template_version: Some(template.version),
+    };
+    Ok(jobs::enqueue(principal, kind, credits_charged))
+}
+
+// The revision rendered with `fields`, and the system prompt the LLM is
+// given it with; no prompt means the rendered text is the document.
+fn document_request(
+    revision: &TemplateRevision,
+    fields: &[(String, String)],
+) -> LexResult<(Rendered, Option<&'static str>)> {
+    let rendered = templates::render(&revision.template_text, &revision.fields, fields)?;
+    let system_prompt = match revision.render_mode {
+        RenderMode::Deterministic => None,
+        RenderMode::LlmAssisted => Some(DOCUMENT_SYSTEM_PROMPT),
+        RenderMode::LlmPolish => Some(POLISH_SYSTEM_PROMPT),
+    };
+    Ok((rendered, system_prompt))
+}
+
+async fn run_document(
+    job_id: u64,
+    owner: Principal,
+    template_id: &str,
+    template_version: Option<u64>,
+    fields: &[(String, String)],
+    outcalls: &mut Vec<Outcall>,
+) -> LexResult<JobResult> {
+    // Jobs queued before templates were versioned use the current template.
+    let revision = match template_version {
+        Some(version) => templates::revision(template_id, version)
+            .filter(|revision| revision.owner.is_none_or(|revision_owner| revision_owner == owner))
+            .ok_or_else(|| LexError::not_found("Template revision", &format!("{} v{}", template_id, version)))?,
+        None => visible_template(template_id, owner)?.revision(None),
+    };
+    let (rendered, system_prompt) = document_request(&revision, fields)?;
+    let document_text = match system_prompt {
+        Some(system_prompt) => {
+            ic_cdk::println!("Sending {} v{} to the LLM: {} bytes", template_id, revision.version, rendered.text.len());
+            query_llm(
+                system_prompt,
+                vec![LlmMessage::user(rendered.text)],
+                None,
+                Projection::ReplyWithUsage,
+                outcalls,
+            )
+            .await?
         }
+        None => rendered.text,
+    };
+    ic_cdk::println!("Generated a document from {} v{}: {} bytes", template_id, revision.version, document_text.len());
+    // Keyed by the job, not the time: jobs run in one round share `time()`.
+    let mut hasher = Sha256::new();
+    hasher.update(owner.as_slice());
+    hasher.update(job_id.to_be_bytes());
+    hasher.update(template_id.as_bytes());
+    let hash = hex::encode(hasher.finalize());
+    let document_id = format!("doc_{}", hash);
+    quota::record(owner, Resource::StoredBytes, document_text.len() as u64);
+    let record = DocumentRecord {
+        owner,
+        template_id: template_id.to_string(),
+        fields: fields.to_vec(),
+        created_at: time(),
+        body: document_text,
+        template_version: Some(revision.version),
+        clauses: rendered.clauses,
+    };
+    DOCUMENTS.with(|documents| {
+        let mut map = documents.borrow_mut();
+        map.insert(KeyString(document_id.clone()), record);
     });
+    DOCUMENT_INDEX.with(|index| index_insert(index, owner, &document_id));
+    Ok(JobResult::Document { document_id })
+}
 
-    let request = HttpRequestArgs {
-        method: HttpMethod::POST,
-        url,
-        headers,
-        body: Some(serde_json::to_vec(&json_body).unwrap()),
-        max_response_bytes: Some(2_000_000),
-        transform: Some(TransformContext {
-            function: TransformFunc::new(canister_self(), "transform".into()),
-            context: vec![],
-        }),
-    };
+#[ic_cdk::query]
+fn get_document(document_id: String) -> LexResult<String> {
+    get_document_record(document_id).map(|record| record.body)
+}
 
-    match http_request(&request).await {
-        Ok(res) => {
-            let body_str = String::from_utf8(res.body).unwrap_or_default();
-            ic_cdk::println!("Gemini API response: {}", body_str);
-            let json: serde_json::Value = serde_json::from_str(&body_str).unwrap_or_default();
-            let parts = json["candidates"][0]["content"]["parts"].as_array();
-            match parts {
-                Some(parts_array) => {
-                    let text = parts_array
-                        .iter()
-                        .filter_map(|part| part["text"].as_str())
-                        .collect::<Vec<&str>>()
-                        .join("\n");
-                    if text.is_empty() {
-                        ic_cdk::println!("Error: No valid text in response");
-                        "Error: No valid text in response".to_string()
-                    } else {
-                        text
-                    }
-                }
-                None => {
-                    ic_cdk::println!("Error: Invalid response structure");
-                    "Error: Invalid response structure".to_string()
+// Admins can also read unclaimed documents, to find out whose they are.
+#[ic_cdk::query]
+fn get_document_record(document_id: String) -> LexResult<DocumentRecord> {
+    let principal = msg_caller();
+    let record = DOCUMENTS.with(|documents| documents.borrow().get(&KeyString(document_id.clone())))
+        .ok_or_else(|| LexError::not_found("Document", &document_id))?;
+    let unclaimed_by_admin = record.owner == Principal::management_canister() && roles::is_admin(principal);
+    if record.owner != principal && !unclaimed_by_admin {
+        return Err(LexError::Unauthorized);
+    }
+    Ok(record)
+}
+
+// Ids of documents stored before documents had owners, in id order, after
+// the id `after`.
+#[ic_cdk::query]
+fn list_unclaimed_documents(after: Option<String>, limit: Option<u64>) -> LexResult<Vec<String>> {
+    ensure_admin()?;
+    let limit = limit.unwrap_or(MAX_DOCUMENTS_PER_PAGE).min(MAX_DOCUMENTS_PER_PAGE) as usize;
+    let start = after.map_or(Unbounded, |id| Excluded(KeyString(id)));
+    Ok(UNCLAIMED_DOCUMENTS.with(|unclaimed| {
+        unclaimed
+            .borrow()
+            .range((start, Unbounded))
+            .take(limit)
+            .map(|(id, _)| id.0)
+            .collect()
+    }))
+}
+
+// Gives an unclaimed document to its owner, whose stored bytes it then
+// counts against. Documents that already have an owner cannot be reassigned.
+#[ic_cdk::update]
+fn assign_document(document_id: String, owner: Principal) -> LexResult<()> {
+    ensure_admin()?;
+    if owner == Principal::anonymous() || owner == Principal::management_canister() {
+        return Err(LexError::validation("documents must be assigned to a user"));
+    }
+    let key = KeyString(document_id.clone());
+    let mut record = DOCUMENTS.with(|documents| documents.borrow().get(&key))
+        .ok_or_else(|| LexError::not_found("Document", &document_id))?;
+    if record.owner != Principal::management_canister() {
+        return Err(LexError::validation(format!("document {} already has an owner", document_id)));
+    }
+    record.owner = owner;
+    quota::record(owner, Resource::StoredBytes, record.body.len() as u64);
+    DOCUMENTS.with(|documents| documents.borrow_mut().insert(key.clone(), record));
+    DOCUMENT_INDEX.with(|index| index_insert(index, owner, &document_id));
+    UNCLAIMED_DOCUMENTS.with(|unclaimed| unclaimed.borrow_mut().remove(&key));
+    Ok(())
+}
+
+// Generation Jobs
+// Runs a job once, recording each LLM call it makes in `outcalls`.
+async fn run_job(job: &Job, outcalls: &mut Vec<Outcall>) -> LexResult<JobResult> {
+    match &job.kind {
+        JobKind::Chat { session_id, input } => run_chat(job.owner, session_id, input, outcalls).await,
+        JobKind::Document {
+            template_id,
+            fields,
+            template_version,
+        } => run_document(job.id, job.owner, template_id, *template_version, fields, outcalls).await,
+    }
+}
+
+// The quota a job draws on; reserved when it is queued.
+fn job_resource(kind: &JobKind) -> Resource {
+    match kind {
+        JobKind::Chat { .. } => Resource::Messages,
+        JobKind::Document { .. } => Resource::Documents,
+    }
+}
+
+// Called once a job has left the queue, whatever its outcome. A failed job
+// gives back the quota or credits it was charged.
+fn finish_job(job: &Job, succeeded: bool) {
+    if !succeeded {
+        payments::reverse_charge(
+            job.owner,
+            job_resource(&job.kind),
+            job.id,
+            job.credits_charged,
+            job.charged_period,
+        );
+    }
+    if let JobKind::Chat { session_id, .. } = &job.kind {
+        let key = KeyString(session_id.clone());
+        SESSIONS.with(|sessions| {
+            let mut map = sessions.borrow_mut();
+            if let Some(mut session) = map.get(&key) {
+                if session.pending_job == Some(job.id) {
+                    session.pending_job = None;
+                    map.insert(key, session);
                 }
             }
-        }
-        Err(e) => {
-            ic_cdk::println!("Gemini API request failed: {:?}", e);
-            format!("Request failed: {:?}", e)
-        }
+        });
     }
 }
 
-// Gemini API Integration
-async fn query_gemini_api(prompt: &str) -> String {
-    let api_key = ""; // Replace with your actual Gemini API key
-    let url = format!(
-        "https://generativelanguage.googleapis.com/v1beta/models/gemini-1.5-flash:generateContent?key={}",
-        api_key
-    );
-
-    let headers = vec![HttpHeader {
-        name: "Content-Type".to_string(),
-        value: "application/json".to_string(),
-    }];
+fn owned_job(job_id: u64, principal: Principal) -> LexResult<Job> {
+    let job = jobs::job(job_id).ok_or_else(|| LexError::not_found("Job", &job_id.to_string()))?;
+    if job.owner != principal {
+        return Err(LexError::Unauthorized);
+    }
+    Ok(job)
+}
 
-    let full_prompt = format!(
-        "{}\n\nAvoid including any disclaimers, introductions, or AI-related statements. like dont say i am ai i cant give leagal advice , just asnswer the question in a professional manner",
-        prompt
-    );
+#[ic_cdk::query]
+fn get_job_status(job_id: u64) -> LexResult<Job> {
+    owned_job(job_id, msg_caller())
+}
 
-    let json_body = json!({
-        "contents": [
-            {
-                "parts": [{ "text": full_prompt }]
+// Puts a failed job back in the queue with its original input.
+#[ic_cdk::update]
+fn retry_job(job_id: u64) -> LexResult<()> {
+    let principal = msg_caller();
+    let job = owned_job(job_id, principal)?;
+    if !matches!(job.status, JobStatus::Failed { .. }) {
+        return Err(LexError::validation("only failed jobs can be retried"));
+    }
+    let chat_session = match &job.kind {
+        JobKind::Chat { session_id, .. } => {
+            let session = owned_session(session_id, principal)?;
+            if session.pending_job.is_some() {
+                return Err(LexError::busy("Session", session_id));
             }
-        ],
-        "generationConfig": {
-            "temperature": 0.3,
-            "topK": 40,
-            "topP": 0.95,
-            "maxOutputTokens": 2048,
-            "stopSequences": []
+            Some(session)
         }
-    });
+        JobKind::Document { .. } => None,
+    };
+    limits::admit_paid_call(principal, "retry_job")?;
+    let credits_charged = payments::charge(principal, job_resource(&job.kind), job_id)
+        .inspect_err(|_| limits::release_paid_call(principal, "retry_job"))?;
+    if let Some(mut session) = chat_session {
+        session.pending_job = Some(job_id);
+        SESSIONS.with(|sessions| {
+            sessions.borrow_mut().insert(KeyString(session.session_id.clone()), session);
+        });
+    }
+    jobs::requeue(job_id, credits_charged);
+    Ok(())
+}
 
-    let request = HttpRequestArgs {
-        method: HttpMethod::POST,
-        url,
-        headers,
-        body: Some(serde_json::to_vec(&json_body).unwrap()),
-        max_response_bytes: Some(2_000_000),
-        transform: Some(TransformContext {
-            function: TransformFunc::new(canister_self(), "transform".into()),
-            context: vec![],
-        }),
+// Plans & Quotas
+#[ic_cdk::query]
+fn get_my_allowance() -> LexResult<Allowance> {
+    Ok(quota::allowance(msg_caller()))
+}
+
+#[ic_cdk::query]
+fn get_user_allowance(principal: Principal) -> LexResult<Allowance> {
+    ensure_controller()?;
+    Ok(quota::allowance(principal))
+}
+
+#[ic_cdk::update]
+fn set_user_plan(principal: Principal, plan: Plan) -> LexResult<()> {
+    ensure_controller()?;
+    USERS.with(|users| {
+        let mut map = users.borrow_mut();
+        let key = KeyPrincipal(principal);
+        let mut user = map.get(&key).ok_or_else(|| LexError::not_found("User", &principal.to_text()))?;
+        user.plan = plan;
+        map.insert(key, user);
+        Ok(())
+    })
+}
+
+// Usage Accounting
+#[ic_cdk::query]
+fn get_my_usage() -> LexResult<UsageTotals> {
+    Ok(usage::user_totals(msg_caller()))
+}
+
+// The caller's LLM calls in the order they were made, from the `start`-th on.
+#[ic_cdk::query]
+fn get_my_usage_entries(start: u64, limit: Option<u64>) -> LexResult<Vec<UsageEntry>> {
+    Ok(usage::user_entries(msg_caller(), start, limit))
+}
+
+#[ic_cdk::query]
+fn get_user_usage(principal: Principal) -> LexResult<UsageTotals> {
+    ensure_controller()?;
+    Ok(usage::user_totals(principal))
+}
+
+// Totals per UTC day, with days counted from the Unix epoch.
+#[ic_cdk::query]
+fn get_daily_usage(from_day: u64, to_day: u64) -> LexResult<Vec<DailyUsage>> {
+    ensure_controller()?;
+    if from_day > to_day {
+        return Err(LexError::validation("from_day must not be after to_day"));
+    }
+    Ok(usage::daily_totals(from_day, to_day))
+}
+
+#[ic_cdk::query]
+fn get_template_usage() -> LexResult<Vec<(String, UsageTotals)>> {
+    ensure_controller()?;
+    Ok(usage::template_totals())
+}
+
+// Payments
+#[ic_cdk::query]
+fn get_my_credits() -> LexResult<u64> {
+    Ok(payments::credits(msg_caller()))
+}
+
+// Buys `amount` credits with tokens the caller has approved this canister to
+// spend via `icrc2_approve` on the configured ledger.
+#[ic_cdk::update]
+async fn top_up(amount: u64) -> LexResult<Payment> {
+    payments::top_up(msg_caller(), amount).await
+}
+
+// The caller's top-ups, debits and refunds, from the `start`-th on.
+#[ic_cdk::query]
+fn get_my_payments(start: u64, limit: Option<u64>) -> LexResult<Vec<Payment>> {
+    Ok(payments::payments(msg_caller(), start, limit))
+}
+
+#[ic_cdk::query]
+fn get_user_payments(principal: Principal, start: u64, limit: Option<u64>) -> LexResult<Vec<Payment>> {
+    ensure_controller()?;
+    Ok(payments::payments(principal, start, limit))
+}
+
+// Sends `amount` of a user's unspent credits back to them as tokens.
+#[ic_cdk::update]
+async fn refund_credits(principal: Principal, amount: u64) -> LexResult<Payment> {
+    ensure_controller()?;
+    payments::refund(principal, amount, msg_caller()).await
+}
+
+#[ic_cdk::query]
+fn get_payment_config() -> LexResult<PaymentConfig> {
+    Ok(payments::payment_config())
+}
+
+#[ic_cdk::update]
+fn set_payment_config(config: PaymentConfig) -> LexResult<()> {
+    ensure_controller()?;
+    payments::set_payment_config(config);
+    Ok(())
+}
+
+// LLM Integration
+const CHAT_SYSTEM_PROMPT: &str = "You are LexAi, a legal assistant. Answer the user's legal questions in a professional manner. Avoid including any disclaimers, introductions, or AI-related statements: do not say that you are an AI or that you cannot give legal advice, just answer the question.";
+
+const SUMMARY_SYSTEM_PROMPT: &str = "You maintain running notes of a legal consultation. Merge the summary so far with the new conversation into one concise summary. Preserve parties, facts, dates, amounts, jurisdictions, documents discussed, advice given and open questions. Write only the summary.";
+
+const POLISH_SYSTEM_PROMPT: &str = "You are LexAi, a legal drafting assistant. The user provides a complete legal document. Return it with its grammar, punctuation and formatting polished. Do not add, remove or reorder clauses, and keep every name, amount, date and defined term exactly as written. Reply with the document only, without any introduction or comments.";
+const DOCUMENT_SYSTEM_PROMPT: &str = "You are LexAi, a legal drafting assistant. Generate a professional legal document based on the details provided by the user. Include all specified fields in the document, ensuring proper formatting with numbered sections, clear headings, and no placeholders (e.g., [Specify]). Avoid including any disclaimers, introductions, or AI-related statements.";
+
+// Sends a conversation to the configured provider with `system_prompt` as the
+// system instruction, optionally capping the reply length. If the provider
+// fails with a retryable error the fallback provider, when configured, is
+// tried straight away. Every call is appended to `outcalls`.
+async fn query_llm(
+    system_prompt: &str,
+    messages: Vec<LlmMessage>,
+    max_output_tokens: Option<u32>,
+    projection: Projection,
+    outcalls: &mut Vec<Outcall>,
+) -> LexResult<String> {
+    let llm_config = config::llm_config();
+    let mut generation = llm_config.generation.clone();
+    if let Some(max_output_tokens) = max_output_tokens {
+        generation.max_output_tokens = max_output_tokens;
+    }
+    let request = LlmRequest {
+        system_prompt: Some(system_prompt.to_string()),
+        messages,
+        generation,
+        projection,
     };
 
-    match http_request(&request).await {
-        Ok(res) => {
-            let body_str = String::from_utf8(res.body).unwrap_or_default();
-            ic_cdk::println!("Gemini API response: {}", body_str);
-            let json: serde_json::Value = serde_json::from_str(&body_str).unwrap_or_default();
-            let parts = json["candidates"][0]["content"]["parts"].as_array();
-            match parts {
-                Some(parts_array) => {
-                    let text = parts_array
-                        .iter()
-                        .filter_map(|part| part["text"].as_str())
-                        .collect::<Vec<&str>>()
-                        .join("\n");
-                    if text.is_empty() {
-                        ic_cdk::println!("Error: No valid text in response");
-                        "Error: No valid text in response".to_string()
-                    } else {
-                        text
-                    }
-                }
-                None => {
-                    ic_cdk::println!("Error: Invalid response structure");
-                    "Error: Invalid response structure".to_string()
-                }
+    let mut endpoints = vec![(llm_config.provider, llm_config.endpoint())];
+    endpoints.extend(llm_config.fallback_endpoint());
+    let mut last_error = None;
+    for (kind, endpoint) in endpoints {
+        let model = endpoint.model.clone();
+        let provider = kind.provider(endpoint);
+        let started_at = time();
+        let (cost, result) = llm::complete(provider.as_ref(), &request).await;
+        outcalls.push(Outcall {
+            provider: provider.name().to_string(),
+            model,
+            started_at,
+            finished_at: time(),
+            error: result.as_ref().err().cloned(),
+            cost: Some(cost),
+            usage: result.as_ref().ok().and_then(|response| response.usage.clone()),
+        });
+        match result {
+            Ok(response) => {
+                ic_cdk::println!(
+                    "{} finished ({:?}), usage: {:?}",
+                    provider.name(),
+                    response.finish_reason,
+                    response.usage
+                );
+                return Ok(response.text);
             }
+            Err(error) if llm::is_retryable(&error) => last_error = Some(error),
+            Err(error) => return Err(error),
         }
-        Err(e) => {
-            ic_cdk::println!("Gemini API request failed: {:?}", e);
-            format!("Request failed: {:?}", e)
-        }
     }
+    Err(last_error.expect("at least one provider is always configured"))
+}
+
+// Configuration Functions
+fn ensure_controller() -> LexResult<()> {
+    if ic_cdk::api::is_controller(&msg_caller()) {
+        Ok(())
+    } else {
+        Err(LexError::Unauthorized)
+    }
+}
+
+fn ensure_admin() -> LexResult<()> {
+    if roles::is_admin(msg_caller()) {
+        Ok(())
+    } else {
+        Err(LexError::Unauthorized)
+    }
+}
+
+#[ic_cdk::update]
+fn set_llm_config(update: LlmConfigUpdate) -> LexResult<LlmConfigView> {
+    ensure_controller()?;
+    config::update_llm_config(update)
+}
+
+#[ic_cdk::query]
+fn get_llm_config() -> LexResult<LlmConfigView> {
+    ensure_controller()?;
+    Ok(config::llm_config().view())
+}
+
+#[ic_cdk::query]
+fn get_rate_limits() -> LexResult<Vec<(String, RateLimit)>> {
+    ensure_controller()?;
+    Ok(limits::rate_limits())
+}
+
+// Overrides the per-principal limit of a paid endpoint; `None` restores the
+// default.
+#[ic_cdk::update]
+fn set_rate_limit(endpoint: String, limit: Option<RateLimit>) -> LexResult<()> {
+    ensure_controller()?;
+    limits::set_rate_limit(&endpoint, limit)
+}
+
+#[ic_cdk::update]
+fn block_principal(principal: Principal, reason: Option<String>) -> LexResult<()> {
+    ensure_controller()?;
+    if ic_cdk::api::is_controller(&principal) {
+        return Err(LexError::validation("controllers cannot be blocked"));
+    }
+    limits::block(principal, reason, msg_caller());
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn unblock_principal(principal: Principal) -> LexResult<()> {
+    ensure_controller()?;
+    if !limits::unblock(principal) {
+        return Err(LexError::not_found("Blocked principal", &principal.to_text()));
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_blocked_principals() -> LexResult<Vec<BlockEntry>> {
+    ensure_controller()?;
+    Ok(limits::blocklist())
+}
+
+// Admins manage system templates; controllers always are admins.
+#[ic_cdk::update]
+fn add_admin(principal: Principal) -> LexResult<()> {
+    ensure_controller()?;
+    if principal == Principal::anonymous() {
+        return Err(LexError::validation("the anonymous principal cannot be an admin"));
+    }
+    roles::add_admin(principal, msg_caller());
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn remove_admin(principal: Principal) -> LexResult<()> {
+    ensure_controller()?;
+    if !roles::remove_admin(principal) {
+        return Err(LexError::not_found("Admin", &principal.to_text()));
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_admins() -> LexResult<Vec<AdminEntry>> {
+    ensure_controller()?;
+    Ok(roles::admins())
 }
 
 // HTTP Response Transformation
+//
+// Every replica runs this on its own copy of the provider reply. Only the
+// canonicalized body and a fixed set of headers are kept, so replies that
+// differ in ids, timestamps or header order still reach consensus.
 #[ic_cdk::query]
 fn transform(raw: TransformArgs) -> HttpRequestResult {
     let headers = vec![
@@ -688,9 +1941,80 @@ fn transform(raw: TransformArgs) -> HttpRequestResult {
         },
     ];
 
+    let body = llm::canonicalize_response(&raw);
     HttpRequestResult {
         status: raw.response.status,
         headers,
-        body: raw.response.body,
+        body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use templates::FieldType;
+
+    const ALL_MODES: [RenderMode; 3] = [RenderMode::Deterministic, RenderMode::LlmAssisted, RenderMode::LlmPolish];
+
+    fn revision(template_text: &str, fields: Vec<TemplateField>, render_mode: RenderMode) -> TemplateRevision {
+        TemplateRevision {
+            template_id: "letter".to_string(),
+            version: 1,
+            name: "Letter".to_string(),
+            template_text: template_text.to_string(),
+            fields,
+            owner: None,
+            created_at: 0,
+            created_by: None,
+            render_mode,
+            metadata: TemplateMetadata::default(),
+        }
+    }
+
+    fn optional(name: &str) -> TemplateField {
+        TemplateField {
+            required: false,
+            ..TemplateField::required(name, name, FieldType::Text)
+        }
+    }
+
+    fn values(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn render_mode_picks_the_system_prompt() {
+        let fields = vec![TemplateField::required("name", "Name", FieldType::Party)];
+        let prompt = |mode| {
+            let revision = revision("Dear {name}", fields.clone(), mode);
+            document_request(&revision, &values(&[("name", "Ada")]))
+        };
+        let (rendered, system_prompt) = prompt(RenderMode::Deterministic).unwrap();
+        assert_eq!((rendered.text.as_str(), system_prompt), ("Dear Ada", None));
+        assert_eq!(prompt(RenderMode::LlmAssisted).unwrap().1, Some(DOCUMENT_SYSTEM_PROMPT));
+        assert_eq!(prompt(RenderMode::LlmPolish).unwrap().1, Some(POLISH_SYSTEM_PROMPT));
+    }
+
+    #[test]
+    fn left_out_optional_fields_render_empty_in_every_mode() {
+        let fields = vec![TemplateField::required("name", "Name", FieldType::Party), optional("note")];
+        let text = "Dear {name}.{#if note} Note: {note}{/if} [{note}]";
+        for mode in ALL_MODES {
+            let revision = revision(text, fields.clone(), mode);
+            let (rendered, _) = document_request(&revision, &values(&[("name", "Ada")])).unwrap();
+            assert_eq!(rendered.text, "Dear Ada. []");
+        }
+    }
+
+    #[test]
+    fn variables_outside_the_schema_fail_in_every_mode() {
+        // A revision whose text refers to a field it does not declare, which
+        // validation only lets through for revisions stored before it.
+        let fields = vec![TemplateField::required("name", "Name", FieldType::Party)];
+        for mode in ALL_MODES {
+            let revision = revision("Dear {name} of {city}", fields.clone(), mode);
+            let result = document_request(&revision, &values(&[("name", "Ada")]));
+            assert!(matches!(result, Err(LexError::Validation { message }) if message == "unknown variable city"));
+        }
     }
 }
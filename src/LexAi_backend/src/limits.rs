@@ -0,0 +1,320 @@
+use crate::error::{LexError, LexResult};
+use crate::migrations::{decode_versioned, encode_versioned, Versioned};
+use crate::{CanisterMemory, KeyPrincipal, KeyString, OwnerKey, MEMORY_MANAGER};
+use candid::{CandidType, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    storable::Bound,
+    StableBTreeMap, Storable,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Endpoints that spend cycles on LLM outcalls, with their default limits.
+const PAID_ENDPOINTS: &[(&str, RateLimit)] = &[
+    ("chat_in_session", RateLimit { max_requests: 20, window_secs: 60 }),
+    ("generate_document", RateLimit { max_requests: 20, window_secs: 3_600 }),
+    ("retry_job", RateLimit { max_requests: 10, window_secs: 60 }),
+];
+
+// At most `max_requests` calls in any `window_secs` long window.
+#[derive(Clone, Copy, Debug, CandidType, Deserialize)]
+pub struct RateLimit {
+    pub max_requests: u32,
+    pub window_secs: u64,
+}
+
+impl Storable for RateLimit {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for RateLimit {
+    const VERSION: u16 = 1;
+}
+
+// Times of a principal's recent calls to one endpoint, oldest first. Never
+// longer than the endpoint's `max_requests`.
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct CallLog {
+    calls: Vec<u64>,
+}
+
+impl Storable for CallLog {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for CallLog {
+    const VERSION: u16 = 1;
+}
+
+impl CallLog {
+    // Forgets calls that have left the window ending at `now`, then counts a
+    // call at `now` if `limit` allows one. A refused call is not counted.
+    fn admit(&mut self, limit: RateLimit, now: u64) -> LexResult<()> {
+        let window = limit.window_secs.saturating_mul(NANOS_PER_SEC);
+        self.calls.retain(|&at| now.saturating_sub(at) < window);
+        let excess = (self.calls.len() + 1).saturating_sub(limit.max_requests as usize);
+        if excess > 0 {
+            // The window slides enough for one more call once the oldest
+            // call that keeps the caller at the limit has aged out.
+            let oldest = self.calls[excess - 1];
+            let retry_after_secs = (oldest + window).saturating_sub(now).div_ceil(NANOS_PER_SEC);
+            return Err(LexError::RateLimited { retry_after_secs });
+        }
+        self.calls.push(now);
+        Ok(())
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct BlockEntry {
+    pub principal: Principal,
+    pub reason: Option<String>,
+    pub blocked_at: u64,
+    pub blocked_by: Principal,
+}
+
+impl Storable for BlockEntry {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for BlockEntry {
+    const VERSION: u16 = 1;
+}
+
+thread_local! {
+    // Keyed by (caller, endpoint).
+    static CALL_LOGS: RefCell<StableBTreeMap<OwnerKey, CallLog, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(11)));
+        StableBTreeMap::init(memory)
+    });
+
+    static BLOCKLIST: RefCell<StableBTreeMap<KeyPrincipal, BlockEntry, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(12)));
+        StableBTreeMap::init(memory)
+    });
+
+    // Controller overrides of the defaults in `PAID_ENDPOINTS`.
+    static RATE_LIMITS: RefCell<StableBTreeMap<KeyString, RateLimit, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(13)));
+        StableBTreeMap::init(memory)
+    });
+}
+
+// Admits a call to a paid endpoint and counts it against the caller's limit.
+// Anonymous and blocked callers are refused outright.
+pub fn admit_paid_call(caller: Principal, endpoint: &str) -> LexResult<()> {
+    check_caller(caller, is_blocked)?;
+    let limit = rate_limit(endpoint).expect("not a paid endpoint");
+    let key = log_key(caller, endpoint);
+    CALL_LOGS.with(|logs| {
+        let mut logs = logs.borrow_mut();
+        let mut log = logs.get(&key).unwrap_or_default();
+        let admitted = log.admit(limit, time());
+        logs.insert(key, log);
+        admitted
+    })
+}
+
+fn check_caller(caller: Principal, is_blocked: impl FnOnce(Principal) -> bool) -> LexResult<()> {
+    if caller == Principal::anonymous() || is_blocked(caller) {
+        return Err(LexError::Unauthorized);
+    }
+    Ok(())
+}
+
+// Each caller has a separate log for each endpoint.
+fn log_key(caller: Principal, endpoint: &str) -> OwnerKey {
+    OwnerKey {
+        owner: caller,
+        id: endpoint.to_string(),
+    }
+}
+
+// Takes back the call `admit_paid_call` counted earlier in this message, for
+// a call that was refused before doing any work, such as one over quota.
+pub fn release_paid_call(caller: Principal, endpoint: &str) {
+    let now = time();
+    let key = log_key(caller, endpoint);
+    CALL_LOGS.with(|logs| {
+        let mut logs = logs.borrow_mut();
+        if let Some(mut log) = logs.get(&key) {
+            if let Some(position) = log.calls.iter().rposition(|&at| at == now) {
+                log.calls.remove(position);
+                logs.insert(key, log);
+            }
+        }
+    });
+}
+
+fn rate_limit(endpoint: &str) -> Option<RateLimit> {
+    let default = PAID_ENDPOINTS.iter().find(|(name, _)| *name == endpoint)?.1;
+    let configured = RATE_LIMITS.with(|limits| limits.borrow().get(&KeyString(endpoint.to_string())));
+    Some(configured.unwrap_or(default))
+}
+
+pub fn rate_limits() -> Vec<(String, RateLimit)> {
+    PAID_ENDPOINTS
+        .iter()
+        .map(|(name, _)| (name.to_string(), rate_limit(name).unwrap()))
+        .collect()
+}
+
+// Overrides the limit of a paid endpoint; `None` restores the default.
+pub fn set_rate_limit(endpoint: &str, limit: Option<RateLimit>) -> LexResult<()> {
+    if rate_limit(endpoint).is_none() {
+        return Err(LexError::not_found("Endpoint", endpoint));
+    }
+    let key = KeyString(endpoint.to_string());
+    RATE_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        match limit {
+            Some(limit) if limit.max_requests == 0 || limit.window_secs == 0 => {
+                Err(LexError::validation("max_requests and window_secs must be positive"))
+            }
+            Some(limit) => {
+                limits.insert(key, limit);
+                Ok(())
+            }
+            None => {
+                limits.remove(&key);
+                Ok(())
+            }
+        }
+    })
+}
+
+pub fn is_blocked(principal: Principal) -> bool {
+    BLOCKLIST.with(|blocklist| blocklist.borrow().contains_key(&KeyPrincipal(principal)))
+}
+
+pub fn block(principal: Principal, reason: Option<String>, blocked_by: Principal) {
+    BLOCKLIST.with(|blocklist| {
+        blocklist.borrow_mut().insert(
+            KeyPrincipal(principal),
+            BlockEntry {
+                principal,
+                reason,
+                blocked_at: time(),
+                blocked_by,
+            },
+        );
+    });
+}
+
+// Returns whether the principal was blocked.
+pub fn unblock(principal: Principal) -> bool {
+    BLOCKLIST.with(|blocklist| blocklist.borrow_mut().remove(&KeyPrincipal(principal)).is_some())
+}
+
+pub fn blocklist() -> Vec<BlockEntry> {
+    BLOCKLIST.with(|blocklist| blocklist.borrow().iter().map(|(_, entry)| entry).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: u64 = NANOS_PER_SEC;
+
+    fn limit(max_requests: u32, window_secs: u64) -> RateLimit {
+        RateLimit { max_requests, window_secs }
+    }
+
+    fn retry_after(result: LexResult<()>) -> Option<u64> {
+        match result {
+            Err(LexError::RateLimited { retry_after_secs }) => Some(retry_after_secs),
+            _ => None,
+        }
+    }
+
+    fn user(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    #[test]
+    fn calls_within_the_limit_are_admitted() {
+        let mut log = CallLog::default();
+        for at in 0..3 {
+            assert!(log.admit(limit(3, 60), 100 * SEC + at * SEC).is_ok());
+        }
+        assert_eq!(log.calls.len(), 3);
+    }
+
+    #[test]
+    fn a_call_over_the_limit_is_refused_until_the_oldest_ages_out() {
+        let mut log = CallLog::default();
+        log.admit(limit(2, 60), 100 * SEC).unwrap();
+        log.admit(limit(2, 60), 110 * SEC).unwrap();
+        assert_eq!(retry_after(log.admit(limit(2, 60), 130 * SEC)), Some(30));
+        assert_eq!(log.calls, vec![100 * SEC, 110 * SEC]);
+        assert_eq!(retry_after(log.admit(limit(2, 60), 160 * SEC - 1)), Some(1));
+        assert!(log.admit(limit(2, 60), 160 * SEC).is_ok());
+        assert_eq!(log.calls, vec![110 * SEC, 160 * SEC]);
+    }
+
+    #[test]
+    fn expired_calls_are_pruned() {
+        let mut log = CallLog {
+            calls: vec![0, 10 * SEC, 20 * SEC],
+        };
+        log.admit(limit(5, 30), 45 * SEC).unwrap();
+        assert_eq!(log.calls, vec![20 * SEC, 45 * SEC]);
+    }
+
+    #[test]
+    fn a_lowered_limit_waits_for_enough_calls_to_age_out() {
+        let mut log = CallLog {
+            calls: vec![0, 10 * SEC, 20 * SEC, 30 * SEC],
+        };
+        // Only one call may stay in the window, so the third-oldest must go first.
+        assert_eq!(retry_after(log.admit(limit(2, 60), 40 * SEC)), Some(40));
+    }
+
+    #[test]
+    fn endpoints_and_callers_have_separate_logs() {
+        let chat = log_key(user(1), "chat_in_session");
+        assert!(chat != log_key(user(1), "retry_job"));
+        assert!(chat != log_key(user(2), "chat_in_session"));
+        assert!(chat == log_key(user(1), "chat_in_session"));
+
+        let mut chat_log = CallLog::default();
+        let mut retry_log = CallLog::default();
+        chat_log.admit(limit(1, 60), 0).unwrap();
+        assert!(chat_log.admit(limit(1, 60), SEC).is_err());
+        assert!(retry_log.admit(limit(1, 60), SEC).is_ok());
+    }
+
+    #[test]
+    fn blocked_and_anonymous_callers_are_refused() {
+        assert!(check_caller(user(1), |_| false).is_ok());
+        assert!(matches!(check_caller(user(1), |blocked| blocked == user(1)), Err(LexError::Unauthorized)));
+        assert!(check_caller(user(2), |blocked| blocked == user(1)).is_ok());
+        assert!(matches!(
+            check_caller(Principal::anonymous(), |_| panic!("anonymous callers are not looked up")),
+            Err(LexError::Unauthorized)
+        ));
+    }
+}
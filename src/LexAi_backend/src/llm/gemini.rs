@@ -0,0 +1,156 @@
+use super::{
+    json_body, transform_context, LlmProvider, LlmProviderKind, LlmRequest, LlmResponse, LlmRole, Projection,
+    ProviderEndpoint, TokenUsage, MAX_RESPONSE_BYTES,
+};
+use crate::error::{LexError, LexResult};
+use ic_cdk::management_canister::{HttpHeader, HttpMethod, HttpRequestArgs, HttpRequestResult};
+use serde_json::json;
+
+// Google Gemini `generateContent` API.
+pub struct GeminiProvider {
+    endpoint: ProviderEndpoint,
+}
+
+impl GeminiProvider {
+    pub fn new(endpoint: ProviderEndpoint) -> Self {
+        GeminiProvider { endpoint }
+    }
+}
+
+impl LlmProvider for GeminiProvider {
+    fn name(&self) -> &'static str {
+        "Gemini"
+    }
+
+    fn kind(&self) -> LlmProviderKind {
+        LlmProviderKind::Gemini
+    }
+
+    fn build_request(&self, request: &LlmRequest) -> HttpRequestArgs {
+        let url = format!(
+            "{}/models/{}:generateContent",
+            self.endpoint.base_url.trim_end_matches('/'),
+            self.endpoint.model
+        );
+
+        // Gemini expects turns to alternate between `user` and `model`, so
+        // consecutive messages from the same role become parts of one turn.
+        let mut contents: Vec<serde_json::Value> = Vec::new();
+        let mut last_role = None;
+        for msg in &request.messages {
+            let role = match msg.role {
+                LlmRole::User => "user",
+                LlmRole::Assistant => "model",
+            };
+            let part = json!({ "text": msg.content });
+            match contents.last_mut() {
+                Some(turn) if last_role == Some(role) => turn["parts"].as_array_mut().unwrap().push(part),
+                _ => contents.push(json!({ "role": role, "parts": [part] })),
+            }
+            last_role = Some(role);
+        }
+
+        let generation = &request.generation;
+        let mut generation_config = json!({
+            "temperature": generation.temperature,
+            "maxOutputTokens": generation.max_output_tokens,
+            "stopSequences": generation.stop_sequences,
+        });
+        if let Some(top_k) = generation.top_k {
+            generation_config["topK"] = json!(top_k);
+        }
+        if let Some(top_p) = generation.top_p {
+            generation_config["topP"] = json!(top_p);
+        }
+
+        let mut json_body = json!({
+            "contents": contents,
+            "generationConfig": generation_config,
+        });
+        if let Some(system_prompt) = &request.system_prompt {
+            json_body["systemInstruction"] = json!({ "parts": [{ "text": system_prompt }] });
+        }
+
+        HttpRequestArgs {
+            method: HttpMethod::POST,
+            url,
+            // The key goes in a header rather than the URL so it never shows
+            // up in logged request URLs.
+            headers: vec![
+                HttpHeader {
+                    name: "Content-Type".to_string(),
+                    value: "application/json".to_string(),
+                },
+                HttpHeader {
+                    name: "x-goog-api-key".to_string(),
+                    value: self.endpoint.api_key.clone(),
+                },
+            ],
+            body: Some(serde_json::to_vec(&json_body).unwrap()),
+            max_response_bytes: Some(MAX_RESPONSE_BYTES),
+            transform: Some(transform_context(self.kind(), request.projection)),
+        }
+    }
+
+    fn parse_response(&self, response: HttpRequestResult) -> LexResult<LlmResponse> {
+        let (status, json) = json_body(self.name(), response)?;
+        let candidate = &json["candidates"][0];
+        let Some(parts) = candidate["content"]["parts"].as_array() else {
+            ic_cdk::println!("Error: Invalid response structure");
+            return Err(LexError::upstream(Some(status), "invalid response structure"));
+        };
+        let text = parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        if text.is_empty() {
+            ic_cdk::println!("Error: No valid text in response");
+            return Err(LexError::upstream(Some(status), "no valid text in response"));
+        }
+
+        let usage = &json["usageMetadata"];
+        Ok(LlmResponse {
+            text,
+            finish_reason: candidate["finishReason"].as_str().map(str::to_string),
+            usage: usage.is_object().then(|| TokenUsage {
+                prompt_tokens: usage["promptTokenCount"].as_u64().unwrap_or(0),
+                completion_tokens: usage["candidatesTokenCount"].as_u64().unwrap_or(0),
+                total_tokens: usage["totalTokenCount"].as_u64().unwrap_or(0),
+            }),
+        })
+    }
+}
+
+// Keeps the joined reply text and finish reason of the first candidate, plus
+// token counts when the projection asks for them.
+pub(super) fn canonicalize(json: &serde_json::Value, projection: Projection) -> serde_json::Value {
+    let candidate = &json["candidates"][0];
+    let text = candidate["content"]["parts"].as_array().map(|parts| {
+        parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    });
+    let mut canonical = json!({
+        "candidates": [{
+            "content": { "parts": text.map(|text| vec![json!({ "text": text })]) },
+            "finishReason": candidate["finishReason"],
+        }],
+    });
+    let usage = &json["usageMetadata"];
+    if projection == Projection::ReplyWithUsage && usage.is_object() {
+        canonical["usageMetadata"] = json!({
+            "promptTokenCount": usage["promptTokenCount"],
+            "candidatesTokenCount": usage["candidatesTokenCount"],
+            "totalTokenCount": usage["totalTokenCount"],
+        });
+    }
+    canonical
+}
+
+// Gemini errors carry a stable gRPC-style status such as `RESOURCE_EXHAUSTED`.
+pub(super) fn error_code(json: &serde_json::Value) -> Option<&str> {
+    json["error"]["status"].as_str()
+}
@@ -0,0 +1,380 @@
+use crate::error::{LexError, LexResult};
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::{
+    api::canister_self,
+    management_canister::{cost_http_request, http_request, HttpRequestArgs, HttpRequestResult, TransformArgs, TransformContext, TransformFunc},
+};
+use serde::Deserialize;
+use serde_json::json;
+
+mod gemini;
+mod openai;
+
+pub use gemini::GeminiProvider;
+pub use openai::OpenAiProvider;
+
+// Upper bound on the size of an LLM response we are willing to pay for.
+pub(crate) const MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum LlmRole {
+    User,
+    Assistant,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LlmMessage {
+    pub role: LlmRole,
+    pub content: String,
+}
+
+impl LlmMessage {
+    pub fn user(content: impl Into<String>) -> Self {
+        LlmMessage {
+            role: LlmRole::User,
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GenerationConfig {
+    pub temperature: f32,
+    pub top_k: Option<u32>,
+    pub top_p: Option<f32>,
+    pub max_output_tokens: u32,
+    pub stop_sequences: Vec<String>,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig {
+            temperature: 0.3,
+            top_k: Some(40),
+            top_p: Some(0.95),
+            max_output_tokens: 2048,
+            stop_sequences: vec![],
+        }
+    }
+}
+
+// Which parts of a provider reply survive the outcall transform. Anything
+// not projected is dropped before consensus, so it cannot make replicas
+// disagree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Projection {
+    // Reply text and finish reason.
+    Reply,
+    // Reply text, finish reason and token usage.
+    ReplyWithUsage,
+}
+
+#[derive(Clone, Debug)]
+pub struct LlmRequest {
+    pub system_prompt: Option<String>,
+    pub messages: Vec<LlmMessage>,
+    pub generation: GenerationConfig,
+    pub projection: Projection,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+// What a single outcall took, whether or not it succeeded.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct OutcallCost {
+    // Attached to the call; whatever the subnet does not charge is refunded.
+    pub cycles_attached: u128,
+    pub request_bytes: u64,
+    // Size of the response after the transform, headers included.
+    pub response_bytes: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct LlmResponse {
+    pub text: String,
+    pub finish_reason: Option<String>,
+    pub usage: Option<TokenUsage>,
+}
+
+// Where and as whom a provider sends its requests.
+#[derive(Clone, Debug)]
+pub struct ProviderEndpoint {
+    pub base_url: String,
+    pub api_key: String,
+    pub model: String,
+}
+
+// A vendor API that can turn an `LlmRequest` into an HTTPS outcall and parse
+// the reply. Providers only shape requests and responses; `complete` performs
+// the outcall so transform, cycles and error handling stay in one place.
+pub trait LlmProvider {
+    fn name(&self) -> &'static str;
+    fn kind(&self) -> LlmProviderKind;
+    fn build_request(&self, request: &LlmRequest) -> HttpRequestArgs;
+    fn parse_response(&self, response: HttpRequestResult) -> LexResult<LlmResponse>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum LlmProviderKind {
+    Gemini,
+    OpenAiCompatible,
+}
+
+impl LlmProviderKind {
+    pub fn default_endpoint(self) -> ProviderEndpoint {
+        match self {
+            LlmProviderKind::Gemini => ProviderEndpoint {
+                base_url: "https://generativelanguage.googleapis.com/v1beta".to_string(),
+                api_key: String::new(),
+                model: "gemini-1.5-flash".to_string(),
+            },
+            LlmProviderKind::OpenAiCompatible => ProviderEndpoint {
+                base_url: "https://api.openai.com/v1".to_string(),
+                api_key: String::new(),
+                model: "gpt-4o-mini".to_string(),
+            },
+        }
+    }
+
+    pub fn provider(self, endpoint: ProviderEndpoint) -> Box<dyn LlmProvider> {
+        match self {
+            LlmProviderKind::Gemini => Box::new(GeminiProvider::new(endpoint)),
+            LlmProviderKind::OpenAiCompatible => Box::new(OpenAiProvider::new(endpoint)),
+        }
+    }
+}
+
+// Whether a failed completion is worth repeating: transport failures,
+// timeouts, rate limiting and server-side errors are; anything the provider
+// rejected on its merits is not.
+pub fn is_retryable(error: &LexError) -> bool {
+    match error {
+        LexError::UpstreamLlm { status: None, .. } => true,
+        LexError::UpstreamLlm { status: Some(status), .. } => matches!(status, 408 | 429 | 500 | 502 | 503 | 504),
+        _ => false,
+    }
+}
+
+pub async fn complete(provider: &dyn LlmProvider, request: &LlmRequest) -> (OutcallCost, LexResult<LlmResponse>) {
+    let args = provider.build_request(request);
+    let mut cost = OutcallCost {
+        cycles_attached: cost_http_request(&args),
+        request_bytes: (args.url.len()
+            + args.headers.iter().map(|h| h.name.len() + h.value.len()).sum::<usize>()
+            + args.body.as_ref().map_or(0, Vec::len)) as u64,
+        response_bytes: 0,
+    };
+    let result = match http_request(&args).await {
+        Ok(res) => {
+            cost.response_bytes = (res.body.len()
+                + res.headers.iter().map(|h| h.name.len() + h.value.len()).sum::<usize>()) as u64;
+            provider.parse_response(res)
+        }
+        Err(e) => {
+            ic_cdk::println!("{} API request failed: {:?}", provider.name(), e);
+            Err(LexError::upstream(None, format!("{:?}", e)))
+        }
+    };
+    (cost, result)
+}
+
+// Carried in the transform context so `transform` knows how to canonicalize
+// the reply of a given outcall.
+#[derive(CandidType, Deserialize)]
+struct TransformSpec {
+    provider: LlmProviderKind,
+    projection: Projection,
+}
+
+pub(crate) fn transform_context(provider: LlmProviderKind, projection: Projection) -> TransformContext {
+    TransformContext {
+        function: TransformFunc::new(canister_self(), "transform".into()),
+        context: Encode!(&TransformSpec { provider, projection }).unwrap(),
+    }
+}
+
+// Reduces a raw provider reply to a canonical JSON body containing only the
+// projected fields, so that every replica ends up with identical bytes.
+// Error replies (and bodies that are not JSON at all) become
+// `{"error": {"code": <status>, "status": <error code>}}`, dropping free-form
+// messages and request ids.
+pub fn canonicalize_response(raw: &TransformArgs) -> Vec<u8> {
+    let status = response_status(&raw.response);
+    let spec = Decode!(&raw.context, TransformSpec).ok();
+    let json: Option<serde_json::Value> = serde_json::from_slice(&raw.response.body).ok();
+    let canonical = match (spec, json) {
+        (Some(spec), Some(json)) if (200..300).contains(&status) => match spec.provider {
+            LlmProviderKind::Gemini => gemini::canonicalize(&json, spec.projection),
+            LlmProviderKind::OpenAiCompatible => openai::canonicalize(&json, spec.projection),
+        },
+        (Some(spec), Some(json)) => {
+            let code = match spec.provider {
+                LlmProviderKind::Gemini => gemini::error_code(&json),
+                LlmProviderKind::OpenAiCompatible => openai::error_code(&json),
+            };
+            canonical_error(status, code.unwrap_or("UNKNOWN"))
+        }
+        (Some(_), None) => canonical_error(status, "UNPARSEABLE_RESPONSE"),
+        (None, _) => canonical_error(status, "INVALID_TRANSFORM_CONTEXT"),
+    };
+    serde_json::to_vec(&canonical).unwrap()
+}
+
+fn canonical_error(status: u16, code: &str) -> serde_json::Value {
+    json!({ "error": { "code": status, "status": code } })
+}
+
+pub(crate) fn response_status(response: &HttpRequestResult) -> u16 {
+    u16::try_from(&response.status.0).unwrap_or(u16::MAX)
+}
+
+// Splits a canonicalized response into its status and JSON body, turning
+// non-2xx replies into `UpstreamLlm` errors.
+pub(crate) fn json_body(provider: &str, response: HttpRequestResult) -> LexResult<(u16, serde_json::Value)> {
+    let status = response_status(&response);
+    let body_str = String::from_utf8(response.body).unwrap_or_default();
+    ic_cdk::println!("{} API response: status {}, {} bytes", provider, status, body_str.len());
+    let json: serde_json::Value = serde_json::from_str(&body_str).unwrap_or_default();
+    if !(200..300).contains(&status) || json.get("error").is_some() {
+        let reason = json["error"]["status"].as_str().unwrap_or("unexpected status").to_string();
+        return Err(LexError::upstream(Some(status), reason));
+    }
+    Ok((status, json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Nat;
+    use ic_cdk::management_canister::HttpHeader;
+
+    fn reply(provider: LlmProviderKind, projection: Projection, status: u16, body: &str) -> TransformArgs {
+        TransformArgs {
+            response: HttpRequestResult {
+                status: Nat::from(status),
+                headers: vec![],
+                body: body.as_bytes().to_vec(),
+            },
+            context: Encode!(&TransformSpec { provider, projection }).unwrap(),
+        }
+    }
+
+    fn with_headers(mut raw: TransformArgs, headers: &[(&str, &str)]) -> TransformArgs {
+        raw.response.headers = headers
+            .iter()
+            .map(|(name, value)| HttpHeader {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+            .collect();
+        raw
+    }
+
+    fn canonical(raw: &TransformArgs) -> serde_json::Value {
+        serde_json::from_slice(&canonicalize_response(raw)).unwrap()
+    }
+
+    const GEMINI_REPLY: &str = r#"{
+        "candidates": [{
+            "content": {"role": "model", "parts": [{"text": "Dear"}, {"text": "Ada"}]},
+            "finishReason": "STOP",
+            "safetyRatings": [{"category": "HARM_CATEGORY_HARASSMENT", "probability": "NEGLIGIBLE"}]
+        }],
+        "usageMetadata": {"promptTokenCount": 10, "candidatesTokenCount": 2, "totalTokenCount": 12},
+        "modelVersion": "gemini-1.5-flash-002",
+        "responseId": "a1b2c3"
+    }"#;
+
+    // The same reply as another replica sees it: fields in another order and
+    // different volatile values.
+    const GEMINI_REPLY_REORDERED: &str = r#"{
+        "responseId": "z9y8x7",
+        "usageMetadata": {"totalTokenCount": 12, "candidatesTokenCount": 2, "promptTokenCount": 10},
+        "candidates": [{
+            "finishReason": "STOP",
+            "content": {"parts": [{"text": "Dear"}, {"text": "Ada"}], "role": "model"}
+        }],
+        "modelVersion": "gemini-1.5-flash-003"
+    }"#;
+
+    #[test]
+    fn gemini_replies_differing_in_headers_and_field_order_agree() {
+        let gemini = |body| reply(LlmProviderKind::Gemini, Projection::ReplyWithUsage, 200, body);
+        let first = with_headers(
+            gemini(GEMINI_REPLY),
+            &[("date", "Mon, 01 Jan 2024 00:00:00 GMT"), ("x-request-id", "1")],
+        );
+        let second = with_headers(
+            gemini(GEMINI_REPLY_REORDERED),
+            &[("x-request-id", "2"), ("date", "Mon, 01 Jan 2024 00:00:01 GMT"), ("server-timing", "dur=512")],
+        );
+        assert_eq!(canonicalize_response(&first), canonicalize_response(&second));
+        assert_eq!(
+            canonical(&first),
+            json!({
+                "candidates": [{"content": {"parts": [{"text": "Dear\nAda"}]}, "finishReason": "STOP"}],
+                "usageMetadata": {"promptTokenCount": 10, "candidatesTokenCount": 2, "totalTokenCount": 12},
+            })
+        );
+    }
+
+    #[test]
+    fn openai_replies_differing_in_volatile_fields_agree() {
+        let body = |id: &str, created: u64, fingerprint: &str| {
+            format!(
+                r#"{{"id": "{}", "object": "chat.completion", "created": {}, "system_fingerprint": "{}",
+                    "choices": [{{"index": 0, "finish_reason": "stop",
+                                  "message": {{"role": "assistant", "content": "Dear Ada"}}}}],
+                    "usage": {{"prompt_tokens": 10, "completion_tokens": 2, "total_tokens": 12}}}}"#,
+                id, created, fingerprint
+            )
+        };
+        let openai = |body: &str| {
+            canonicalize_response(&reply(LlmProviderKind::OpenAiCompatible, Projection::ReplyWithUsage, 200, body))
+        };
+        assert_eq!(
+            openai(&body("chatcmpl-1", 1_700_000_000, "fp_a")),
+            openai(&body("chatcmpl-2", 1_700_000_001, "fp_b"))
+        );
+    }
+
+    #[test]
+    fn usage_is_only_kept_when_projected() {
+        let canonical = canonical(&reply(LlmProviderKind::Gemini, Projection::Reply, 200, GEMINI_REPLY));
+        assert!(canonical.get("usageMetadata").is_none());
+        assert_eq!(canonical["candidates"][0]["content"]["parts"][0]["text"], "Dear\nAda");
+    }
+
+    #[test]
+    fn error_statuses_are_kept_and_messages_dropped() {
+        let gemini = |message: &str| {
+            let body = format!(
+                r#"{{"error": {{"code": 429, "message": "{}", "status": "RESOURCE_EXHAUSTED"}}}}"#,
+                message
+            );
+            reply(LlmProviderKind::Gemini, Projection::ReplyWithUsage, 429, &body)
+        };
+        let first = gemini("Quota exceeded, retry in 31s");
+        assert_eq!(canonicalize_response(&first), canonicalize_response(&gemini("Quota exceeded, retry in 30s")));
+        assert_eq!(canonical(&first), json!({"error": {"code": 429, "status": "RESOURCE_EXHAUSTED"}}));
+
+        let body = r#"{"error": {"message": "Rate limit reached", "type": "requests", "code": "rate_limit_exceeded"}}"#;
+        let openai = reply(LlmProviderKind::OpenAiCompatible, Projection::Reply, 429, body);
+        assert_eq!(canonical(&openai), json!({"error": {"code": 429, "status": "rate_limit_exceeded"}}));
+    }
+
+    #[test]
+    fn unparseable_bodies_and_contexts_keep_the_status() {
+        let html = reply(LlmProviderKind::Gemini, Projection::Reply, 502, "<html>Bad Gateway</html>");
+        assert_eq!(canonical(&html), json!({"error": {"code": 502, "status": "UNPARSEABLE_RESPONSE"}}));
+
+        let mut bad_context = reply(LlmProviderKind::Gemini, Projection::Reply, 200, GEMINI_REPLY);
+        bad_context.context = b"not candid".to_vec();
+        assert_eq!(canonical(&bad_context), json!({"error": {"code": 200, "status": "INVALID_TRANSFORM_CONTEXT"}}));
+    }
+}
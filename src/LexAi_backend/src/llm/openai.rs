@@ -0,0 +1,125 @@
+use super::{
+    json_body, transform_context, LlmProvider, LlmProviderKind, LlmRequest, LlmResponse, LlmRole, Projection,
+    ProviderEndpoint, TokenUsage, MAX_RESPONSE_BYTES,
+};
+use crate::error::{LexError, LexResult};
+use ic_cdk::management_canister::{HttpHeader, HttpMethod, HttpRequestArgs, HttpRequestResult};
+use serde_json::json;
+
+// OpenAI `chat/completions` API, or any server speaking the same protocol.
+pub struct OpenAiProvider {
+    endpoint: ProviderEndpoint,
+}
+
+impl OpenAiProvider {
+    pub fn new(endpoint: ProviderEndpoint) -> Self {
+        OpenAiProvider { endpoint }
+    }
+}
+
+impl LlmProvider for OpenAiProvider {
+    fn name(&self) -> &'static str {
+        "OpenAI-compatible"
+    }
+
+    fn kind(&self) -> LlmProviderKind {
+        LlmProviderKind::OpenAiCompatible
+    }
+
+    fn build_request(&self, request: &LlmRequest) -> HttpRequestArgs {
+        let url = format!("{}/chat/completions", self.endpoint.base_url.trim_end_matches('/'));
+
+        let mut messages = Vec::new();
+        if let Some(system_prompt) = &request.system_prompt {
+            messages.push(json!({ "role": "system", "content": system_prompt }));
+        }
+        for msg in &request.messages {
+            let role = match msg.role {
+                LlmRole::User => "user",
+                LlmRole::Assistant => "assistant",
+            };
+            messages.push(json!({ "role": role, "content": msg.content }));
+        }
+
+        let generation = &request.generation;
+        let mut json_body = json!({
+            "model": self.endpoint.model,
+            "messages": messages,
+            "temperature": generation.temperature,
+            "max_tokens": generation.max_output_tokens,
+        });
+        if let Some(top_p) = generation.top_p {
+            json_body["top_p"] = json!(top_p);
+        }
+        if !generation.stop_sequences.is_empty() {
+            json_body["stop"] = json!(generation.stop_sequences);
+        }
+
+        let mut headers = vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        }];
+        if !self.endpoint.api_key.is_empty() {
+            headers.push(HttpHeader {
+                name: "Authorization".to_string(),
+                value: format!("Bearer {}", self.endpoint.api_key),
+            });
+        }
+
+        HttpRequestArgs {
+            method: HttpMethod::POST,
+            url,
+            headers,
+            body: Some(serde_json::to_vec(&json_body).unwrap()),
+            max_response_bytes: Some(MAX_RESPONSE_BYTES),
+            transform: Some(transform_context(self.kind(), request.projection)),
+        }
+    }
+
+    fn parse_response(&self, response: HttpRequestResult) -> LexResult<LlmResponse> {
+        let (status, json) = json_body(self.name(), response)?;
+        let choice = &json["choices"][0];
+        let text = choice["message"]["content"].as_str().unwrap_or_default();
+        if text.is_empty() {
+            return Err(LexError::upstream(Some(status), "no valid text in response"));
+        }
+
+        let usage = &json["usage"];
+        Ok(LlmResponse {
+            text: text.to_string(),
+            finish_reason: choice["finish_reason"].as_str().map(str::to_string),
+            usage: usage.is_object().then(|| TokenUsage {
+                prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or(0),
+                completion_tokens: usage["completion_tokens"].as_u64().unwrap_or(0),
+                total_tokens: usage["total_tokens"].as_u64().unwrap_or(0),
+            }),
+        })
+    }
+}
+
+// Keeps the reply text and finish reason of the first choice, plus token
+// counts when the projection asks for them.
+pub(super) fn canonicalize(json: &serde_json::Value, projection: Projection) -> serde_json::Value {
+    let choice = &json["choices"][0];
+    let mut canonical = json!({
+        "choices": [{
+            "message": { "content": choice["message"]["content"] },
+            "finish_reason": choice["finish_reason"],
+        }],
+    });
+    let usage = &json["usage"];
+    if projection == Projection::ReplyWithUsage && usage.is_object() {
+        canonical["usage"] = json!({
+            "prompt_tokens": usage["prompt_tokens"],
+            "completion_tokens": usage["completion_tokens"],
+            "total_tokens": usage["total_tokens"],
+        });
+    }
+    canonical
+}
+
+// OpenAI-compatible servers report a machine-readable `code`, falling back to
+// the error `type` on servers that leave it null.
+pub(super) fn error_code(json: &serde_json::Value) -> Option<&str> {
+    json["error"]["code"].as_str().or_else(|| json["error"]["type"].as_str())
+}
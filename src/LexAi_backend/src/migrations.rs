@@ -0,0 +1,611 @@
+use crate::{
+    index_insert, CanisterMemory, ChatMessage, DocumentRecord, KeyString, LegalTemplate, MessageKey, Session, DOCUMENTS,
+    MEMORY_MANAGER, MESSAGES, SESSIONS, SESSION_INDEX, TEMPLATES, UNCLAIMED_DOCUMENTS, USERS,
+};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    storable::Bound,
+    StableBTreeMap, StableCell, Storable,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::time::Duration;
+
+// Schema version of the data layout written by this build. Bump it together
+// with a new entry in `MIGRATIONS` whenever a stored record or map changes.
+pub const CURRENT_SCHEMA_VERSION: u32 = 6;
+
+// Maximum number of entries rewritten per migration batch.
+const MIGRATION_BATCH_SIZE: usize = 200;
+
+// Leading byte of an enveloped record. Legacy Candid records start with
+// "DIDL" and legacy documents are UTF-8 text, neither of which can begin
+// with 0xFF.
+const ENVELOPE_TAG: u8 = 0xFF;
+
+// Versioned record encoding
+//
+// Every stored record is written as `[ENVELOPE_TAG, version (u16 BE), candid...]`
+// so that a build can tell which layout it is reading and upgrade it in place
+// instead of trapping on a failed `Decode!`.
+pub trait Versioned: CandidType + DeserializeOwned {
+    const VERSION: u16;
+
+    // Decodes a record written with an older `version`. Version 0 is the
+    // un-enveloped layout used before schema versioning was introduced.
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        decode_candid(version, bytes)
+    }
+}
+
+pub fn encode_versioned<T: Versioned>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![ENVELOPE_TAG];
+    bytes.extend_from_slice(&T::VERSION.to_be_bytes());
+    bytes.extend(Encode!(value).expect("failed to encode stable record"));
+    bytes
+}
+
+pub fn decode_versioned<T: Versioned>(bytes: &[u8]) -> T {
+    match bytes {
+        [ENVELOPE_TAG, hi, lo, rest @ ..] => {
+            let version = u16::from_be_bytes([*hi, *lo]);
+            if version == T::VERSION {
+                decode_candid(version, rest)
+            } else if version < T::VERSION {
+                T::upgrade(version, rest)
+            } else {
+                ic_cdk::trap(format!(
+                    "stable record has version {} but this build only understands up to {}",
+                    version,
+                    T::VERSION
+                ))
+            }
+        }
+        legacy => T::upgrade(0, legacy),
+    }
+}
+
+pub fn decode_candid<T: CandidType + DeserializeOwned>(version: u16, bytes: &[u8]) -> T {
+    Decode!(bytes, T).unwrap_or_else(|e| {
+        ic_cdk::trap(format!("failed to decode stable record (version {}): {}", version, e))
+    })
+}
+
+// Schema metadata
+#[derive(Clone, CandidType, Deserialize)]
+pub struct MigrationProgress {
+    pub target_version: u32,
+    pub step: u32,
+    pub cursor: Option<Vec<u8>>,
+    pub migrated: u64,
+    pub started_at: u64,
+}
+
+// A canister that has never stored metadata defaults to schema version 0,
+// i.e. it predates schema versioning.
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct SchemaMeta {
+    pub schema_version: u32,
+    pub migration: Option<MigrationProgress>,
+    pub last_upgrade_at: Option<u64>,
+}
+
+impl Storable for SchemaMeta {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_candid(0, &bytes)
+    }
+}
+
+thread_local! {
+    static SCHEMA_META: RefCell<StableCell<SchemaMeta, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(4)));
+        StableCell::init(memory, SchemaMeta::default()).expect("failed to initialize schema metadata")
+    });
+}
+
+pub fn schema_meta() -> SchemaMeta {
+    SCHEMA_META.with(|meta| meta.borrow().get().clone())
+}
+
+pub fn schema_at_least(version: u32) -> bool {
+    schema_meta().schema_version >= version
+}
+
+fn set_schema_meta(meta: SchemaMeta) {
+    SCHEMA_META.with(|cell| {
+        cell.borrow_mut()
+            .set(meta)
+            .expect("failed to persist schema metadata");
+    });
+}
+
+// Migrations
+//
+// A migration moves the stored data from `to - 1` to `to`. Each step walks one
+// map in key order, processing at most `limit` entries after `after` and
+// returning the key to resume from, or `None` once the map is exhausted.
+struct StepOutcome {
+    processed: u64,
+    next: Option<Vec<u8>>,
+}
+
+type MigrationStep = fn(after: Option<Vec<u8>>, limit: usize) -> StepOutcome;
+
+struct Migration {
+    to: u32,
+    description: &'static str,
+    steps: &'static [MigrationStep],
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // Sessions are re-encoded by the v2 migration, which needs their legacy
+    // embedded messages intact.
+    Migration {
+        to: 1,
+        description: "wrap every stored record in a versioned envelope",
+        steps: &[rewrite_users, rewrite_templates, rewrite_documents],
+    },
+    Migration {
+        to: 2,
+        description: "move chat messages out of sessions into their own map",
+        steps: &[move_session_messages],
+    },
+    Migration {
+        to: 3,
+        description: "backfill the per-principal session index",
+        steps: &[index_sessions],
+    },
+    // Documents from before this step have no known owner. They are stored
+    // as owned by the management canister and listed as unclaimed until an
+    // admin assigns them with `assign_document` (see
+    // `DocumentRecord::upgrade`).
+    Migration {
+        to: 4,
+        description: "convert stored documents into owner-tagged document records",
+        steps: &[rewrite_documents, index_unclaimed_documents],
+    },
+    Migration {
+        to: 5,
+        description: "record every existing template as revision 1",
+        steps: &[snapshot_templates],
+    },
+    Migration {
+        to: 6,
+        description: "record every existing clause as revision 1",
+        steps: &[snapshot_clauses],
+    },
+];
+
+fn rewrite_users(after: Option<Vec<u8>>, limit: usize) -> StepOutcome {
+    USERS.with(|map| rewrite_batch(map, after, limit))
+}
+
+fn rewrite_templates(after: Option<Vec<u8>>, limit: usize) -> StepOutcome {
+    TEMPLATES.with(|map| rewrite_batch(map, after, limit))
+}
+
+fn rewrite_documents(after: Option<Vec<u8>>, limit: usize) -> StepOutcome {
+    DOCUMENTS.with(|map| rewrite_batch(map, after, limit))
+}
+
+// Re-inserts a batch of entries so they are re-encoded with the current
+// record version.
+fn rewrite_batch<K, V>(
+    map: &RefCell<StableBTreeMap<K, V, VirtualMemory<CanisterMemory>>>,
+    after: Option<Vec<u8>>,
+    limit: usize,
+) -> StepOutcome
+where
+    K: Storable + Ord + Clone,
+    V: Storable,
+{
+    let mut map = map.borrow_mut();
+    let batch: Vec<(K, V)> = match after {
+        Some(bytes) => map
+            .range((Excluded(K::from_bytes(Cow::Owned(bytes))), Unbounded))
+            .take(limit)
+            .collect(),
+        None => map.iter().take(limit).collect(),
+    };
+    let processed = batch.len() as u64;
+    let next = if batch.len() < limit {
+        None
+    } else {
+        batch.last().map(|(key, _)| key.to_bytes().into_owned())
+    };
+    for (key, value) in batch {
+        map.insert(key, value);
+    }
+    StepOutcome { processed, next }
+}
+
+fn index_unclaimed_documents(after: Option<Vec<u8>>, limit: usize) -> StepOutcome {
+    let batch: Vec<(KeyString, DocumentRecord)> = DOCUMENTS.with(|map| {
+        let map = map.borrow();
+        match after {
+            Some(bytes) => map
+                .range((Excluded(KeyString::from_bytes(Cow::Owned(bytes))), Unbounded))
+                .take(limit)
+                .collect(),
+            None => map.iter().take(limit).collect(),
+        }
+    });
+    let processed = batch.len() as u64;
+    let next = if batch.len() < limit {
+        None
+    } else {
+        batch.last().map(|(key, _)| key.to_bytes().into_owned())
+    };
+    UNCLAIMED_DOCUMENTS.with(|unclaimed| {
+        let mut unclaimed = unclaimed.borrow_mut();
+        for (key, record) in batch {
+            if record.owner == Principal::management_canister() {
+                unclaimed.insert(key, ());
+            }
+        }
+    });
+    StepOutcome { processed, next }
+}
+
+// Session layout before schema v2, with the whole conversation embedded.
+#[derive(CandidType, Deserialize)]
+pub struct LegacySession {
+    session_id: String,
+    principal: Principal,
+    title: Option<String>,
+    created_at: u64,
+    messages: Vec<ChatMessage>,
+}
+
+impl LegacySession {
+    // The session with its messages still embedded, for the v2 migration to
+    // move.
+    pub fn into_session(self) -> Session {
+        Session {
+            session_id: self.session_id,
+            principal: self.principal,
+            title: self.title,
+            created_at: self.created_at,
+            message_count: self.messages.len() as u64,
+            summary: None,
+            pending_job: None,
+            legacy_messages: self.messages,
+        }
+    }
+}
+
+// A session whose messages the v2 migration has not moved yet.
+fn legacy_session(session_id: &str) -> Option<Session> {
+    if schema_at_least(2) {
+        return None;
+    }
+    SESSIONS.with(|map| map.borrow().get(&KeyString(session_id.to_string())))
+        .filter(|session| !session.legacy_messages.is_empty())
+}
+
+// Returns the embedded messages of a session not yet moved by the v2 migration.
+pub fn legacy_session_messages(session_id: &str) -> Option<Vec<ChatMessage>> {
+    legacy_session(session_id).map(|session| session.legacy_messages)
+}
+
+// Moves a single session ahead of the batch migration. Must be called before
+// appending to a session while the v2 migration is still pending.
+pub fn migrate_session(session_id: &str) {
+    if let Some(session) = legacy_session(session_id) {
+        move_legacy_session(KeyString(session_id.to_string()), session);
+    }
+}
+
+// Messages go to the sequence numbers they had in the embedded list.
+fn move_legacy_session(key: KeyString, mut session: Session) {
+    let messages = std::mem::take(&mut session.legacy_messages);
+    MESSAGES.with(|map| {
+        let mut map = map.borrow_mut();
+        for (seq, msg) in messages.into_iter().enumerate() {
+            let msg_key = MessageKey {
+                session_id: key.0.clone(),
+                seq: seq as u64,
+            };
+            map.insert(msg_key, msg);
+        }
+    });
+    SESSIONS.with(|map| {
+        map.borrow_mut().insert(key, session);
+    });
+}
+
+fn move_session_messages(after: Option<Vec<u8>>, limit: usize) -> StepOutcome {
+    let batch: Vec<(KeyString, Session)> = SESSIONS.with(|map| {
+        let map = map.borrow();
+        match after {
+            Some(bytes) => map
+                .range((Excluded(KeyString::from_bytes(Cow::Owned(bytes))), Unbounded))
+                .take(limit)
+                .collect(),
+            None => map.iter().take(limit).collect(),
+        }
+    });
+    let processed = batch.len() as u64;
+    let next = if batch.len() < limit {
+        None
+    } else {
+        batch.last().map(|(key, _)| key.to_bytes().into_owned())
+    };
+    for (key, session) in batch {
+        if !session.legacy_messages.is_empty() {
+            move_legacy_session(key, session);
+        }
+    }
+    StepOutcome { processed, next }
+}
+
+fn index_sessions(after: Option<Vec<u8>>, limit: usize) -> StepOutcome {
+    let batch: Vec<Session> = SESSIONS.with(|map| {
+        let map = map.borrow();
+        match after {
+            Some(bytes) => map
+                .range((Excluded(KeyString::from_bytes(Cow::Owned(bytes))), Unbounded))
+                .take(limit)
+                .map(|(_, session)| session)
+                .collect(),
+            None => map.iter().take(limit).map(|(_, session)| session).collect(),
+        }
+    });
+    let processed = batch.len() as u64;
+    let next = if batch.len() < limit {
+        None
+    } else {
+        batch.last().map(|session| session.session_id.as_bytes().to_vec())
+    };
+    SESSION_INDEX.with(|index| {
+        for session in &batch {
+            index_insert(index, session.principal, &session.session_id);
+        }
+    });
+    StepOutcome { processed, next }
+}
+
+fn snapshot_templates(after: Option<Vec<u8>>, limit: usize) -> StepOutcome {
+    let batch: Vec<(KeyString, LegalTemplate)> = TEMPLATES.with(|map| {
+        let map = map.borrow();
+        match after {
+            Some(bytes) => map
+                .range((Excluded(KeyString::from_bytes(Cow::Owned(bytes))), Unbounded))
+                .take(limit)
+                .collect(),
+            None => map.iter().take(limit).collect(),
+        }
+    });
+    let processed = batch.len() as u64;
+    let next = if batch.len() < limit {
+        None
+    } else {
+        batch.last().map(|(key, _)| key.to_bytes().into_owned())
+    };
+    for (_, template) in batch {
+        crate::templates::record_revision(template.revision(None));
+    }
+    StepOutcome { processed, next }
+}
+
+fn snapshot_clauses(after: Option<Vec<u8>>, limit: usize) -> StepOutcome {
+    let after = after.map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+    let batch = crate::templates::clauses_after(after, limit);
+    let processed = batch.len() as u64;
+    let next = if batch.len() < limit {
+        None
+    } else {
+        batch.last().map(|clause| clause.id.as_bytes().to_vec())
+    };
+    for clause in batch {
+        crate::templates::record_clause_revision(clause);
+    }
+    StepOutcome { processed, next }
+}
+
+fn migration_to(version: u32) -> &'static Migration {
+    MIGRATIONS
+        .iter()
+        .find(|m| m.to == version)
+        .unwrap_or_else(|| ic_cdk::trap(format!("no migration registered for schema version {}", version)))
+}
+
+// Marks a freshly installed canister as already being on the current schema.
+pub fn init_schema() {
+    set_schema_meta(SchemaMeta {
+        schema_version: CURRENT_SCHEMA_VERSION,
+        migration: None,
+        last_upgrade_at: None,
+    });
+}
+
+pub fn record_pre_upgrade() {
+    let mut meta = schema_meta();
+    if let Some(progress) = &meta.migration {
+        ic_cdk::println!(
+            "Upgrading during migration to schema v{} (step {}, {} records migrated)",
+            progress.target_version,
+            progress.step,
+            progress.migrated
+        );
+    }
+    meta.last_upgrade_at = Some(time());
+    set_schema_meta(meta);
+}
+
+// Starts (or resumes) migrating the stored data up to `CURRENT_SCHEMA_VERSION`.
+// Timers do not survive an upgrade, so this must be called from `post_upgrade`.
+pub fn resume_migrations() {
+    let mut meta = schema_meta();
+    if meta.schema_version > CURRENT_SCHEMA_VERSION {
+        ic_cdk::trap(format!(
+            "stable memory is at schema v{} but this build only supports up to v{}",
+            meta.schema_version, CURRENT_SCHEMA_VERSION
+        ));
+    }
+    if meta.schema_version == CURRENT_SCHEMA_VERSION {
+        return;
+    }
+    if meta.migration.is_none() {
+        meta.migration = Some(MigrationProgress {
+            target_version: meta.schema_version + 1,
+            step: 0,
+            cursor: None,
+            migrated: 0,
+            started_at: time(),
+        });
+        set_schema_meta(meta);
+    }
+    schedule_migration_batch();
+}
+
+fn schedule_migration_batch() {
+    ic_cdk_timers::set_timer(Duration::ZERO, run_migration_batch);
+}
+
+fn run_migration_batch() {
+    let mut meta = schema_meta();
+    let Some(mut progress) = meta.migration.take() else {
+        return;
+    };
+    let migration = migration_to(progress.target_version);
+
+    match migration.steps.get(progress.step as usize) {
+        Some(step) => {
+            let outcome = step(progress.cursor.take(), MIGRATION_BATCH_SIZE);
+            progress.migrated += outcome.processed;
+            match outcome.next {
+                Some(cursor) => progress.cursor = Some(cursor),
+                None => progress.step += 1,
+            }
+            meta.migration = Some(progress);
+        }
+        None => {
+            ic_cdk::println!(
+                "Schema migrated to v{} ({}): {} records rewritten",
+                migration.to,
+                migration.description,
+                progress.migrated
+            );
+            meta.schema_version = migration.to;
+            if migration.to < CURRENT_SCHEMA_VERSION {
+                meta.migration = Some(MigrationProgress {
+                    target_version: migration.to + 1,
+                    step: 0,
+                    cursor: None,
+                    migrated: 0,
+                    started_at: time(),
+                });
+            }
+        }
+    }
+
+    let pending = meta.migration.is_some();
+    set_schema_meta(meta);
+    if pending {
+        schedule_migration_batch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, CandidType, Deserialize)]
+    struct Record {
+        name: String,
+        count: u64,
+    }
+
+    // Layout of `Record` version 1, before it had a count.
+    #[derive(CandidType, Deserialize)]
+    struct RecordV1 {
+        name: String,
+    }
+
+    impl Versioned for Record {
+        const VERSION: u16 = 2;
+        fn upgrade(version: u16, bytes: &[u8]) -> Self {
+            let legacy: RecordV1 = decode_candid(version, bytes);
+            Record {
+                name: format!("{} (v{})", legacy.name, version),
+                count: 0,
+            }
+        }
+    }
+
+    fn enveloped(version: u16, candid: Vec<u8>) -> Vec<u8> {
+        let mut bytes = vec![ENVELOPE_TAG];
+        bytes.extend_from_slice(&version.to_be_bytes());
+        bytes.extend(candid);
+        bytes
+    }
+
+    #[test]
+    fn current_records_round_trip() {
+        let record = Record {
+            name: "nda".to_string(),
+            count: 3,
+        };
+        let bytes = encode_versioned(&record);
+        assert_eq!(bytes[..3], [ENVELOPE_TAG, 0, 2]);
+        assert_eq!(decode_versioned::<Record>(&bytes), record);
+    }
+
+    #[test]
+    fn raw_candid_is_upgraded_as_version_0() {
+        let bytes = Encode!(&RecordV1 { name: "nda".to_string() }).unwrap();
+        assert!(bytes.starts_with(b"DIDL"));
+        let record: Record = decode_versioned(&bytes);
+        assert_eq!(record.name, "nda (v0)");
+    }
+
+    #[test]
+    fn older_envelopes_are_upgraded_from_their_version() {
+        let bytes = enveloped(1, Encode!(&RecordV1 { name: "nda".to_string() }).unwrap());
+        let record: Record = decode_versioned(&bytes);
+        assert_eq!(record.name, "nda (v1)");
+    }
+
+    // Natively `trap` panics instead of trapping the canister.
+    #[test]
+    #[should_panic(expected = "trap should only be called inside canisters")]
+    fn records_from_a_newer_build_trap() {
+        let record = Record {
+            name: "nda".to_string(),
+            count: 3,
+        };
+        decode_versioned::<Record>(&enveloped(3, Encode!(&record).unwrap()));
+    }
+
+    #[test]
+    fn legacy_sessions_keep_their_messages_until_moved() {
+        let message = |content: &str| ChatMessage {
+            role: "user".to_string(),
+            content: content.to_string(),
+        };
+        let legacy = LegacySession {
+            session_id: "session_1".to_string(),
+            principal: Principal::anonymous(),
+            title: None,
+            created_at: 7,
+            messages: vec![message("hello"), message("again")],
+        };
+        let session: Session = decode_versioned(&Encode!(&legacy).unwrap());
+        assert_eq!(session.message_count, 2);
+        let contents: Vec<&str> = session.legacy_messages.iter().map(|msg| msg.content.as_str()).collect();
+        assert_eq!(contents, ["hello", "again"]);
+
+        // Rewritten in the current layout, the messages are still there.
+        let rewritten: Session = decode_versioned(&encode_versioned(&session));
+        assert_eq!(rewritten.legacy_messages.len(), 2);
+    }
+}
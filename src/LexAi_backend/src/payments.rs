@@ -0,0 +1,403 @@
+use crate::error::{LexError, LexResult};
+use crate::migrations::{self, decode_versioned, encode_versioned, Versioned};
+use crate::quota::{self, Resource};
+use crate::{CanisterMemory, KeyPrincipal, PrincipalSeqKey, MEMORY_MANAGER, USERS};
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::{canister_self, time};
+use ic_cdk::call::{Call, CallFailed, RejectCode};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    storable::Bound,
+    StableBTreeMap, StableCell, Storable,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt::Debug;
+
+// Maximum number of payment history entries returned per page.
+const MAX_PAYMENTS_PER_PAGE: u64 = 100;
+
+const TOP_UP_MEMO: &[u8] = b"LexAi top-up";
+const REFUND_MEMO: &[u8] = b"LexAi refund";
+
+// ICRC-1/ICRC-2 ledger interface, limited to what top-ups and refunds use.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Account { owner, subaccount: None }
+    }
+}
+
+#[derive(CandidType)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Debug, CandidType, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Controller-managed payment settings. Prices are in the ledger's base units
+// and apply once a user's plan quota for the month is used up; a price of
+// zero means the resource cannot be bought with credits.
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct PaymentConfig {
+    pub ledger: Option<Principal>,
+    pub message_price: u64,
+    pub document_price: u64,
+}
+
+impl Storable for PaymentConfig {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for PaymentConfig {
+    const VERSION: u16 = 1;
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub enum PaymentKind {
+    // Tokens pulled from the user with `icrc2_transfer_from`.
+    TopUp { block_index: u64 },
+    // Tokens sent back to the user by a controller.
+    Refund { block_index: u64, refunded_by: Principal },
+    // Credits spent on a job once the plan quota was used up.
+    Debit { job_id: u64 },
+    // Credits returned because the job they paid for failed.
+    Reversal { job_id: u64 },
+    // A refund the ledger definitely did not make; the credits were restored.
+    FailedRefund { reason: String, refunded_by: Principal },
+    // A refund whose outcome is unknown, for example because the ledger's
+    // reply could not be decoded. The credits stay taken until a controller
+    // finds the transfer with this `created_at_time` on the ledger.
+    PendingRefund { created_at_time: u64, refunded_by: Principal },
+    // A top-up whose outcome is unknown. Nothing was credited; a controller
+    // finds the transfer with this `created_at_time` on the ledger and
+    // credits it if it was made.
+    PendingTopUp { created_at_time: u64 },
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Payment {
+    pub principal: Principal,
+    pub kind: PaymentKind,
+    pub amount: u64,
+    pub balance_after: u64,
+    pub at: u64,
+}
+
+impl Storable for Payment {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for Payment {
+    const VERSION: u16 = 3;
+    // Version 1 had no `FailedRefund` or `PendingRefund` kinds and version 2
+    // no `PendingTopUp`; their records decode unchanged.
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        migrations::decode_candid(version, bytes)
+    }
+}
+
+thread_local! {
+    static PAYMENT_CONFIG: RefCell<StableCell<PaymentConfig, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(19)));
+        StableCell::init(memory, PaymentConfig::default()).expect("failed to initialize payment config")
+    });
+
+    // Keyed by (principal, n) for the principal's n-th payment.
+    static PAYMENTS: RefCell<StableBTreeMap<PrincipalSeqKey, Payment, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(20)));
+        StableBTreeMap::init(memory)
+    });
+}
+
+pub fn payment_config() -> PaymentConfig {
+    PAYMENT_CONFIG.with(|config| config.borrow().get().clone())
+}
+
+pub fn set_payment_config(config: PaymentConfig) {
+    PAYMENT_CONFIG.with(|cell| {
+        cell.borrow_mut()
+            .set(config)
+            .expect("failed to persist payment config");
+    });
+}
+
+pub fn credits(principal: Principal) -> u64 {
+    USERS.with(|users| users.borrow().get(&KeyPrincipal(principal))).map_or(0, |user| user.credits)
+}
+
+// Applies `f` to the user's credit balance and returns the new balance.
+// Fails if the user is not registered or `f` rejects the balance.
+fn adjust_credits(principal: Principal, f: impl FnOnce(u64) -> Option<u64>) -> LexResult<u64> {
+    USERS.with(|users| {
+        let mut map = users.borrow_mut();
+        let key = KeyPrincipal(principal);
+        let mut user = map.get(&key).ok_or_else(|| LexError::not_found("User", &principal.to_text()))?;
+        let balance = f(user.credits).ok_or(LexError::QuotaExceeded {
+            resource: "credits".to_string(),
+        })?;
+        user.credits = balance;
+        map.insert(key, user);
+        Ok(balance)
+    })
+}
+
+fn log_payment(principal: Principal, kind: PaymentKind, amount: u64, balance_after: u64) -> Payment {
+    let payment = Payment {
+        principal,
+        kind,
+        amount,
+        balance_after,
+        at: time(),
+    };
+    PAYMENTS.with(|payments| {
+        let mut payments = payments.borrow_mut();
+        let from = PrincipalSeqKey { principal, seq: 0 };
+        let to = PrincipalSeqKey { principal, seq: u64::MAX };
+        let seq = payments.range(from..=to).next_back().map_or(0, |(key, _)| key.seq + 1);
+        payments.insert(PrincipalSeqKey { principal, seq }, payment.clone());
+    });
+    payment
+}
+
+// Takes one unit of `resource` for job `job_id`: from the plan quota while it
+// lasts, then from credits at the configured price. Returns the credits
+// spent, if any.
+pub fn charge(principal: Principal, resource: Resource, job_id: u64) -> LexResult<Option<u64>> {
+    let quota_error = match quota::consume(principal, resource, 1) {
+        Ok(()) => return Ok(None),
+        Err(error) => error,
+    };
+    let config = payment_config();
+    let price = match resource {
+        Resource::Messages => config.message_price,
+        Resource::Documents => config.document_price,
+        Resource::StoredBytes | Resource::Templates => 0,
+    };
+    if price == 0 || credits(principal) < price {
+        return Err(quota_error);
+    }
+    let balance = adjust_credits(principal, |balance| balance.checked_sub(price))?;
+    log_payment(principal, PaymentKind::Debit { job_id }, price, balance);
+    Ok(Some(price))
+}
+
+// Undoes `charge` for a job that failed. Plan quota is given back to the
+// month it was charged in, `charged_period`.
+pub fn reverse_charge(
+    principal: Principal,
+    resource: Resource,
+    job_id: u64,
+    credits_charged: Option<u64>,
+    charged_period: u64,
+) {
+    match credits_charged {
+        Some(amount) => {
+            if let Ok(balance) = adjust_credits(principal, |balance| Some(balance.saturating_add(amount))) {
+                log_payment(principal, PaymentKind::Reversal { job_id }, amount, balance);
+            }
+        }
+        None => quota::release_in_period(principal, resource, 1, charged_period),
+    }
+}
+
+fn ledger() -> LexResult<Principal> {
+    payment_config()
+        .ledger
+        .ok_or_else(|| LexError::validation("payments are not configured"))
+}
+
+fn payment_failed(reason: impl Into<String>) -> LexError {
+    LexError::PaymentFailed { reason: reason.into() }
+}
+
+// How a ledger transfer ended.
+enum TransferOutcome {
+    Done(Nat),
+    // The ledger did not make the transfer.
+    Failed(String),
+    // The ledger may or may not have made the transfer.
+    Unknown(String),
+}
+
+// Calls a ledger transfer `method`, whose reply is a block index or an `E`.
+async fn transfer<A: CandidType, E: Debug + CandidType + DeserializeOwned>(
+    ledger: Principal,
+    method: &str,
+    arg: A,
+) -> TransferOutcome {
+    match Call::unbounded_wait(ledger, method).with_arg(arg).await {
+        Ok(response) => match response.candid::<Result<Nat, E>>() {
+            Ok(Ok(block_index)) => TransferOutcome::Done(block_index),
+            Ok(Err(e)) => TransferOutcome::Failed(format!("{:?}", e)),
+            // The ledger replied, so the transfer may have been made.
+            Err(e) => TransferOutcome::Unknown(format!("unexpected {} response: {:?}", method, e)),
+        },
+        // Only `SysUnknown` leaves it open whether the ledger ran the call.
+        Err(CallFailed::CallRejected(rejected)) if rejected.reject_code().is_ok_and(|code| code != RejectCode::SysUnknown) => {
+            TransferOutcome::Failed(format!("{} call failed: {:?}", method, rejected))
+        }
+        Err(e @ CallFailed::CallRejected(_)) => TransferOutcome::Unknown(format!("{} call failed: {:?}", method, e)),
+        Err(e) => TransferOutcome::Failed(format!("{} call failed: {:?}", method, e)),
+    }
+}
+
+fn block_index(nat: Nat) -> u64 {
+    u64::try_from(&nat.0).unwrap_or(u64::MAX)
+}
+
+// Pulls `amount` tokens the caller approved for this canister and credits
+// them. The caller must be registered. When the outcome of the transfer is
+// unknown, nothing is credited and a pending top-up is logged instead.
+pub async fn top_up(principal: Principal, amount: u64) -> LexResult<Payment> {
+    if principal == Principal::anonymous() {
+        return Err(LexError::Unauthorized);
+    }
+    if amount == 0 {
+        return Err(LexError::validation("amount must be positive"));
+    }
+    if !USERS.with(|users| users.borrow().contains_key(&KeyPrincipal(principal))) {
+        return Err(LexError::not_found("User", &principal.to_text()));
+    }
+    let ledger = ledger()?;
+    let created_at_time = time();
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: principal.into(),
+        to: canister_self().into(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(TOP_UP_MEMO.to_vec()),
+        created_at_time: Some(created_at_time),
+    };
+    match transfer::<_, TransferFromError>(ledger, "icrc2_transfer_from", args).await {
+        TransferOutcome::Done(block_index) => {
+            let block_index = self::block_index(block_index);
+            let balance = adjust_credits(principal, |balance| Some(balance.saturating_add(amount)))?;
+            Ok(log_payment(principal, PaymentKind::TopUp { block_index }, amount, balance))
+        }
+        TransferOutcome::Failed(reason) => Err(payment_failed(reason)),
+        TransferOutcome::Unknown(reason) => {
+            log_payment(principal, PaymentKind::PendingTopUp { created_at_time }, amount, credits(principal));
+            Err(payment_failed(format!(
+                "top-up outcome unknown ({}); logged as pending with created_at_time {}",
+                reason, created_at_time
+            )))
+        }
+    }
+}
+
+// Sends `amount` of a user's credits back to them as tokens. The credits are
+// taken before the transfer and restored only if the ledger definitely did
+// not make it; an unknown outcome is logged as a pending refund instead. The
+// ledger fee is paid by the canister.
+pub async fn refund(principal: Principal, amount: u64, refunded_by: Principal) -> LexResult<Payment> {
+    if amount == 0 {
+        return Err(LexError::validation("amount must be positive"));
+    }
+    let ledger = ledger()?;
+    let balance = adjust_credits(principal, |balance| balance.checked_sub(amount))?;
+    let created_at_time = time();
+    let args = TransferArg {
+        from_subaccount: None,
+        to: principal.into(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: Some(REFUND_MEMO.to_vec()),
+        created_at_time: Some(created_at_time),
+    };
+    match transfer::<_, TransferError>(ledger, "icrc1_transfer", args).await {
+        TransferOutcome::Done(block_index) => {
+            let kind = PaymentKind::Refund {
+                block_index: self::block_index(block_index),
+                refunded_by,
+            };
+            Ok(log_payment(principal, kind, amount, balance))
+        }
+        TransferOutcome::Failed(reason) => {
+            let balance = adjust_credits(principal, |balance| Some(balance.saturating_add(amount)))?;
+            let kind = PaymentKind::FailedRefund {
+                reason: reason.clone(),
+                refunded_by,
+            };
+            log_payment(principal, kind, amount, balance);
+            Err(payment_failed(reason))
+        }
+        TransferOutcome::Unknown(reason) => {
+            let kind = PaymentKind::PendingRefund {
+                created_at_time,
+                refunded_by,
+            };
+            log_payment(principal, kind, amount, balance);
+            Err(payment_failed(format!(
+                "refund outcome unknown ({}); logged as pending with created_at_time {}",
+                reason, created_at_time
+            )))
+        }
+    }
+}
+
+// The principal's payments from its `start`-th on, oldest first.
+pub fn payments(principal: Principal, start: u64, limit: Option<u64>) -> Vec<Payment> {
+    let limit = limit.unwrap_or(MAX_PAYMENTS_PER_PAGE).min(MAX_PAYMENTS_PER_PAGE) as usize;
+    let from = PrincipalSeqKey { principal, seq: start };
+    let to = PrincipalSeqKey { principal, seq: u64::MAX };
+    PAYMENTS.with(|payments| payments.borrow().range(from..=to).take(limit).map(|(_, payment)| payment).collect())
+}
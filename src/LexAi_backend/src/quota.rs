@@ -0,0 +1,368 @@
+use crate::error::{LexError, LexResult};
+use crate::migrations::{decode_versioned, encode_versioned, Versioned};
+use crate::{CanisterMemory, KeyPrincipal, MEMORY_MANAGER, USERS};
+use candid::{CandidType, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    storable::Bound,
+    StableBTreeMap, Storable,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+const MB: u64 = 1_000_000;
+
+// Subscription tier of a user. Users without one are on `Free`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub enum Plan {
+    #[default]
+    Free,
+    Pro,
+    Firm,
+}
+
+// Amounts of each metered resource. Messages and documents are counted per
+// calendar month (UTC); stored bytes and templates are standing totals.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct QuotaAmounts {
+    pub messages: u64,
+    pub documents: u64,
+    pub stored_bytes: u64,
+    pub templates: u64,
+}
+
+impl Plan {
+    pub fn limits(self) -> QuotaAmounts {
+        match self {
+            Plan::Free => QuotaAmounts {
+                messages: 50,
+                documents: 5,
+                stored_bytes: 5 * MB,
+                templates: 2,
+            },
+            Plan::Pro => QuotaAmounts {
+                messages: 1_000,
+                documents: 100,
+                stored_bytes: 100 * MB,
+                templates: 25,
+            },
+            Plan::Firm => QuotaAmounts {
+                messages: 10_000,
+                documents: 1_000,
+                stored_bytes: 1_000 * MB,
+                templates: 250,
+            },
+        }
+    }
+}
+
+// What a principal has used. `period` is the month the monthly counters
+// belong to, as months since January 1970.
+#[derive(Clone, Default, CandidType, Deserialize)]
+struct QuotaUsage {
+    period: u64,
+    used: QuotaAmounts,
+}
+
+impl Storable for QuotaUsage {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for QuotaUsage {
+    const VERSION: u16 = 1;
+}
+
+impl QuotaUsage {
+    // This usage as of `period`: the monthly counters start over in a new month.
+    fn in_period(mut self, period: u64) -> Self {
+        if self.period != period {
+            self.period = period;
+            self.used.messages = 0;
+            self.used.documents = 0;
+        }
+        self
+    }
+
+    // Takes `amount` of `resource` within `limits`, or fails without taking anything.
+    fn take(&mut self, resource: Resource, amount: u64, limits: &QuotaAmounts) -> LexResult<()> {
+        let used = resource.amount(&mut self.used);
+        if used.saturating_add(amount) > resource.limit(limits) {
+            return Err(resource.exceeded());
+        }
+        *used += amount;
+        Ok(())
+    }
+
+    // Fails if none of `resource` is left within `limits`.
+    fn ensure_left(mut self, resource: Resource, limits: &QuotaAmounts) -> LexResult<()> {
+        if *resource.amount(&mut self.used) >= resource.limit(limits) {
+            return Err(resource.exceeded());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Allowance {
+    pub plan: Plan,
+    // Month the message and document counters cover, as "YYYY-MM".
+    pub period: String,
+    pub limits: QuotaAmounts,
+    pub used: QuotaAmounts,
+    pub remaining: QuotaAmounts,
+}
+
+// A metered resource, as named in `QuotaExceeded` errors.
+#[derive(Clone, Copy)]
+pub enum Resource {
+    Messages,
+    Documents,
+    StoredBytes,
+    Templates,
+}
+
+impl Resource {
+    fn name(self) -> &'static str {
+        match self {
+            Resource::Messages => "messages",
+            Resource::Documents => "documents",
+            Resource::StoredBytes => "stored_bytes",
+            Resource::Templates => "templates",
+        }
+    }
+
+    fn limit(self, limits: &QuotaAmounts) -> u64 {
+        *self.amount(&mut limits.clone())
+    }
+
+    fn exceeded(self) -> LexError {
+        LexError::QuotaExceeded {
+            resource: self.name().to_string(),
+        }
+    }
+
+    fn amount(self, amounts: &mut QuotaAmounts) -> &mut u64 {
+        match self {
+            Resource::Messages => &mut amounts.messages,
+            Resource::Documents => &mut amounts.documents,
+            Resource::StoredBytes => &mut amounts.stored_bytes,
+            Resource::Templates => &mut amounts.templates,
+        }
+    }
+}
+
+thread_local! {
+    static QUOTA_USAGE: RefCell<StableBTreeMap<KeyPrincipal, QuotaUsage, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(18)));
+        StableBTreeMap::init(memory)
+    });
+}
+
+pub fn plan_of(principal: Principal) -> Plan {
+    USERS.with(|users| users.borrow().get(&KeyPrincipal(principal)))
+        .map_or_else(Plan::default, |user| user.plan)
+}
+
+// The month monthly usage is currently counted in.
+pub fn current_period() -> u64 {
+    month_index(time())
+}
+
+// Usage with the monthly counters reset if a new month has started.
+fn current_usage(principal: Principal) -> QuotaUsage {
+    QUOTA_USAGE.with(|usage| usage.borrow().get(&KeyPrincipal(principal)))
+        .unwrap_or_default()
+        .in_period(current_period())
+}
+
+fn store_usage(principal: Principal, usage: QuotaUsage) {
+    QUOTA_USAGE.with(|map| map.borrow_mut().insert(KeyPrincipal(principal), usage));
+}
+
+// Takes `amount` of `resource` from the principal's allowance, or fails with
+// `QuotaExceeded` without taking anything. Controllers are not metered.
+pub fn consume(principal: Principal, resource: Resource, amount: u64) -> LexResult<()> {
+    if ic_cdk::api::is_controller(&principal) {
+        return Ok(());
+    }
+    let mut usage = current_usage(principal);
+    usage.take(resource, amount, &plan_of(principal).limits())?;
+    store_usage(principal, usage);
+    Ok(())
+}
+
+// Fails with `QuotaExceeded` if the principal has none of `resource` left.
+pub fn ensure_available(principal: Principal, resource: Resource) -> LexResult<()> {
+    if ic_cdk::api::is_controller(&principal) {
+        return Ok(());
+    }
+    current_usage(principal).ensure_left(resource, &plan_of(principal).limits())
+}
+
+// Records usage that was already allowed, such as the bytes of a reply that
+// was checked before it was generated. May exceed the limit.
+pub fn record(principal: Principal, resource: Resource, amount: u64) {
+    let mut usage = current_usage(principal);
+    let used = resource.amount(&mut usage.used);
+    *used = used.saturating_add(amount);
+    store_usage(principal, usage);
+}
+
+// Gives back usage: a failed job's reservation, or deleted data.
+pub fn release(principal: Principal, resource: Resource, amount: u64) {
+    let mut usage = current_usage(principal);
+    let used = resource.amount(&mut usage.used);
+    *used = used.saturating_sub(amount);
+    store_usage(principal, usage);
+}
+
+// Gives back monthly usage that was taken in `period`. Once that month is
+// over its counters are gone, so nothing is given back: the current month's
+// usage must not drop for it.
+pub fn release_in_period(principal: Principal, resource: Resource, amount: u64, period: u64) {
+    if period == current_period() {
+        release(principal, resource, amount);
+    }
+}
+
+pub fn allowance(principal: Principal) -> Allowance {
+    let plan = plan_of(principal);
+    let limits = plan.limits();
+    let usage = current_usage(principal);
+    let remaining = QuotaAmounts {
+        messages: limits.messages.saturating_sub(usage.used.messages),
+        documents: limits.documents.saturating_sub(usage.used.documents),
+        stored_bytes: limits.stored_bytes.saturating_sub(usage.used.stored_bytes),
+        templates: limits.templates.saturating_sub(usage.used.templates),
+    };
+    Allowance {
+        plan,
+        period: format!("{:04}-{:02}", usage.period / 12 + 1970, usage.period % 12 + 1),
+        limits,
+        used: usage.used,
+        remaining,
+    }
+}
+
+// Months since January 1970 for a timestamp in nanoseconds, using the
+// proleptic Gregorian calendar (Howard Hinnant's `civil_from_days`).
+pub fn month_index(nanos: u64) -> u64 {
+    let z = (nanos / NANOS_PER_DAY) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    ((year - 1970) * 12 + month - 1) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at_day(days: u64) -> u64 {
+        days * NANOS_PER_DAY
+    }
+
+    fn month(year: u64, month: u64) -> u64 {
+        (year - 1970) * 12 + month - 1
+    }
+
+    fn usage(period: u64, messages: u64, stored_bytes: u64) -> QuotaUsage {
+        QuotaUsage {
+            period,
+            used: QuotaAmounts {
+                messages,
+                stored_bytes,
+                ..QuotaAmounts::default()
+            },
+        }
+    }
+
+    fn is_exceeded(result: LexResult<()>, name: &str) -> bool {
+        matches!(result, Err(LexError::QuotaExceeded { resource }) if resource == name)
+    }
+
+    #[test]
+    fn the_epoch_is_month_zero() {
+        assert_eq!(month_index(0), 0);
+        assert_eq!(month_index(at_day(31) - 1), 0);
+        assert_eq!(month_index(at_day(31)), 1);
+    }
+
+    #[test]
+    fn december_rolls_over_into_january() {
+        // 2023-12-31 and 2024-01-01.
+        assert_eq!(month_index(at_day(19_722)), month(2023, 12));
+        assert_eq!(month_index(at_day(19_723) - 1), month(2023, 12));
+        assert_eq!(month_index(at_day(19_723)), month(2024, 1));
+    }
+
+    #[test]
+    fn february_ends_on_the_leap_day_in_leap_years() {
+        // 2023-02-28 and 2023-03-01.
+        assert_eq!(month_index(at_day(19_416)), month(2023, 2));
+        assert_eq!(month_index(at_day(19_417)), month(2023, 3));
+        // 2024-02-29 and 2024-03-01.
+        assert_eq!(month_index(at_day(19_782)), month(2024, 2));
+        assert_eq!(month_index(at_day(19_783)), month(2024, 3));
+        // 2000 is a leap year although it is a century: 2000-02-29 and 2000-03-01.
+        assert_eq!(month_index(at_day(11_016)), month(2000, 2));
+        assert_eq!(month_index(at_day(11_017)), month(2000, 3));
+    }
+
+    #[test]
+    fn a_new_month_resets_only_the_monthly_counters() {
+        let mut old = usage(month(2023, 12), 40, 1_000);
+        old.used.documents = 3;
+        old.used.templates = 2;
+        let rolled = old.in_period(month(2024, 1));
+        assert_eq!(rolled.period, month(2024, 1));
+        assert_eq!(rolled.used.messages, 0);
+        assert_eq!(rolled.used.documents, 0);
+        assert_eq!(rolled.used.stored_bytes, 1_000);
+        assert_eq!(rolled.used.templates, 2);
+    }
+
+    #[test]
+    fn the_same_month_keeps_its_counters() {
+        let same = usage(month(2024, 2), 40, 0).in_period(month(2024, 2));
+        assert_eq!(same.used.messages, 40);
+    }
+
+    #[test]
+    fn take_allows_up_to_the_limit() {
+        let limits = Plan::Free.limits();
+        let mut used = usage(0, limits.messages - 1, 0);
+        assert!(used.take(Resource::Messages, 1, &limits).is_ok());
+        assert_eq!(used.used.messages, limits.messages);
+    }
+
+    #[test]
+    fn take_refuses_past_the_limit_without_taking_anything() {
+        let limits = Plan::Free.limits();
+        let mut used = usage(0, 0, limits.stored_bytes - 10);
+        assert!(is_exceeded(used.take(Resource::StoredBytes, 11, &limits), "stored_bytes"));
+        assert_eq!(used.used.stored_bytes, limits.stored_bytes - 10);
+        assert!(is_exceeded(used.take(Resource::StoredBytes, u64::MAX, &limits), "stored_bytes"));
+    }
+
+    #[test]
+    fn ensure_left_refuses_once_the_limit_is_reached() {
+        let limits = Plan::Free.limits();
+        assert!(usage(0, limits.messages - 1, 0).ensure_left(Resource::Messages, &limits).is_ok());
+        assert!(is_exceeded(usage(0, limits.messages, 0).ensure_left(Resource::Messages, &limits), "messages"));
+        assert!(usage(0, limits.messages, 0).ensure_left(Resource::Messages, &Plan::Pro.limits()).is_ok());
+    }
+}
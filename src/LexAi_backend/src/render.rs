@@ -0,0 +1,832 @@
+use crate::error::{LexError, LexResult};
+use crate::templates::{is_identifier, TextPosition};
+use std::ops::Range;
+
+// Template language
+//
+// Tags are written in braces; anything else, including braces around text
+// that is not a tag, is copied as-is.
+//
+//   {name}                          the value of a field
+//   {name | upper}                  filters: upper, lower, title, date,
+//   {date | date:"Do MMMM YYYY"}    date:"format" and default:"text"
+//   {#if name} .. {:else} .. {/if}  also `!name`, `name == "x"`, `name != "x"`
+//   {#each parties as party} .. {/each}
+//                                   one pass per line of the field, with
+//                                   {@index}, {@first} and {@last} set
+//   {> clause_id}                   the text of a clause, rendered in place
+//
+// A block tag alone on its line takes the whole line with it, so blocks can
+// be laid out one tag per line without leaving blank lines behind.
+
+// Nesting limit for clauses that include other clauses.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+const DEFAULT_DATE_FORMAT: &str = "D MMMM YYYY";
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+// A tag in a template's text, with the text between its braces trimmed.
+pub struct Tag<'a> {
+    // Byte offsets of the opening brace and just past the closing one.
+    pub start: usize,
+    pub end: usize,
+    // Bytes the tag replaces: the tag itself, or its whole line for a block
+    // tag that stands alone.
+    pub span: Range<usize>,
+    pub body: &'a str,
+}
+
+impl Tag<'_> {
+    fn is_block(&self) -> bool {
+        self.body.starts_with(['#', ':', '/'])
+    }
+}
+
+fn is_tag(body: &str) -> bool {
+    let body = body.trim();
+    body.starts_with(['#', ':', '/', '>']) || is_name(body.split('|').next().unwrap_or_default().trim())
+}
+
+fn is_name(name: &str) -> bool {
+    is_identifier(name.strip_prefix('@').unwrap_or(name))
+}
+
+pub fn tags(text: &str) -> Vec<Tag<'_>> {
+    let mut tags = Vec::new();
+    let mut rest = 0;
+    while let Some(open) = text[rest..].find('{').map(|i| rest + i) {
+        match text[open + 1..].find(['{', '}']).map(|i| open + 1 + i) {
+            Some(close) if text.as_bytes()[close] == b'}' && is_tag(&text[open + 1..close]) => {
+                let mut tag = Tag {
+                    start: open,
+                    end: close + 1,
+                    span: open..close + 1,
+                    body: text[open + 1..close].trim(),
+                };
+                if tag.is_block() {
+                    tag.span = standalone_line(text, tag.span.clone());
+                }
+                tags.push(tag);
+                rest = close + 1;
+            }
+            _ => rest = open + 1,
+        }
+    }
+    tags
+}
+
+// The whole line around `span` if nothing but blanks shares it, else `span`.
+fn standalone_line(text: &str, span: Range<usize>) -> Range<usize> {
+    let line_start = text[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = text[span.end..].find('\n').map_or(text.len(), |i| span.end + i + 1);
+    let blank = |s: &str| s.chars().all(|c| c == ' ' || c == '\t' || c == '\r' || c == '\n');
+    if blank(&text[line_start..span.start]) && blank(&text[span.end..line_end]) {
+        line_start..line_end
+    } else {
+        span
+    }
+}
+
+pub fn position(text: &str, offset: usize) -> TextPosition {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    TextPosition {
+        line: before.matches('\n').count() as u32 + 1,
+        column: before[line_start..].chars().count() as u32 + 1,
+    }
+}
+
+enum Filter {
+    Upper,
+    Lower,
+    Title,
+    Date(String),
+    Default(String),
+}
+
+enum Condition {
+    Present(String),
+    Absent(String),
+    Equals(String, String),
+    NotEquals(String, String),
+}
+
+enum Node {
+    Text(String),
+    Var {
+        name: String,
+        filters: Vec<Filter>,
+        at: usize,
+    },
+    If {
+        condition: Condition,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+        at: usize,
+    },
+    Each {
+        list: String,
+        item: String,
+        body: Vec<Node>,
+        at: usize,
+    },
+    Include {
+        clause_id: String,
+        at: usize,
+    },
+}
+
+// A parsed template text.
+pub struct Parsed(Vec<Node>);
+
+// A field a template refers to, at the byte offset of the tag. A field an
+// included clause refers to is reported at the outermost include tag.
+pub struct Reference {
+    pub name: String,
+    pub at: usize,
+    // Iterated over with `#each`.
+    pub list: bool,
+    // The clause whose text refers to the field, if not the template's own.
+    pub clause: Option<String>,
+}
+
+pub fn parse(text: &str) -> LexResult<Parsed> {
+    parse_text(text).map_err(LexError::validation)
+}
+
+fn parse_text(text: &str) -> Result<Parsed, String> {
+    let mut parser = Parser {
+        text,
+        tags: tags(text).into_iter(),
+        cursor: 0,
+    };
+    match parser.block()? {
+        (nodes, None) => Ok(Parsed(nodes)),
+        (_, Some(tag)) => Err(parser.error(&tag, format!("{{{}}} has no matching opening tag", tag.body))),
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    tags: std::vec::IntoIter<Tag<'a>>,
+    cursor: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, tag: &Tag, message: impl std::fmt::Display) -> String {
+        let position = position(self.text, tag.start);
+        format!("line {}, column {}: {}", position.line, position.column, message)
+    }
+
+    // Nodes up to the next `{:...}` or `{/...}` tag, which is returned, or up
+    // to the end of the text.
+    fn block(&mut self) -> Result<(Vec<Node>, Option<Tag<'a>>), String> {
+        let mut nodes = Vec::new();
+        while let Some(tag) = self.tags.next() {
+            if tag.span.start > self.cursor {
+                nodes.push(Node::Text(self.text[self.cursor..tag.span.start].to_string()));
+            }
+            self.cursor = tag.span.end;
+            if tag.body.starts_with([':', '/']) {
+                return Ok((nodes, Some(tag)));
+            }
+            if let Some(block) = tag.body.strip_prefix('#') {
+                let (keyword, args) = block.split_once(char::is_whitespace).unwrap_or((block, ""));
+                let node = match keyword {
+                    "if" => {
+                        let condition = parse_condition(args.trim()).map_err(|e| self.error(&tag, e))?;
+                        let (then, end) = self.block()?;
+                        let (otherwise, end) = match end {
+                            Some(end) if end.body == ":else" => self.block()?,
+                            end => (Vec::new(), end),
+                        };
+                        self.expect_close(&tag, end, "/if")?;
+                        Node::If {
+                            condition,
+                            then,
+                            otherwise,
+                            at: tag.start,
+                        }
+                    }
+                    "each" => {
+                        let (list, item) = parse_each(args.trim()).map_err(|e| self.error(&tag, e))?;
+                        let (body, end) = self.block()?;
+                        self.expect_close(&tag, end, "/each")?;
+                        Node::Each {
+                            list,
+                            item,
+                            body,
+                            at: tag.start,
+                        }
+                    }
+                    _ => return Err(self.error(&tag, format!("unknown block {{#{}}}", keyword))),
+                };
+                nodes.push(node);
+            } else if let Some(clause_id) = tag.body.strip_prefix('>') {
+                let clause_id = clause_id.trim();
+                if clause_id.is_empty() {
+                    return Err(self.error(&tag, "missing clause id"));
+                }
+                nodes.push(Node::Include {
+                    clause_id: clause_id.to_string(),
+                    at: tag.start,
+                });
+            } else {
+                let (name, filters) = parse_var(tag.body).map_err(|e| self.error(&tag, e))?;
+                nodes.push(Node::Var {
+                    name,
+                    filters,
+                    at: tag.start,
+                });
+            }
+        }
+        if self.cursor < self.text.len() {
+            nodes.push(Node::Text(self.text[self.cursor..].to_string()));
+            self.cursor = self.text.len();
+        }
+        Ok((nodes, None))
+    }
+
+    fn expect_close(&self, open: &Tag, end: Option<Tag>, close: &str) -> Result<(), String> {
+        match end {
+            Some(end) if end.body == close => Ok(()),
+            Some(end) => Err(self.error(&end, format!("expected {{{}}}, found {{{}}}", close, end.body))),
+            None => Err(self.error(open, format!("{{{}}} is never closed with {{{}}}", open.body, close))),
+        }
+    }
+}
+
+fn parse_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if is_name(name) {
+        Ok(name.to_string())
+    } else {
+        Err(format!("{:?} is not a field name", name))
+    }
+}
+
+fn parse_literal(literal: &str) -> Result<String, String> {
+    let literal = literal.trim();
+    literal
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+        .filter(|inner| !inner.contains('"'))
+        .map(str::to_string)
+        .ok_or_else(|| format!("expected a quoted string, found {}", literal))
+}
+
+fn parse_condition(args: &str) -> Result<Condition, String> {
+    if let Some((name, literal)) = args.split_once("!=") {
+        Ok(Condition::NotEquals(parse_name(name)?, parse_literal(literal)?))
+    } else if let Some((name, literal)) = args.split_once("==") {
+        Ok(Condition::Equals(parse_name(name)?, parse_literal(literal)?))
+    } else if let Some(name) = args.strip_prefix('!') {
+        Ok(Condition::Absent(parse_name(name)?))
+    } else {
+        Ok(Condition::Present(parse_name(args)?))
+    }
+}
+
+fn parse_each(args: &str) -> Result<(String, String), String> {
+    let (list, item) = args
+        .split_once(" as ")
+        .ok_or_else(|| "expected {#each list as item}".to_string())?;
+    let item = parse_name(item)?;
+    if item.starts_with('@') {
+        return Err(format!("{} is reserved", item));
+    }
+    Ok((parse_name(list)?, item))
+}
+
+fn parse_var(body: &str) -> Result<(String, Vec<Filter>), String> {
+    let mut parts = body.split('|');
+    let name = parse_name(parts.next().unwrap_or_default())?;
+    let filters = parts
+        .map(|filter| {
+            let filter = filter.trim();
+            let (filter_name, arg) = match filter.split_once(':') {
+                Some((filter_name, arg)) => (filter_name.trim(), Some(parse_literal(arg)?)),
+                None => (filter, None),
+            };
+            match (filter_name, arg) {
+                ("upper", None) => Ok(Filter::Upper),
+                ("lower", None) => Ok(Filter::Lower),
+                ("title", None) => Ok(Filter::Title),
+                ("date", format) => Ok(Filter::Date(format.unwrap_or_else(|| DEFAULT_DATE_FORMAT.to_string()))),
+                ("default", Some(text)) => Ok(Filter::Default(text)),
+                _ => Err(format!("unknown filter {:?}", filter)),
+            }
+        })
+        .collect::<Result<_, String>>()?;
+    Ok((name, filters))
+}
+
+impl Parsed {
+    // Fields the text refers to, in text order with repeats, followed into
+    // the clauses it includes as `clause` looks them up now. Loop items and
+    // `@` variables are not fields. Clauses that are missing, do not parse or
+    // nest too deep are left out; rendering reports them.
+    pub fn references(&self, clause: &dyn Fn(&str) -> Option<String>) -> Vec<Reference> {
+        let mut references = Vec::new();
+        collect_references(&self.0, &mut Vec::new(), Clauses { clause, depth: 0 }, None, &mut references);
+        references
+    }
+
+    // Ids of the clauses the text includes directly, in text order.
+    pub fn includes(&self) -> Vec<String> {
+        let mut includes = Vec::new();
+        collect_includes(&self.0, &mut includes);
+        includes
+    }
+}
+
+fn collect_includes(nodes: &[Node], includes: &mut Vec<String>) {
+    for node in nodes {
+        match node {
+            Node::Text(_) | Node::Var { .. } => {}
+            Node::If { then, otherwise, .. } => {
+                collect_includes(then, includes);
+                collect_includes(otherwise, includes);
+            }
+            Node::Each { body, .. } => collect_includes(body, includes),
+            Node::Include { clause_id, .. } => includes.push(clause_id.clone()),
+        }
+    }
+}
+
+// How `collect_references` follows includes, `depth` clauses down.
+#[derive(Clone, Copy)]
+struct Clauses<'c> {
+    clause: &'c dyn Fn(&str) -> Option<String>,
+    depth: usize,
+}
+
+// `within` is the clause being walked, if any, and the offset of the
+// outermost include tag that led to it.
+fn collect_references<'a>(
+    nodes: &'a [Node],
+    bound: &mut Vec<&'a str>,
+    clauses: Clauses,
+    within: Option<(&str, usize)>,
+    references: &mut Vec<Reference>,
+) {
+    let add = |name: &str, at: usize, list: bool, bound: &[&str], references: &mut Vec<Reference>| {
+        if !name.starts_with('@') && !bound.contains(&name) {
+            references.push(Reference {
+                name: name.to_string(),
+                at: within.map_or(at, |(_, include_at)| include_at),
+                list,
+                clause: within.map(|(clause_id, _)| clause_id.to_string()),
+            });
+        }
+    };
+    for node in nodes {
+        match node {
+            Node::Text(_) => {}
+            Node::Var { name, at, .. } => add(name, *at, false, bound, references),
+            Node::If {
+                condition,
+                then,
+                otherwise,
+                at,
+            } => {
+                let (Condition::Present(name)
+                | Condition::Absent(name)
+                | Condition::Equals(name, _)
+                | Condition::NotEquals(name, _)) = condition;
+                add(name, *at, false, bound, references);
+                collect_references(then, bound, clauses, within, references);
+                collect_references(otherwise, bound, clauses, within, references);
+            }
+            Node::Each { list, item, body, at } => {
+                add(list, *at, true, bound, references);
+                bound.push(item);
+                collect_references(body, bound, clauses, within, references);
+                bound.pop();
+            }
+            Node::Include { clause_id, at } => {
+                if clauses.depth >= MAX_INCLUDE_DEPTH {
+                    continue;
+                }
+                let Some(parsed) = (clauses.clause)(clause_id).and_then(|text| parse_text(&text).ok()) else {
+                    continue;
+                };
+                // A clause sees the loop items bound where it is included.
+                let mut inner: Vec<&str> = bound.clone();
+                let inner_clauses = Clauses {
+                    depth: clauses.depth + 1,
+                    ..clauses
+                };
+                let include_at = within.map_or(*at, |(_, include_at)| include_at);
+                let within = Some((clause_id.as_str(), include_at));
+                collect_references(&parsed.0, &mut inner, inner_clauses, within, references);
+            }
+        }
+    }
+}
+
+// Renders `parsed` with `values` as the fields. `clause` looks up the text
+// of an included clause.
+pub fn render(parsed: &Parsed, values: Vec<(String, String)>, clause: &dyn Fn(&str) -> Option<String>) -> LexResult<String> {
+    let mut renderer = Renderer {
+        bindings: values,
+        clause,
+        depth: 0,
+    };
+    let mut out = String::new();
+    renderer.render(&parsed.0, &mut out)?;
+    Ok(out)
+}
+
+struct Renderer<'a> {
+    // Innermost last, so loop items shadow fields.
+    bindings: Vec<(String, String)>,
+    clause: &'a dyn Fn(&str) -> Option<String>,
+    depth: usize,
+}
+
+impl Renderer<'_> {
+    fn lookup(&self, name: &str) -> LexResult<&str> {
+        self.bindings
+            .iter()
+            .rev()
+            .find(|(bound, _)| bound == name)
+            .map(|(_, value)| value.as_str())
+            .ok_or_else(|| LexError::validation(format!("unknown variable {}", name)))
+    }
+
+    fn render(&mut self, nodes: &[Node], out: &mut String) -> LexResult<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => out.push_str(text),
+                Node::Var { name, filters, .. } => {
+                    let mut value = self.lookup(name)?.to_string();
+                    for filter in filters {
+                        value = apply(filter, value)
+                            .map_err(|e| LexError::validation(format!("{}: {}", name, e)))?;
+                    }
+                    out.push_str(&value);
+                }
+                Node::If {
+                    condition,
+                    then,
+                    otherwise,
+                    ..
+                } => {
+                    let holds = match condition {
+                        Condition::Present(name) => !self.lookup(name)?.is_empty(),
+                        Condition::Absent(name) => self.lookup(name)?.is_empty(),
+                        Condition::Equals(name, literal) => self.lookup(name)? == literal,
+                        Condition::NotEquals(name, literal) => self.lookup(name)? != literal,
+                    };
+                    self.render(if holds { then } else { otherwise }, out)?;
+                }
+                Node::Each { list, item, body, .. } => {
+                    let items: Vec<String> = self
+                        .lookup(list)?
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty())
+                        .map(str::to_string)
+                        .collect();
+                    let outer = self.bindings.len();
+                    for (i, value) in items.iter().enumerate() {
+                        let flag = |set: bool| if set { "true" } else { "" }.to_string();
+                        self.bindings.extend([
+                            (item.clone(), value.clone()),
+                            ("@index".to_string(), (i + 1).to_string()),
+                            ("@first".to_string(), flag(i == 0)),
+                            ("@last".to_string(), flag(i + 1 == items.len())),
+                        ]);
+                        self.render(body, out)?;
+                        self.bindings.truncate(outer);
+                    }
+                }
+                Node::Include { clause_id, .. } => {
+                    if self.depth >= MAX_INCLUDE_DEPTH {
+                        return Err(LexError::validation(format!(
+                            "clauses are nested more than {} deep at {}",
+                            MAX_INCLUDE_DEPTH, clause_id
+                        )));
+                    }
+                    let text = (self.clause)(clause_id).ok_or_else(|| LexError::not_found("Clause", clause_id))?;
+                    let parsed =
+                        parse_text(&text).map_err(|e| LexError::validation(format!("clause {}: {}", clause_id, e)))?;
+                    self.depth += 1;
+                    let rendered = self.render(&parsed.0, out);
+                    self.depth -= 1;
+                    rendered?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn apply(filter: &Filter, value: String) -> Result<String, String> {
+    Ok(match filter {
+        Filter::Upper => value.to_uppercase(),
+        Filter::Lower => value.to_lowercase(),
+        Filter::Title => value
+            .split(' ')
+            .map(|word| {
+                let mut chars = word.chars();
+                chars
+                    .next()
+                    .map_or_else(String::new, |first| first.to_uppercase().chain(chars).collect())
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        Filter::Date(format) => format_date(&value, format)?,
+        Filter::Default(text) if value.is_empty() => text.clone(),
+        Filter::Default(_) => value,
+    })
+}
+
+// Formats a YYYY-MM-DD date. Recognised tokens are YYYY, MMMM (month name),
+// MM, M, DD, Do (ordinal day) and D; everything else is copied.
+fn format_date(value: &str, format: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Ok(String::new());
+    }
+    let parts: Vec<u32> = value.split('-').filter_map(|part| part.parse().ok()).collect();
+    let [year, month, day] = parts[..] else {
+        return Err("expected a date as YYYY-MM-DD".to_string());
+    };
+    if !(1..=12).contains(&month) {
+        return Err("expected a date as YYYY-MM-DD".to_string());
+    }
+    let ordinal = match (day % 10, day % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    let tokens = [
+        ("YYYY", format!("{:04}", year)),
+        ("MMMM", MONTHS[month as usize - 1].to_string()),
+        ("MM", format!("{:02}", month)),
+        ("M", month.to_string()),
+        ("DD", format!("{:02}", day)),
+        ("Do", format!("{}{}", day, ordinal)),
+        ("D", day.to_string()),
+    ];
+    let mut formatted = String::new();
+    let mut rest = format;
+    'outer: while !rest.is_empty() {
+        for (token, replacement) in &tokens {
+            if let Some(after) = rest.strip_prefix(token) {
+                formatted.push_str(replacement);
+                rest = after;
+                continue 'outer;
+            }
+        }
+        let c = rest.chars().next().unwrap_or_default();
+        formatted.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    Ok(formatted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn no_clauses(_: &str) -> Option<String> {
+        None
+    }
+
+    fn render_text(text: &str, pairs: &[(&str, &str)]) -> LexResult<String> {
+        render(&parse(text)?, values(pairs), &no_clauses)
+    }
+
+    fn parse_error(text: &str) -> String {
+        match parse(text) {
+            Err(LexError::Validation { message }) => message,
+            Err(e) => panic!("expected a validation error, got {}", e),
+            Ok(_) => panic!("expected {:?} not to parse", text),
+        }
+    }
+
+    fn validation_message(result: LexResult<String>) -> String {
+        match result {
+            Err(LexError::Validation { message }) => message,
+            Err(e) => panic!("expected a validation error, got {}", e),
+            Ok(text) => panic!("expected an error, rendered {:?}", text),
+        }
+    }
+
+    #[test]
+    fn parse_errors_report_line_and_column() {
+        assert_eq!(parse_error("Dear {name},\n{#if paid}"), "line 2, column 1: {#if paid} is never closed with {/if}");
+        assert_eq!(parse_error("{#each parties as party}\n{/if}"), "line 2, column 1: expected {/each}, found {/if}");
+        assert_eq!(parse_error("text {/if}"), "line 1, column 6: {/if} has no matching opening tag");
+        assert_eq!(parse_error("{#loop items}{/loop}"), "line 1, column 1: unknown block {#loop}");
+        assert_eq!(parse_error("{name | shout}"), "line 1, column 1: unknown filter \"shout\"");
+        assert_eq!(parse_error("{#each parties}{/each}"), "line 1, column 1: expected {#each list as item}");
+        assert_eq!(parse_error("{#each parties as @index}{/each}"), "line 1, column 1: @index is reserved");
+        assert_eq!(
+            parse_error("{#if kind == buyer}{/if}"),
+            "line 1, column 1: expected a quoted string, found buyer"
+        );
+        assert_eq!(parse_error("{>  }"), "line 1, column 1: missing clause id");
+    }
+
+    #[test]
+    fn braces_that_are_not_tags_are_copied() {
+        assert_eq!(render_text("{ not a tag } and {}", &[]).unwrap(), "{ not a tag } and {}");
+    }
+
+    #[test]
+    fn unknown_variable_is_an_error() {
+        assert_eq!(validation_message(render_text("Dear {name}", &[])), "unknown variable name");
+        assert_eq!(validation_message(render_text("{#if paid}yes{/if}", &[])), "unknown variable paid");
+        assert_eq!(
+            validation_message(render_text("{#each parties as party}{party}{/each}", &[])),
+            "unknown variable parties"
+        );
+    }
+
+    #[test]
+    fn empty_value_renders_as_empty_text() {
+        assert_eq!(render_text("Dear {name}.", &[("name", "")]).unwrap(), "Dear .");
+        assert_eq!(render_text("{name | default:\"Sir or Madam\"}", &[("name", "")]).unwrap(), "Sir or Madam");
+        assert_eq!(render_text("{name | default:\"Sir or Madam\"}", &[("name", "Ada")]).unwrap(), "Ada");
+        assert_eq!(render_text("{#if name}set{:else}unset{/if}", &[("name", "")]).unwrap(), "unset");
+        assert_eq!(render_text("{signed | date}", &[("signed", "")]).unwrap(), "");
+    }
+
+    #[test]
+    fn filters() {
+        let fields = [("name", "ada LOVELACE"), ("signed", "2025-07-03")];
+        assert_eq!(render_text("{name | upper}", &fields).unwrap(), "ADA LOVELACE");
+        assert_eq!(render_text("{name | lower}", &fields).unwrap(), "ada lovelace");
+        assert_eq!(render_text("{name | lower | title}", &fields).unwrap(), "Ada Lovelace");
+        assert_eq!(render_text("{signed | date}", &fields).unwrap(), "3 July 2025");
+        assert_eq!(render_text("{signed | date:\"Do of MMMM, YYYY\"}", &fields).unwrap(), "3rd of July, 2025");
+        assert_eq!(render_text("{signed | date:\"DD/MM/YYYY (M)\"}", &fields).unwrap(), "03/07/2025 (7)");
+        assert_eq!(
+            validation_message(render_text("{name | date}", &fields)),
+            "name: expected a date as YYYY-MM-DD"
+        );
+    }
+
+    #[test]
+    fn ordinal_days() {
+        let ordinal = |day: &str| render_text("{d | date:\"Do\"}", &[("d", &format!("2025-01-{}", day))]).unwrap();
+        assert_eq!(ordinal("01"), "1st");
+        assert_eq!(ordinal("02"), "2nd");
+        assert_eq!(ordinal("11"), "11th");
+        assert_eq!(ordinal("12"), "12th");
+        assert_eq!(ordinal("13"), "13th");
+        assert_eq!(ordinal("22"), "22nd");
+        assert_eq!(ordinal("31"), "31st");
+    }
+
+    #[test]
+    fn conditions() {
+        let text = "{#if kind == \"sale\"}sale{:else}other{/if} {#if kind != \"sale\"}not sale{/if}{#if !note}, no note{/if}";
+        assert_eq!(render_text(text, &[("kind", "sale"), ("note", "")]).unwrap(), "sale , no note");
+        assert_eq!(render_text(text, &[("kind", "lease"), ("note", "x")]).unwrap(), "other not sale");
+    }
+
+    #[test]
+    fn loops_bind_items_and_drop_standalone_tag_lines() {
+        let text = "Parties:\n{#each parties as party}\n{@index}. {party}{#if @last}.{:else};{/if}\n{/each}\nEnd";
+        assert_eq!(
+            render_text(text, &[("parties", "Ada\n\n  Charles  \n")]).unwrap(),
+            "Parties:\n1. Ada;\n2. Charles.\nEnd"
+        );
+        assert_eq!(render_text(text, &[("parties", "")]).unwrap(), "Parties:\nEnd");
+    }
+
+    #[test]
+    fn references_skip_loop_items_and_at_variables() {
+        let parsed = parse("{name} {#each parties as party}{party} {@index} {city}{/each} {> governing_law}").unwrap();
+        let references: Vec<(String, bool)> =
+            parsed.references(&no_clauses).into_iter().map(|r| (r.name, r.list)).collect();
+        assert_eq!(
+            references,
+            vec![("name".to_string(), false), ("parties".to_string(), true), ("city".to_string(), false)]
+        );
+        assert_eq!(parsed.includes(), vec!["governing_law".to_string()]);
+    }
+
+    #[test]
+    fn references_follow_clauses_to_the_include_tag() {
+        let clause = |id: &str| match id {
+            "signatures" => Some("{#each parties as party}{> signature}{/each}".to_string()),
+            "signature" => Some("Signed: {party}, {title}".to_string()),
+            _ => None,
+        };
+        let text = "{name}\n{> signatures}{> missing}";
+        let references: Vec<(String, Option<String>, (u32, u32))> = parse(text)
+            .unwrap()
+            .references(&clause)
+            .into_iter()
+            .map(|r| {
+                let position = position(text, r.at);
+                (r.name, r.clause, (position.line, position.column))
+            })
+            .collect();
+        assert_eq!(
+            references,
+            vec![
+                ("name".to_string(), None, (1, 1)),
+                ("parties".to_string(), Some("signatures".to_string()), (2, 1)),
+                // `party` is bound by the loop the clause is included in.
+                ("title".to_string(), Some("signature".to_string()), (2, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn clauses_render_in_place_with_the_fields() {
+        let clause = |id: &str| (id == "governing_law").then(|| "governed by the laws of {state}".to_string());
+        let parsed = parse("This agreement is {> governing_law}.").unwrap();
+        assert_eq!(
+            render(&parsed, values(&[("state", "New York")]), &clause).unwrap(),
+            "This agreement is governed by the laws of New York."
+        );
+    }
+
+    #[test]
+    fn missing_clause_is_not_found() {
+        let parsed = parse("{> missing}").unwrap();
+        assert!(matches!(
+            render(&parsed, vec![], &no_clauses),
+            Err(LexError::NotFound { resource, id }) if resource == "Clause" && id == "missing"
+        ));
+    }
+
+    #[test]
+    fn malformed_clause_names_the_clause() {
+        let clause = |_: &str| Some("{#if x}".to_string());
+        let parsed = parse("{> broken}").unwrap();
+        assert_eq!(
+            validation_message(render(&parsed, vec![], &clause)),
+            "clause broken: line 1, column 1: {#if x} is never closed with {/if}"
+        );
+    }
+
+    #[test]
+    fn include_cycles_stop_at_the_depth_limit() {
+        let clause = |id: &str| match id {
+            "a" => Some("a{> b}".to_string()),
+            "b" => Some("b{> a}".to_string()),
+            _ => None,
+        };
+        let parsed = parse("{> a}").unwrap();
+        assert_eq!(
+            validation_message(render(&parsed, vec![], &clause)),
+            "clauses are nested more than 8 deep at a"
+        );
+    }
+
+    #[test]
+    fn includes_may_nest_up_to_the_depth_limit() {
+        // Clause `c<n>` includes `c<n + 1>` up to `c<last>`.
+        let chain = |last: usize| {
+            move |id: &str| {
+                let n: usize = id.strip_prefix('c')?.parse().ok()?;
+                Some(if n < last { format!("{}{{> c{}}}", n, n + 1) } else { n.to_string() })
+            }
+        };
+        let parsed = parse("{> c1}").unwrap();
+        assert_eq!(render(&parsed, vec![], &chain(MAX_INCLUDE_DEPTH)).unwrap(), "12345678");
+        assert_eq!(
+            validation_message(render(&parsed, vec![], &chain(MAX_INCLUDE_DEPTH + 1))),
+            "clauses are nested more than 8 deep at c9"
+        );
+    }
+
+    #[test]
+    fn positions_count_characters() {
+        let text = "é{x}\n  {y}";
+        let position = |offset| {
+            let TextPosition { line, column } = super::position(text, offset);
+            (line, column)
+        };
+        assert_eq!(position(2), (1, 2));
+        assert_eq!(position(text.find("{y}").unwrap()), (2, 3));
+    }
+}
@@ -0,0 +1,68 @@
+use crate::migrations::{decode_versioned, encode_versioned, Versioned};
+use crate::{CanisterMemory, KeyPrincipal, MEMORY_MANAGER};
+use candid::{CandidType, Principal};
+use ic_cdk::api::time;
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    storable::Bound,
+    StableBTreeMap, Storable,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+// A principal allowed to manage system templates without being a controller.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct AdminEntry {
+    pub principal: Principal,
+    pub added_at: u64,
+    pub added_by: Principal,
+}
+
+impl Storable for AdminEntry {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for AdminEntry {
+    const VERSION: u16 = 1;
+}
+
+thread_local! {
+    static ADMINS: RefCell<StableBTreeMap<KeyPrincipal, AdminEntry, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(21)));
+        StableBTreeMap::init(memory)
+    });
+}
+
+// Controllers are always admins.
+pub fn is_admin(principal: Principal) -> bool {
+    ic_cdk::api::is_controller(&principal) || ADMINS.with(|admins| admins.borrow().contains_key(&KeyPrincipal(principal)))
+}
+
+pub fn add_admin(principal: Principal, added_by: Principal) {
+    ADMINS.with(|admins| {
+        admins.borrow_mut().insert(
+            KeyPrincipal(principal),
+            AdminEntry {
+                principal,
+                added_at: time(),
+                added_by,
+            },
+        );
+    });
+}
+
+// Returns whether the principal was an admin.
+pub fn remove_admin(principal: Principal) -> bool {
+    ADMINS.with(|admins| admins.borrow_mut().remove(&KeyPrincipal(principal)).is_some())
+}
+
+pub fn admins() -> Vec<AdminEntry> {
+    ADMINS.with(|admins| admins.borrow().iter().map(|(_, entry)| entry).collect())
+}
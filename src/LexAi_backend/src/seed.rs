@@ -0,0 +1,131 @@
+use crate::templates::{self, RenderMode, TemplateField, TemplateMetadata};
+use crate::{save_template, CanisterMemory, KeyString, LegalTemplate, MEMORY_MANAGER, TEMPLATES};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    StableBTreeMap,
+};
+use serde::Deserialize;
+use std::cell::RefCell;
+
+// Built-in templates
+//
+// The built-in catalogue ships with the canister as one pair of files per
+// template in `builtin/`: `<name>.json` with its id, name, render mode,
+// fields and metadata, and `<name>.txt` with its text. `reconcile` brings the system
+// templates in line with it on every install and upgrade.
+
+macro_rules! builtin {
+    ($name:literal) => {
+        (
+            include_str!(concat!("../builtin/", $name, ".json")),
+            include_str!(concat!("../builtin/", $name, ".txt")),
+        )
+    };
+}
+
+const BUILTIN_FILES: &[(&str, &str)] = &[
+    builtin!("nda"),
+    builtin!("employment"),
+    builtin!("service"),
+    builtin!("partnership"),
+    builtin!("rental"),
+    builtin!("purchase"),
+];
+
+#[derive(Deserialize)]
+struct BuiltinSpec {
+    id: String,
+    name: String,
+    render_mode: RenderMode,
+    fields: Vec<TemplateField>,
+    metadata: TemplateMetadata,
+}
+
+thread_local! {
+    // Version each built-in template was last seeded at, by id. A template
+    // whose version moved on since was saved by an admin.
+    static SEEDED: RefCell<StableBTreeMap<KeyString, u64, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(24)));
+        StableBTreeMap::init(memory)
+    });
+}
+
+fn builtin_templates() -> Vec<LegalTemplate> {
+    BUILTIN_FILES
+        .iter()
+        .map(|(spec, text)| {
+            let spec: BuiltinSpec = serde_json::from_str(spec)
+                .unwrap_or_else(|e| ic_cdk::trap(format!("malformed built-in template file: {}", e)));
+            LegalTemplate {
+                id: spec.id,
+                name: spec.name,
+                template_text: text.strip_suffix('\n').unwrap_or(text).to_string(),
+                fields: spec.fields,
+                owner: None,
+                version: 0,
+                render_mode: spec.render_mode,
+                metadata: spec.metadata,
+            }
+        })
+        .collect()
+}
+
+// Whether the shipped `builtin` may replace `existing`, the template currently
+// stored under its id, if any.
+fn replaceable(builtin: &LegalTemplate, existing: Option<&LegalTemplate>, seeded: Option<u64>) -> bool {
+    match (existing, seeded) {
+        // A private template that took a built-in's id after it was deleted.
+        (Some(existing), _) if existing.owner.is_some() => false,
+        // Seeded before and unchanged since.
+        (Some(existing), Some(seeded)) => existing.version == seeded,
+        // Seeded before and deleted by an admin since.
+        (None, Some(_)) => false,
+        // Never seeded, unless a private template used the id before.
+        (None, None) => templates::history_owned_by(&builtin.id, None),
+        // Seeded before this record was kept: built-in unless an admin
+        // saved the revision in effect.
+        (Some(existing), None) => {
+            existing.same_content(builtin)
+                || templates::revision(&existing.id, existing.version).is_none_or(|revision| revision.created_by.is_none())
+        }
+    }
+}
+
+// Seeds the built-in templates and updates them to the shipped version.
+// Idempotent: a template already at the shipped version is left as it is,
+// and so are user templates and system templates an admin has edited or
+// deleted. Runs in `init` and `post_upgrade`.
+pub fn reconcile() {
+    seed(false);
+}
+
+// Restores every built-in template to the shipped version, undoing admin
+// edits and deletions. Private templates, and ids whose history belongs to
+// one, are still left alone.
+pub fn reset() {
+    seed(true);
+}
+
+fn seed(force: bool) {
+    for builtin in builtin_templates() {
+        let key = KeyString(builtin.id.clone());
+        let existing = TEMPLATES.with(|templates| templates.borrow().get(&key));
+        let seeded = SEEDED.with(|seeded| seeded.borrow().get(&key));
+        let allowed = if force {
+            match &existing {
+                Some(existing) => existing.owner.is_none(),
+                None => templates::history_owned_by(&builtin.id, None),
+            }
+        } else {
+            replaceable(&builtin, existing.as_ref(), seeded)
+        };
+        if !allowed {
+            continue;
+        }
+        let version = match existing {
+            Some(existing) if existing.same_content(&builtin) => existing.version,
+            _ => save_template(builtin, None),
+        };
+        SEEDED.with(|seeded| seeded.borrow_mut().insert(key, version));
+    }
+}
@@ -0,0 +1,1224 @@
+use crate::error::{LexError, LexResult};
+use crate::migrations::{self, decode_versioned, encode_versioned, Versioned};
+use crate::render;
+use crate::{CanisterMemory, KeyString, MEMORY_MANAGER};
+use candid::{CandidType, Principal};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    storable::Bound,
+    StableBTreeMap, Storable,
+};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::ops::Bound::{Excluded, Unbounded};
+
+const MAX_FIELDS: usize = 100;
+const MAX_FIELD_NAME_LEN: usize = 64;
+const MAX_LABEL_LEN: usize = 200;
+const MAX_VALUE_LEN: usize = 10_000;
+const MAX_PARTY_LEN: usize = 200;
+const MAX_CLAUSE_LEN: usize = 20_000;
+const MAX_DESCRIPTION_LEN: usize = 2_000;
+const MAX_AUTHOR_LEN: usize = 200;
+const MAX_LIST_ITEMS: usize = 20;
+const MAX_LIST_ITEM_LEN: usize = 100;
+
+// Longest texts, in lines, that `diff_lines` compares line by line.
+const MAX_DIFF_LINES: usize = 2_000;
+
+// Currency symbols accepted in front of or after a `Money` amount, besides
+// three-letter ISO 4217 codes.
+const CURRENCY_SYMBOLS: &[char] = &['$', '€', '£', '¥', '₹'];
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub enum FieldType {
+    // Free text on one or more lines.
+    Text,
+    // A calendar date as YYYY-MM-DD.
+    Date,
+    // An amount such as "1,250.50 USD" or "$100,000".
+    Money,
+    // The name of a person or organisation, on a single line.
+    Party,
+    // One of a fixed set of values.
+    Enum { options: Vec<String> },
+    // Parties one per line, for templates that loop over them with `#each`.
+    PartyList,
+}
+
+// How a document is produced from a template's rendered text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub enum RenderMode {
+    // The rendered text is the document, word for word, with no LLM call.
+    Deterministic,
+    // The rendered text is a brief the LLM drafts the document from.
+    #[default]
+    LlmAssisted,
+    // The rendered text is the document; the LLM only polishes its wording.
+    LlmPolish,
+}
+
+// One input a template expects, referred to in its text as `{name}`.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub struct TemplateField {
+    pub name: String,
+    pub label: String,
+    pub field_type: FieldType,
+    pub required: bool,
+    // Used when the field is left out.
+    pub default: Option<String>,
+    pub help: Option<String>,
+}
+
+impl TemplateField {
+    pub fn required(name: &str, label: &str, field_type: FieldType) -> Self {
+        TemplateField {
+            name: name.to_string(),
+            label: label.to_string(),
+            field_type,
+            required: true,
+            default: None,
+            help: None,
+        }
+    }
+
+    // Describes why `value` is not acceptable for this field, if it is not.
+    fn check(&self, value: &str) -> Option<String> {
+        if value.chars().count() > MAX_VALUE_LEN {
+            return Some(format!("{}: must be at most {} characters", self.name, MAX_VALUE_LEN));
+        }
+        let problem = match &self.field_type {
+            FieldType::Text => None,
+            FieldType::Date if !is_date(value) => Some("expected a date as YYYY-MM-DD".to_string()),
+            FieldType::Money if !is_money(value) => Some("expected an amount such as 1,250.50 USD".to_string()),
+            FieldType::Party if value.chars().count() > MAX_PARTY_LEN || value.contains('\n') => {
+                Some(format!("expected a single line of at most {} characters", MAX_PARTY_LEN))
+            }
+            FieldType::Enum { options } if !options.iter().any(|option| option == value) => {
+                Some(format!("expected one of {}", options.join(", ")))
+            }
+            FieldType::PartyList if value.lines().any(|line| line.trim().chars().count() > MAX_PARTY_LEN) => {
+                Some(format!("expected one party per line, each at most {} characters", MAX_PARTY_LEN))
+            }
+            _ => None,
+        };
+        problem.map(|problem| format!("{}: {}", self.name, problem))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub enum TemplateCategory {
+    Employment,
+    RealEstate,
+    Commercial,
+    Corporate,
+    IntellectualProperty,
+    Personal,
+    Other,
+}
+
+// Catalogue information about a template, for finding the right one.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub struct TemplateMetadata {
+    pub description: String,
+    pub category: TemplateCategory,
+    // Jurisdictions the template is written for, such as "New York" or
+    // "England and Wales"; empty if it is not specific to any.
+    pub jurisdictions: Vec<String>,
+    // Language of the text as a language tag, such as "en" or "en-GB".
+    pub language: String,
+    // Lowercase keywords.
+    pub tags: Vec<String>,
+    pub author: Option<String>,
+}
+
+impl Default for TemplateMetadata {
+    fn default() -> Self {
+        TemplateMetadata {
+            description: String::new(),
+            category: TemplateCategory::Other,
+            jurisdictions: Vec::new(),
+            language: "en".to_string(),
+            tags: Vec::new(),
+            author: None,
+        }
+    }
+}
+
+impl TemplateMetadata {
+    // Checks the metadata and returns it tidied up: text trimmed, tags
+    // lowercased and duplicates dropped.
+    pub fn normalized(self) -> LexResult<TemplateMetadata> {
+        let list = |what: &str, items: Vec<String>, lowercase: bool| -> LexResult<Vec<String>> {
+            let mut normalized: Vec<String> = Vec::new();
+            for item in items {
+                let item = if lowercase { item.trim().to_lowercase() } else { item.trim().to_string() };
+                if item.is_empty() || item.chars().count() > MAX_LIST_ITEM_LEN {
+                    return Err(LexError::validation(format!(
+                        "{} must be between 1 and {} characters",
+                        what, MAX_LIST_ITEM_LEN
+                    )));
+                }
+                if !normalized.iter().any(|existing| existing.to_lowercase() == item.to_lowercase()) {
+                    normalized.push(item);
+                }
+            }
+            if normalized.len() > MAX_LIST_ITEMS {
+                return Err(LexError::validation(format!("at most {} {}s", MAX_LIST_ITEMS, what)));
+            }
+            Ok(normalized)
+        };
+        let description = self.description.trim().to_string();
+        if description.chars().count() > MAX_DESCRIPTION_LEN {
+            return Err(LexError::validation(format!(
+                "description must be at most {} characters",
+                MAX_DESCRIPTION_LEN
+            )));
+        }
+        let language = self.language.trim().to_string();
+        if !is_language_tag(&language) {
+            return Err(LexError::validation(format!(
+                "language {:?} must be a language tag such as \"en\" or \"en-GB\"",
+                language
+            )));
+        }
+        let author = self.author.map(|author| author.trim().to_string()).filter(|author| !author.is_empty());
+        if author.as_ref().is_some_and(|author| author.chars().count() > MAX_AUTHOR_LEN) {
+            return Err(LexError::validation(format!("author must be at most {} characters", MAX_AUTHOR_LEN)));
+        }
+        Ok(TemplateMetadata {
+            description,
+            category: self.category,
+            jurisdictions: list("jurisdiction", self.jurisdictions, false)?,
+            language,
+            tags: list("tag", self.tags, true)?,
+            author,
+        })
+    }
+
+    // Templates that are not specific to a jurisdiction match every one.
+    pub fn covers_jurisdiction(&self, jurisdiction: &str) -> bool {
+        self.jurisdictions.is_empty() || self.jurisdictions.iter().any(|j| j.eq_ignore_ascii_case(jurisdiction.trim()))
+    }
+
+    // "en" matches "en" and "en-GB"; "en-GB" matches only "en-GB".
+    pub fn has_language(&self, language: &str) -> bool {
+        let (language, wanted) = (self.language.to_lowercase(), language.trim().to_lowercase());
+        language == wanted || language.starts_with(&format!("{}-", wanted))
+    }
+}
+
+// A primary language subtag of two or three letters, optionally followed by
+// a region or script subtag.
+fn is_language_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-');
+    let primary = parts.next().unwrap_or_default();
+    (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && parts.all(|part| (2..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+// Lowercase words of a search text.
+pub fn search_terms(text: &str) -> Vec<String> {
+    text.split_whitespace().map(str::to_lowercase).collect()
+}
+
+// How well a template matches search `terms`, or `None` unless every term
+// occurs in its id, name, description or tags. Matches in the name count most.
+pub fn relevance(terms: &[String], id: &str, name: &str, metadata: &TemplateMetadata) -> Option<u32> {
+    let (id, name, description) = (id.to_lowercase(), name.to_lowercase(), metadata.description.to_lowercase());
+    terms.iter().try_fold(0, |score, term| {
+        let term = term.as_str();
+        let term_score = [
+            (name.contains(term), 4),
+            (metadata.tags.iter().any(|tag| tag.contains(term)), 3),
+            (id.contains(term), 2),
+            (description.contains(term), 1),
+        ]
+        .iter()
+        .filter(|(matched, _)| *matched)
+        .map(|(_, weight)| weight)
+        .sum::<u32>();
+        (term_score > 0).then_some(score + term_score)
+    })
+}
+
+// Orders scored search results best first, and equally good ones by name.
+// Without search terms every template scores 0, so all are in name order.
+pub fn by_relevance((a_score, a_name): (u32, &str), (b_score, b_name): (u32, &str)) -> Ordering {
+    b_score.cmp(&a_score).then_with(|| a_name.cmp(b_name))
+}
+
+// 1-based line and column (in characters) within a template's text.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TextPosition {
+    pub line: u32,
+    pub column: u32,
+}
+
+// A placeholder and where it occurs, so repeated uses are visible.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PlaceholderUse {
+    pub name: String,
+    pub positions: Vec<TextPosition>,
+}
+
+// What a template's text refers to, for template authors.
+pub struct TextOutline {
+    // In order of first appearance.
+    pub placeholders: Vec<PlaceholderUse>,
+    // Braces that are not part of a tag, such as a `{` that is never closed
+    // or braces around something other than a field name.
+    pub unmatched_braces: Vec<TextPosition>,
+    // Why the text does not parse, if it does not; `placeholders` is empty
+    // then.
+    pub syntax_error: Option<String>,
+}
+
+pub fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Fields the included clauses refer to are listed at their include tag.
+pub fn outline(text: &str) -> TextOutline {
+    let tags = render::tags(text);
+    let (placeholders, syntax_error) = match render::parse(text) {
+        Ok(parsed) => {
+            let mut placeholders: Vec<PlaceholderUse> = Vec::new();
+            for reference in parsed.references(&clause_text) {
+                let position = render::position(text, reference.at);
+                match placeholders.iter_mut().find(|p| p.name == reference.name) {
+                    Some(placeholder) => placeholder.positions.push(position),
+                    None => placeholders.push(PlaceholderUse {
+                        name: reference.name,
+                        positions: vec![position],
+                    }),
+                }
+            }
+            (placeholders, None)
+        }
+        Err(LexError::Validation { message }) => (Vec::new(), Some(message)),
+        Err(error) => (Vec::new(), Some(error.to_string())),
+    };
+    let unmatched_braces = text
+        .char_indices()
+        .filter(|(i, c)| (*c == '{' || *c == '}') && !tags.iter().any(|tag| (tag.start..tag.end).contains(i)))
+        .map(|(i, _)| render::position(text, i))
+        .collect();
+    TextOutline {
+        placeholders,
+        unmatched_braces,
+        syntax_error,
+    }
+}
+
+// Schema for a template that does not declare one: every field the text or
+// its clauses refer to becomes a required text field, or a party list if it
+// is looped over.
+pub fn fields_from_placeholders(text: &str) -> LexResult<Vec<TemplateField>> {
+    let mut fields: Vec<TemplateField> = Vec::new();
+    for reference in render::parse(text)?.references(&clause_text) {
+        let field_type = if reference.list { FieldType::PartyList } else { FieldType::Text };
+        match fields.iter_mut().find(|field| field.name == reference.name) {
+            Some(field) if reference.list => field.field_type = field_type,
+            Some(_) => {}
+            None => fields.push(TemplateField::required(&reference.name, &reference.name, field_type)),
+        }
+    }
+    Ok(fields)
+}
+
+// Checks that `text` parses and that the schema is well formed and declares
+// every field the text and the clauses it includes refer to.
+pub fn validate_schema(text: &str, fields: &[TemplateField]) -> LexResult<()> {
+    if fields.len() > MAX_FIELDS {
+        return Err(LexError::validation(format!("a template has at most {} fields", MAX_FIELDS)));
+    }
+    let parsed = render::parse(text)?;
+    let mut problems = Vec::new();
+    let mut names = BTreeSet::new();
+    for field in fields {
+        if !is_identifier(&field.name) || field.name.len() > MAX_FIELD_NAME_LEN {
+            problems.push(format!(
+                "field name {:?} must be a letter or underscore followed by letters, digits or underscores, at most {} bytes",
+                field.name, MAX_FIELD_NAME_LEN
+            ));
+            continue;
+        }
+        if !names.insert(field.name.as_str()) {
+            problems.push(format!("{}: declared more than once", field.name));
+        }
+        if field.label.trim().is_empty() || field.label.chars().count() > MAX_LABEL_LEN {
+            problems.push(format!("{}: label must be between 1 and {} characters", field.name, MAX_LABEL_LEN));
+        }
+        if let FieldType::Enum { options } = &field.field_type {
+            let distinct: BTreeSet<_> = options.iter().collect();
+            if options.is_empty() || distinct.len() != options.len() || options.iter().any(|o| o.trim().is_empty()) {
+                problems.push(format!("{}: enum options must be non-empty and distinct", field.name));
+            }
+        }
+        if let Some(problem) = field.default.as_deref().and_then(|default| field.check(default)) {
+            problems.push(format!("default of {}", problem));
+        }
+    }
+    let mut missing = BTreeSet::new();
+    for reference in parsed.references(&clause_text) {
+        if !names.contains(reference.name.as_str()) && missing.insert(reference.name.clone()) {
+            match reference.clause {
+                Some(clause_id) => problems.push(format!(
+                    "placeholder {{{}}} in clause {} has no field",
+                    reference.name, clause_id
+                )),
+                None => problems.push(format!("placeholder {{{}}} has no field", reference.name)),
+            }
+        }
+    }
+    into_result(problems)
+}
+
+// Checks submitted values against a template's schema and returns them in
+// schema order, with defaults filled in and surrounding whitespace trimmed.
+// Every problem found is reported at once.
+pub fn validate_fields(schema: &[TemplateField], submitted: &[(String, String)]) -> LexResult<Vec<(String, String)>> {
+    let mut problems = Vec::new();
+    let mut seen = BTreeSet::new();
+    for (name, _) in submitted {
+        if !schema.iter().any(|field| &field.name == name) {
+            problems.push(format!("{}: unknown field", name));
+        } else if !seen.insert(name.as_str()) {
+            problems.push(format!("{}: given more than once", name));
+        }
+    }
+
+    let mut values = Vec::new();
+    for field in schema {
+        let value = submitted
+            .iter()
+            .find(|(name, _)| name == &field.name)
+            .map(|(_, value)| value.trim())
+            .filter(|value| !value.is_empty())
+            .or(field.default.as_deref());
+        match value {
+            Some(value) => match field.check(value) {
+                Some(problem) => problems.push(problem),
+                None => values.push((field.name.clone(), value.to_string())),
+            },
+            None if field.required => problems.push(format!("{}: required", field.name)),
+            None => {}
+        }
+    }
+    into_result(problems).map(|()| values)
+}
+
+fn into_result(problems: Vec<String>) -> LexResult<()> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(LexError::validation(problems.join("; ")))
+    }
+}
+
+// A rendered template text and the clause revisions it included.
+pub struct Rendered {
+    pub text: String,
+    // In order of first inclusion.
+    pub clauses: Vec<ClauseVersion>,
+}
+
+// Renders a template's text with validated `values`. Optional fields that
+// were left out render as empty text.
+pub fn render(text: &str, schema: &[TemplateField], values: &[(String, String)]) -> LexResult<Rendered> {
+    let parsed = render::parse(text)?;
+    let values = schema
+        .iter()
+        .map(|field| {
+            let value = values.iter().find(|(name, _)| name == &field.name).map(|(_, value)| value.clone());
+            (field.name.clone(), value.unwrap_or_default())
+        })
+        .collect();
+    let included = RefCell::new(Vec::new());
+    let text = render::render(&parsed, values, &|id| {
+        let clause = clause(id)?;
+        let mut included = included.borrow_mut();
+        if !included.iter().any(|used: &ClauseVersion| used.clause_id == clause.id) {
+            included.push(ClauseVersion {
+                clause_id: clause.id,
+                version: clause.version,
+            });
+        }
+        Some(clause.text)
+    })?;
+    Ok(Rendered {
+        text,
+        clauses: included.into_inner(),
+    })
+}
+
+// Clauses
+//
+// Reusable snippets of template text, such as a standard governing law
+// clause, included into templates with `{> clause_id}`. Clauses are shared by
+// all templates and are managed by admins. Like templates, every saved state
+// of a clause is kept as an immutable revision, and documents record the
+// clause versions they included.
+
+// A clause as it was saved at `version`. Versions of one clause id count up
+// from 1 and are never reused, not even after the clause is deleted.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct Clause {
+    pub id: String,
+    pub text: String,
+    pub updated_at: u64,
+    pub updated_by: Principal,
+    pub version: u64,
+}
+
+// Layout of `Clause` version 1, before clauses were versioned.
+#[derive(CandidType, Deserialize)]
+struct ClauseV1 {
+    id: String,
+    text: String,
+    updated_at: u64,
+    updated_by: Principal,
+}
+
+// A clause revision that a document included.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub struct ClauseVersion {
+    pub clause_id: String,
+    pub version: u64,
+}
+
+impl Storable for Clause {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for Clause {
+    const VERSION: u16 = 2;
+    // A clause saved before clauses were versioned is at version 1; the
+    // schema v6 migration records it as that revision.
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        let legacy: ClauseV1 = migrations::decode_candid(version, bytes);
+        Clause {
+            id: legacy.id,
+            text: legacy.text,
+            updated_at: legacy.updated_at,
+            updated_by: legacy.updated_by,
+            version: 1,
+        }
+    }
+}
+
+pub fn validate_clause(id: &str, text: &str) -> LexResult<()> {
+    if !is_identifier(id) || id.len() > MAX_FIELD_NAME_LEN {
+        return Err(LexError::validation(format!(
+            "clause id must be a letter or underscore followed by letters, digits or underscores, at most {} bytes",
+            MAX_FIELD_NAME_LEN
+        )));
+    }
+    if text.trim().is_empty() || text.len() > MAX_CLAUSE_LEN {
+        return Err(LexError::validation(format!(
+            "clause text must be between 1 and {} bytes",
+            MAX_CLAUSE_LEN
+        )));
+    }
+    render::parse(text).map(|_| ())
+}
+
+pub fn clause(id: &str) -> Option<Clause> {
+    CLAUSES.with(|clauses| clauses.borrow().get(&KeyString(id.to_string())))
+}
+
+fn clause_text(id: &str) -> Option<String> {
+    clause(id).map(|clause| clause.text)
+}
+
+pub fn clauses() -> Vec<Clause> {
+    CLAUSES.with(|clauses| clauses.borrow().iter().map(|(_, clause)| clause).collect())
+}
+
+// Up to `limit` clauses after id `after`, in id order.
+pub fn clauses_after(after: Option<String>, limit: usize) -> Vec<Clause> {
+    CLAUSES.with(|clauses| {
+        let clauses = clauses.borrow();
+        match after {
+            Some(id) => clauses
+                .range((Excluded(KeyString(id)), Unbounded))
+                .take(limit)
+                .map(|(_, clause)| clause)
+                .collect(),
+            None => clauses.iter().take(limit).map(|(_, clause)| clause).collect(),
+        }
+    })
+}
+
+// Stores `text` as the next revision of clause `id` and makes it current.
+// Returns the new version.
+pub fn set_clause(id: String, text: String, updated_at: u64, updated_by: Principal) -> u64 {
+    // A clause that predates versioning gets its revision 1 recorded first,
+    // unless the v6 migration already did.
+    if let Some(current) = clause(&id) {
+        record_clause_revision(current);
+    }
+    let version = latest_clause_version(&id).map_or(1, |version| version + 1);
+    let clause = Clause {
+        id,
+        text,
+        updated_at,
+        updated_by,
+        version,
+    };
+    record_clause_revision(clause.clone());
+    CLAUSES.with(|clauses| clauses.borrow_mut().insert(KeyString(clause.id.clone()), clause));
+    version
+}
+
+// Returns whether the clause existed. Its revisions are kept.
+pub fn delete_clause(id: &str) -> bool {
+    CLAUSES.with(|clauses| clauses.borrow_mut().remove(&KeyString(id.to_string())).is_some())
+}
+
+fn latest_clause_version(id: &str) -> Option<u64> {
+    CLAUSE_REVISIONS.with(|revisions| {
+        revisions
+            .borrow()
+            .range(revision_range(id))
+            .next_back()
+            .map(|(key, _)| key.version)
+    })
+}
+
+pub fn clause_revision(id: &str, version: u64) -> Option<Clause> {
+    let key = RevisionKey {
+        template_id: id.to_string(),
+        version,
+    };
+    CLAUSE_REVISIONS.with(|revisions| revisions.borrow().get(&key))
+}
+
+// Every revision of a clause, oldest first.
+pub fn clause_revisions(id: &str) -> Vec<Clause> {
+    CLAUSE_REVISIONS.with(|revisions| {
+        revisions
+            .borrow()
+            .range(revision_range(id))
+            .map(|(_, revision)| revision)
+            .collect()
+    })
+}
+
+// Stores a clause revision unless one with the same id and version already
+// exists; revisions are never overwritten.
+pub fn record_clause_revision(clause: Clause) {
+    let key = RevisionKey {
+        template_id: clause.id.clone(),
+        version: clause.version,
+    };
+    CLAUSE_REVISIONS.with(|revisions| {
+        let mut revisions = revisions.borrow_mut();
+        if !revisions.contains_key(&key) {
+            revisions.insert(key, clause);
+        }
+    });
+}
+
+// Revisions
+//
+// Every saved state of a template is kept as an immutable revision, so a
+// document can always be traced to the exact text that produced it, even
+// after the template is changed or deleted.
+
+// A template as it was saved at `version`. Versions of one template id count
+// up from 1 and are never reused, not even after the template is deleted.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TemplateRevision {
+    pub template_id: String,
+    pub version: u64,
+    pub name: String,
+    pub template_text: String,
+    pub fields: Vec<TemplateField>,
+    // `None` for system templates.
+    pub owner: Option<Principal>,
+    pub created_at: u64,
+    // `None` for built-in templates and for templates that predate revisions.
+    pub created_by: Option<Principal>,
+    pub render_mode: RenderMode,
+    pub metadata: TemplateMetadata,
+}
+
+// Layout of `TemplateRevision` version 1. Revisions recorded before render
+// modes or metadata lack those fields.
+#[derive(CandidType, Deserialize)]
+struct TemplateRevisionV1 {
+    template_id: String,
+    version: u64,
+    name: String,
+    template_text: String,
+    fields: Vec<TemplateField>,
+    owner: Option<Principal>,
+    created_at: u64,
+    created_by: Option<Principal>,
+    render_mode: Option<RenderMode>,
+    metadata: Option<TemplateMetadata>,
+}
+
+impl Storable for TemplateRevision {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for TemplateRevision {
+    const VERSION: u16 = 2;
+    // Revisions that predate render modes were all drafted by the LLM, and
+    // those that predate metadata get the default.
+    fn upgrade(version: u16, bytes: &[u8]) -> Self {
+        let legacy: TemplateRevisionV1 = migrations::decode_candid(version, bytes);
+        TemplateRevision {
+            template_id: legacy.template_id,
+            version: legacy.version,
+            name: legacy.name,
+            template_text: legacy.template_text,
+            fields: legacy.fields,
+            owner: legacy.owner,
+            created_at: legacy.created_at,
+            created_by: legacy.created_by,
+            render_mode: legacy.render_mode.unwrap_or(RenderMode::LlmAssisted),
+            metadata: legacy.metadata.unwrap_or_default(),
+        }
+    }
+}
+
+// Revisions are keyed by (template or clause id, version), so the history of
+// one template or clause is a contiguous range, oldest first.
+#[derive(Ord, PartialOrd, Eq, PartialEq, Clone)]
+struct RevisionKey {
+    template_id: String,
+    version: u64,
+}
+
+impl Storable for RevisionKey {
+    const BOUND: Bound = Bound::Bounded { max_size: 108, is_fixed_size: false };
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.version.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.template_id.as_bytes());
+        Cow::Owned(bytes)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (version, template_id) = bytes.split_at(8);
+        RevisionKey {
+            template_id: String::from_utf8(template_id.to_vec()).unwrap(),
+            version: u64::from_be_bytes(version.try_into().unwrap()),
+        }
+    }
+}
+
+thread_local! {
+    static CLAUSES: RefCell<StableBTreeMap<KeyString, Clause, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(23)));
+        StableBTreeMap::init(memory)
+    });
+
+    static TEMPLATE_REVISIONS: RefCell<StableBTreeMap<RevisionKey, TemplateRevision, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(22)));
+        StableBTreeMap::init(memory)
+    });
+
+    static CLAUSE_REVISIONS: RefCell<StableBTreeMap<RevisionKey, Clause, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(25)));
+        StableBTreeMap::init(memory)
+    });
+}
+
+fn revision_range(template_id: &str) -> std::ops::RangeInclusive<RevisionKey> {
+    RevisionKey {
+        template_id: template_id.to_string(),
+        version: 0,
+    }..=RevisionKey {
+        template_id: template_id.to_string(),
+        version: u64::MAX,
+    }
+}
+
+pub fn latest_version(template_id: &str) -> Option<u64> {
+    TEMPLATE_REVISIONS.with(|revisions| {
+        revisions
+            .borrow()
+            .range(revision_range(template_id))
+            .next_back()
+            .map(|(key, _)| key.version)
+    })
+}
+
+pub fn revision(template_id: &str, version: u64) -> Option<TemplateRevision> {
+    let key = RevisionKey {
+        template_id: template_id.to_string(),
+        version,
+    };
+    TEMPLATE_REVISIONS.with(|revisions| revisions.borrow().get(&key))
+}
+
+pub fn revisions(template_id: &str) -> Vec<TemplateRevision> {
+    TEMPLATE_REVISIONS.with(|revisions| {
+        revisions
+            .borrow()
+            .range(revision_range(template_id))
+            .map(|(_, revision)| revision)
+            .collect()
+    })
+}
+
+// Whether every recorded revision of `template_id` has `owner`. An id keeps
+// its history after the template is deleted, so only the owner it was
+// recorded under may use it again.
+pub fn history_owned_by(template_id: &str, owner: Option<Principal>) -> bool {
+    TEMPLATE_REVISIONS.with(|revisions| {
+        revisions
+            .borrow()
+            .range(revision_range(template_id))
+            .all(|(_, revision)| revision.owner == owner)
+    })
+}
+
+// Stores a revision unless one with the same id and version already exists;
+// revisions are never overwritten.
+pub fn record_revision(revision: TemplateRevision) {
+    let key = RevisionKey {
+        template_id: revision.template_id.clone(),
+        version: revision.version,
+    };
+    TEMPLATE_REVISIONS.with(|revisions| {
+        let mut revisions = revisions.borrow_mut();
+        if !revisions.contains_key(&key) {
+            revisions.insert(key, revision);
+        }
+    });
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum DiffLine {
+    Same(String),
+    Added(String),
+    Removed(String),
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct FieldChange {
+    pub before: TemplateField,
+    pub after: TemplateField,
+}
+
+// What changed between two revisions of a template.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct TemplateDiff {
+    pub template_id: String,
+    pub from_version: u64,
+    pub to_version: u64,
+    // Set when the name changed.
+    pub name: Option<(String, String)>,
+    // Set when the render mode changed.
+    pub render_mode: Option<(RenderMode, RenderMode)>,
+    // Set when the metadata changed.
+    pub metadata: Option<(TemplateMetadata, TemplateMetadata)>,
+    // The text line by line, unchanged lines included.
+    pub text: Vec<DiffLine>,
+    pub fields_added: Vec<TemplateField>,
+    pub fields_removed: Vec<TemplateField>,
+    pub fields_changed: Vec<FieldChange>,
+}
+
+pub fn diff(from: &TemplateRevision, to: &TemplateRevision) -> LexResult<TemplateDiff> {
+    let field = |fields: &[TemplateField], name: &str| fields.iter().find(|f| f.name == name).cloned();
+    Ok(TemplateDiff {
+        template_id: to.template_id.clone(),
+        from_version: from.version,
+        to_version: to.version,
+        name: (from.name != to.name).then(|| (from.name.clone(), to.name.clone())),
+        render_mode: (from.render_mode != to.render_mode).then_some((from.render_mode, to.render_mode)),
+        metadata: (from.metadata != to.metadata).then(|| (from.metadata.clone(), to.metadata.clone())),
+        text: diff_lines(&from.template_text, &to.template_text)?,
+        fields_added: to.fields.iter().filter(|f| field(&from.fields, &f.name).is_none()).cloned().collect(),
+        fields_removed: from.fields.iter().filter(|f| field(&to.fields, &f.name).is_none()).cloned().collect(),
+        fields_changed: from
+            .fields
+            .iter()
+            .filter_map(|before| {
+                let after = field(&to.fields, &before.name)?;
+                (after != *before).then(|| FieldChange {
+                    before: before.clone(),
+                    after,
+                })
+            })
+            .collect(),
+    })
+}
+
+// Line diff from the longest common subsequence of the two texts' lines.
+fn diff_lines(from: &str, to: &str) -> LexResult<Vec<DiffLine>> {
+    let a: Vec<&str> = from.lines().collect();
+    let b: Vec<&str> = to.lines().collect();
+    if a.len() > MAX_DIFF_LINES || b.len() > MAX_DIFF_LINES {
+        return Err(LexError::validation(format!(
+            "texts longer than {} lines cannot be diffed",
+            MAX_DIFF_LINES
+        )));
+    }
+    // lcs[i][j]: length of the common subsequence of a[i..] and b[j..].
+    let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::new();
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push(DiffLine::Same(a[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(DiffLine::Removed(a[i].to_string()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(b[j].to_string()));
+            j += 1;
+        }
+    }
+    Ok(lines)
+}
+
+fn is_date(value: &str) -> bool {
+    let parts: Vec<&str> = value.split('-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        return false;
+    };
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return false;
+    }
+    let (Ok(year), Ok(month), Ok(day)) = (year.parse::<u32>(), month.parse::<u32>(), day.parse::<u32>()) else {
+        return false;
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days_in_month).contains(&day)
+}
+
+// An amount with an optional currency code or symbol before or after it.
+// Thousands may be grouped with commas; at most two decimals are allowed.
+fn is_money(value: &str) -> bool {
+    let amount = strip_currency(value.trim());
+    let (whole, decimals) = match amount.split_once('.') {
+        Some((whole, decimals)) => (whole, Some(decimals)),
+        None => (amount, None),
+    };
+    let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    let whole_ok = if whole.contains(',') {
+        let groups: Vec<&str> = whole.split(',').collect();
+        (1..=3).contains(&groups[0].len())
+            && groups.iter().all(|group| digits(group))
+            && groups[1..].iter().all(|group| group.len() == 3)
+    } else {
+        digits(whole)
+    };
+    whole_ok && decimals.is_none_or(|decimals| digits(decimals) && decimals.len() <= 2)
+}
+
+fn strip_currency(value: &str) -> &str {
+    let is_code = |s: &str| s.len() == 3 && s.chars().all(|c| c.is_ascii_uppercase());
+    if let Some(rest) = value.strip_prefix(CURRENCY_SYMBOLS) {
+        return rest.trim_start();
+    }
+    if let Some(rest) = value.strip_suffix(CURRENCY_SYMBOLS) {
+        return rest.trim_end();
+    }
+    match (value.split_once(' '), value.rsplit_once(' ')) {
+        (Some((code, rest)), _) if is_code(code) => rest.trim_start(),
+        (_, Some((rest, code))) if is_code(code) => rest.trim_end(),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(name: &str, field_type: FieldType) -> TemplateField {
+        TemplateField::required(name, name, field_type)
+    }
+
+    fn optional(name: &str, default: Option<&str>) -> TemplateField {
+        TemplateField {
+            required: false,
+            default: default.map(str::to_string),
+            ..field(name, FieldType::Text)
+        }
+    }
+
+    fn submit(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    fn problems(schema: &[TemplateField], pairs: &[(&str, &str)]) -> String {
+        match validate_fields(schema, &submit(pairs)) {
+            Err(LexError::Validation { message }) => message,
+            Err(e) => panic!("expected a validation error, got {}", e),
+            Ok(values) => panic!("expected problems, got {:?}", values),
+        }
+    }
+
+    #[test]
+    fn validate_fields_trims_and_fills_defaults() {
+        let schema = [
+            field("buyer", FieldType::Party),
+            optional("note", None),
+            optional("venue", Some("New York")),
+        ];
+        assert_eq!(
+            validate_fields(&schema, &submit(&[("buyer", "  Ada Lovelace "), ("venue", "  ")])).unwrap(),
+            submit(&[("buyer", "Ada Lovelace"), ("venue", "New York")])
+        );
+    }
+
+    #[test]
+    fn validate_fields_reports_every_problem() {
+        let schema = [field("buyer", FieldType::Party), field("price", FieldType::Money)];
+        assert_eq!(
+            problems(&schema, &[("seller", "Bob"), ("price", "1"), ("price", "2")]),
+            "seller: unknown field; price: given more than once; buyer: required"
+        );
+        assert_eq!(
+            problems(&schema, &[("buyer", " "), ("price", "ten")]),
+            "buyer: required; price: expected an amount such as 1,250.50 USD"
+        );
+    }
+
+    #[test]
+    fn validate_fields_checks_types() {
+        let schema = [
+            field("signed", FieldType::Date),
+            field("party", FieldType::Party),
+            field(
+                "kind",
+                FieldType::Enum {
+                    options: vec!["sale".to_string(), "lease".to_string()],
+                },
+            ),
+            field("parties", FieldType::PartyList),
+        ];
+        let long = "x".repeat(MAX_PARTY_LEN + 1);
+        assert_eq!(
+            problems(&schema, &[("signed", "2025-02-30"), ("party", "Ada\nBob"), ("kind", "gift"), ("parties", &long)]),
+            format!(
+                "signed: expected a date as YYYY-MM-DD; party: expected a single line of at most {0} characters; \
+                 kind: expected one of sale, lease; parties: expected one party per line, each at most {0} characters",
+                MAX_PARTY_LEN
+            )
+        );
+        assert!(validate_fields(
+            &schema,
+            &submit(&[("signed", "2024-02-29"), ("party", "Ada"), ("kind", "lease"), ("parties", "Ada\nBob")])
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn validate_fields_limits_value_length() {
+        let schema = [field("body", FieldType::Text)];
+        let long = "x".repeat(MAX_VALUE_LEN + 1);
+        assert_eq!(
+            problems(&schema, &[("body", &long)]),
+            format!("body: must be at most {} characters", MAX_VALUE_LEN)
+        );
+    }
+
+    #[test]
+    fn money() {
+        for valid in ["100", "1,250.50 USD", "USD 1,250.50", "$100,000", "100,000$", "€ 5.5", "1,000,000", "0.99 GBP"] {
+            assert!(is_money(valid), "{:?} should be money", valid);
+        }
+        for invalid in ["", "$", "USD", "ten", "1,25", "12,345,67", ",100", "1.234", "1.", "1,000.5.0", "usd 5", "-5"] {
+            assert!(!is_money(invalid), "{:?} should not be money", invalid);
+        }
+    }
+
+    #[test]
+    fn dates() {
+        for valid in ["2025-01-31", "2024-02-29", "2000-02-29", "2025-12-01"] {
+            assert!(is_date(valid), "{:?} should be a date", valid);
+        }
+        for invalid in [
+            "", "2025-1-01", "2025-01-1", "25-01-01", "2023-02-29", "1900-02-29", "2025-04-31", "2025-13-01", "2025-00-10",
+            "2025-01-00", "2025/01/01", "2025-01-01-01",
+        ] {
+            assert!(!is_date(invalid), "{:?} should not be a date", invalid);
+        }
+    }
+
+    #[test]
+    fn diff_lines_follows_the_longest_common_subsequence() {
+        use DiffLine::{Added, Removed, Same};
+        let line = |s: &str| s.to_string();
+        assert_eq!(
+            diff_lines("a\nb\nc\nd", "a\nc\nx\nd\ne").unwrap(),
+            vec![Same(line("a")), Removed(line("b")), Same(line("c")), Added(line("x")), Same(line("d")), Added(line("e"))]
+        );
+        assert_eq!(diff_lines("", "a").unwrap(), vec![Added(line("a"))]);
+        assert_eq!(diff_lines("a", "").unwrap(), vec![Removed(line("a"))]);
+        assert!(diff_lines("", "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn diff_lines_is_capped() {
+        let text = |lines: usize| vec!["line"; lines].join("\n");
+        assert_eq!(diff_lines(&text(MAX_DIFF_LINES), &text(MAX_DIFF_LINES)).unwrap().len(), MAX_DIFF_LINES);
+        for (from, to) in [(MAX_DIFF_LINES + 1, 1), (1, MAX_DIFF_LINES + 1)] {
+            assert!(matches!(
+                diff_lines(&text(from), &text(to)),
+                Err(LexError::Validation { message }) if message == "texts longer than 2000 lines cannot be diffed"
+            ));
+        }
+    }
+
+    fn metadata(description: &str, tags: &[&str]) -> TemplateMetadata {
+        TemplateMetadata {
+            description: description.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..TemplateMetadata::default()
+        }
+    }
+
+    // (id, name, metadata) of a small catalogue.
+    fn catalogue() -> Vec<(&'static str, &'static str, TemplateMetadata)> {
+        vec![
+            ("nda", "Non-Disclosure Agreement", metadata("Mutual confidentiality", &["confidentiality"])),
+            ("lease", "Residential Lease", metadata("Lease of a flat or house", &["property", "rental"])),
+            ("employment", "Employment Contract", metadata("Hires an employee", &["employment", "hr"])),
+            ("contractor", "Consulting Agreement", metadata("Confidential work by a non-employee", &[])),
+        ]
+    }
+
+    // Names of the catalogue entries matching `text`, as search orders them.
+    fn search(text: &str) -> Vec<&'static str> {
+        let terms = search_terms(text);
+        let mut matches: Vec<(u32, &str)> = catalogue()
+            .iter()
+            .filter_map(|(id, name, metadata)| Some((relevance(&terms, id, name, metadata)?, *name)))
+            .collect();
+        matches.sort_by(|a, b| by_relevance(*a, *b));
+        matches.into_iter().map(|(_, name)| name).collect()
+    }
+
+    #[test]
+    fn search_terms_are_lowercase_words() {
+        assert_eq!(search_terms("  Lease\tRESIDENTIAL  flat "), ["lease", "residential", "flat"]);
+        assert!(search_terms("   ").is_empty());
+    }
+
+    #[test]
+    fn relevance_weighs_where_a_term_matches() {
+        let nda = metadata("Mutual confidentiality", &["confidentiality"]);
+        let score = |text: &str| relevance(&search_terms(text), "nda", "Non-Disclosure Agreement", &nda);
+        assert_eq!(score("agreement"), Some(4));
+        assert_eq!(score("nda"), Some(2));
+        assert_eq!(score("mutual"), Some(1));
+        // Tag and description.
+        assert_eq!(score("confidential"), Some(4));
+        // Name and id.
+        assert_eq!(score("disclosure NDA"), Some(4 + 2));
+        assert_eq!(score("lease"), None);
+    }
+
+    #[test]
+    fn every_term_must_match() {
+        assert_eq!(search("agreement confidential"), ["Non-Disclosure Agreement", "Consulting Agreement"]);
+        assert!(search("agreement rental").is_empty());
+    }
+
+    #[test]
+    fn better_matches_come_first_then_names() {
+        // A name match beats a tag match, which beats a description match.
+        assert_eq!(search("employ"), ["Employment Contract", "Consulting Agreement"]);
+        assert_eq!(search("agreement"), ["Consulting Agreement", "Non-Disclosure Agreement"]);
+    }
+
+    #[test]
+    fn an_empty_query_lists_every_template_by_name() {
+        assert_eq!(
+            search(""),
+            ["Consulting Agreement", "Employment Contract", "Non-Disclosure Agreement", "Residential Lease"]
+        );
+    }
+
+    #[test]
+    fn normalized_tidies_lists_and_text() {
+        let normalized = TemplateMetadata {
+            description: "  A lease.  ".to_string(),
+            jurisdictions: vec![" New York ".to_string(), "new york".to_string(), "Ontario".to_string()],
+            language: " en-GB ".to_string(),
+            tags: vec!["Property".to_string(), " property ".to_string(), "RENTAL".to_string()],
+            author: Some("  ".to_string()),
+            ..TemplateMetadata::default()
+        }
+        .normalized()
+        .unwrap();
+        assert_eq!(normalized.description, "A lease.");
+        assert_eq!(normalized.jurisdictions, ["New York", "Ontario"]);
+        assert_eq!(normalized.language, "en-GB");
+        assert_eq!(normalized.tags, ["property", "rental"]);
+        assert_eq!(normalized.author, None);
+    }
+
+    #[test]
+    fn normalized_rejects_invalid_metadata() {
+        let invalid = |metadata: TemplateMetadata| metadata.normalized().is_err();
+        assert!(invalid(TemplateMetadata {
+            language: "english".to_string(),
+            ..TemplateMetadata::default()
+        }));
+        assert!(invalid(metadata("", &[" "])));
+        assert!(invalid(metadata(&"x".repeat(MAX_DESCRIPTION_LEN + 1), &[])));
+    }
+
+    #[test]
+    fn jurisdiction_and_language_filters() {
+        let any = TemplateMetadata::default();
+        let york = TemplateMetadata {
+            jurisdictions: vec!["New York".to_string()],
+            language: "en-US".to_string(),
+            ..TemplateMetadata::default()
+        };
+        assert!(any.covers_jurisdiction("Ontario"));
+        assert!(york.covers_jurisdiction(" new york "));
+        assert!(!york.covers_jurisdiction("Ontario"));
+        assert!(york.has_language("en"));
+        assert!(york.has_language("EN-us"));
+        assert!(!york.has_language("en-GB"));
+        assert!(!any.has_language("en-GB"));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::jobs::{Job, JobKind, Outcall};
+use crate::migrations::{decode_versioned, encode_versioned, Versioned};
+use crate::{CanisterMemory, KeyPrincipal, KeyString, PrincipalSeqKey, MEMORY_MANAGER};
+use candid::{CandidType, Principal};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, VirtualMemory},
+    storable::Bound,
+    StableBTreeMap, Storable,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+// Maximum number of ledger entries returned per page.
+const MAX_ENTRIES_PER_PAGE: u64 = 100;
+
+// One LLM outcall, attributed to the principal whose job made it.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct UsageEntry {
+    pub principal: Principal,
+    pub job_id: u64,
+    // Set for document jobs, so expensive templates can be spotted.
+    pub template_id: Option<String>,
+    pub provider: String,
+    pub model: String,
+    pub at: u64,
+    pub succeeded: bool,
+    pub cycles_attached: u128,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl Storable for UsageEntry {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for UsageEntry {
+    const VERSION: u16 = 1;
+}
+
+// Running sums over a set of ledger entries.
+#[derive(Clone, Default, CandidType, Deserialize)]
+pub struct UsageTotals {
+    pub calls: u64,
+    pub failed_calls: u64,
+    pub cycles_attached: u128,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, entry: &UsageEntry) {
+        self.calls += 1;
+        if !entry.succeeded {
+            self.failed_calls += 1;
+        }
+        self.cycles_attached += entry.cycles_attached;
+        self.request_bytes += entry.request_bytes;
+        self.response_bytes += entry.response_bytes;
+        self.prompt_tokens += entry.prompt_tokens;
+        self.completion_tokens += entry.completion_tokens;
+    }
+}
+
+impl Storable for UsageTotals {
+    const BOUND: Bound = Bound::Unbounded;
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(encode_versioned(self))
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_versioned(&bytes)
+    }
+}
+
+impl Versioned for UsageTotals {
+    const VERSION: u16 = 1;
+}
+
+#[derive(Clone, CandidType, Deserialize)]
+pub struct DailyUsage {
+    // Days since the Unix epoch (UTC).
+    pub day: u64,
+    pub totals: UsageTotals,
+}
+
+thread_local! {
+    // Keyed by (principal, n) for the principal's n-th call.
+    static USAGE_LEDGER: RefCell<StableBTreeMap<PrincipalSeqKey, UsageEntry, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(14)));
+        StableBTreeMap::init(memory)
+    });
+
+    // Aggregates kept up to date on every call, so reading them never scans
+    // the ledger.
+    static USER_USAGE: RefCell<StableBTreeMap<KeyPrincipal, UsageTotals, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(15)));
+        StableBTreeMap::init(memory)
+    });
+
+    static DAILY_USAGE: RefCell<StableBTreeMap<u64, UsageTotals, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(16)));
+        StableBTreeMap::init(memory)
+    });
+
+    static TEMPLATE_USAGE: RefCell<StableBTreeMap<KeyString, UsageTotals, VirtualMemory<CanisterMemory>>> = RefCell::new({
+        let memory = MEMORY_MANAGER.with(|mm| mm.borrow().get(MemoryId::new(17)));
+        StableBTreeMap::init(memory)
+    });
+}
+
+// Adds the outcalls of one job run to the ledger and the aggregates.
+pub fn record_job_usage(job: &Job, outcalls: &[Outcall]) {
+    let template_id = match &job.kind {
+        JobKind::Document { template_id, .. } => Some(template_id.clone()),
+        JobKind::Chat { .. } => None,
+    };
+    for outcall in outcalls {
+        let cost = outcall.cost.clone().unwrap_or_default();
+        let usage = outcall.usage.clone().unwrap_or_default();
+        record(UsageEntry {
+            principal: job.owner,
+            job_id: job.id,
+            template_id: template_id.clone(),
+            provider: outcall.provider.clone(),
+            model: outcall.model.clone(),
+            at: outcall.finished_at,
+            succeeded: outcall.error.is_none(),
+            cycles_attached: cost.cycles_attached,
+            request_bytes: cost.request_bytes,
+            response_bytes: cost.response_bytes,
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        });
+    }
+}
+
+fn record(entry: UsageEntry) {
+    let user_key = KeyPrincipal(entry.principal);
+    let mut user_totals = USER_USAGE.with(|usage| usage.borrow().get(&user_key)).unwrap_or_default();
+    let key = PrincipalSeqKey {
+        principal: entry.principal,
+        seq: user_totals.calls,
+    };
+    user_totals.add(&entry);
+    USER_USAGE.with(|usage| usage.borrow_mut().insert(user_key, user_totals));
+
+    let day = entry.at / NANOS_PER_DAY;
+    DAILY_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let mut totals = usage.get(&day).unwrap_or_default();
+        totals.add(&entry);
+        usage.insert(day, totals);
+    });
+
+    if let Some(template_id) = &entry.template_id {
+        let template_key = KeyString(template_id.clone());
+        TEMPLATE_USAGE.with(|usage| {
+            let mut usage = usage.borrow_mut();
+            let mut totals = usage.get(&template_key).unwrap_or_default();
+            totals.add(&entry);
+            usage.insert(template_key, totals);
+        });
+    }
+
+    USAGE_LEDGER.with(|ledger| ledger.borrow_mut().insert(key, entry));
+}
+
+pub fn user_totals(principal: Principal) -> UsageTotals {
+    USER_USAGE.with(|usage| usage.borrow().get(&KeyPrincipal(principal))).unwrap_or_default()
+}
+
+// The principal's ledger entries from its `start`-th call on.
+pub fn user_entries(principal: Principal, start: u64, limit: Option<u64>) -> Vec<UsageEntry> {
+    let limit = limit.unwrap_or(MAX_ENTRIES_PER_PAGE).min(MAX_ENTRIES_PER_PAGE) as usize;
+    let from = PrincipalSeqKey { principal, seq: start };
+    let to = PrincipalSeqKey { principal, seq: u64::MAX };
+    USAGE_LEDGER.with(|ledger| ledger.borrow().range(from..=to).take(limit).map(|(_, entry)| entry).collect())
+}
+
+pub fn daily_totals(from_day: u64, to_day: u64) -> Vec<DailyUsage> {
+    DAILY_USAGE.with(|usage| {
+        usage
+            .borrow()
+            .range(from_day..=to_day)
+            .map(|(day, totals)| DailyUsage { day, totals })
+            .collect()
+    })
+}
+
+pub fn template_totals() -> Vec<(String, UsageTotals)> {
+    TEMPLATE_USAGE.with(|usage| usage.borrow().iter().map(|(id, totals)| (id.0, totals)).collect())
+}
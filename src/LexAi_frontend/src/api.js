@@ -0,0 +1,68 @@
+// Helpers for the backend's `variant { Ok; Err: LexError }` results.
+
+export class LexError extends Error {
+  constructor(err) {
+    const [kind, detail] = Object.entries(err)[0];
+    super(describeError(kind, detail));
+    this.name = "LexError";
+    this.kind = kind;
+    this.detail = detail;
+  }
+}
+
+const describeError = (kind, detail) => {
+  switch (kind) {
+    case "NotFound":
+      return `${detail.resource} not found`;
+    case "Busy":
+      return `${detail.resource} is busy, please wait for the current request to finish`;
+    case "Unauthorized":
+      return "You are not allowed to access this resource";
+    case "Validation":
+      return detail.message;
+    case "UpstreamLlm":
+      return `The AI service failed: ${detail.reason}`;
+    case "QuotaExceeded":
+      return `Quota exceeded: ${detail.resource}`;
+    case "RateLimited":
+      return `Too many requests, please try again in ${detail.retry_after_secs} seconds`;
+    case "PaymentFailed":
+      return `Payment failed: ${detail.reason}`;
+    default:
+      return detail?.message || kind;
+  }
+};
+
+// Returns the `Ok` payload of a backend result or throws a `LexError`.
+export const unwrap = (result) => {
+  if ("Ok" in result) return result.Ok;
+  throw new LexError(result.Err);
+};
+
+const JOB_POLL_INTERVAL_MS = 1500;
+
+// Long enough for every retry the backend makes before it gives up on a job.
+const JOB_TIMEOUT_MS = 5 * 60 * 1000;
+
+// Thrown by `waitForJob` when a job is still unfinished at the deadline. The
+// job keeps running; its result can be fetched later with `get_job_status`.
+export class JobTimeoutError extends Error {
+  constructor(jobId) {
+    super("The request is taking longer than expected. Please check back later.");
+    this.name = "JobTimeoutError";
+    this.jobId = jobId;
+  }
+}
+
+// Polls a generation job until it finishes. Resolves with the job's result
+// reference, or throws the `LexError` it failed with or a `JobTimeoutError`.
+export const waitForJob = async (actor, jobId, timeoutMs = JOB_TIMEOUT_MS) => {
+  const deadline = Date.now() + timeoutMs;
+  for (;;) {
+    const job = unwrap(await actor.get_job_status(jobId));
+    if ("Succeeded" in job.status) return job.status.Succeeded.result;
+    if ("Failed" in job.status) throw new LexError(job.status.Failed.error);
+    if (Date.now() + JOB_POLL_INTERVAL_MS > deadline) throw new JobTimeoutError(jobId);
+    await new Promise((resolve) => setTimeout(resolve, JOB_POLL_INTERVAL_MS));
+  }
+};
@@ -1,5 +1,6 @@
 import React, {  useEffect } from 'react';
 import { useUserStore } from '../store';
+import { unwrap } from '../api';
 
 const network = import.meta.env.DFX_NETWORK || "local";
 const identityProvider =
@@ -67,7 +68,7 @@ const LoginButton = () => {
 
     try {
       console.log("📞 Calling get_or_register_user...");
-      const result = await actor.get_or_register_user();
+      const result = unwrap(await actor.get_or_register_user());
 
       console.log("✅ User registered/fetched:", result);
       return result;
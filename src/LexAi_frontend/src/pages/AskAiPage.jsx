@@ -4,6 +4,7 @@ import Sidebar from "../components/Sidebar";
 import ChatWindow from "../components/ChatWindow";
 import ChatInput from "../components/ChatInput";
 import { useUserStore } from "../store";
+import { unwrap, waitForJob } from "../api";
 
 const AskAiPage = () => {
   const [chats, setChats] = useState([]);
@@ -17,7 +18,7 @@ const AskAiPage = () => {
     const fetchSessions = async () => {
       if (!isAuthenticated || !actor) return;
       try {
-        const sessions = await actor.list_sessions();
+        const sessions = unwrap(await actor.list_sessions());
         const formattedChats = sessions.map(([id, title, created_at]) => ({
           id,
           title: title?.[0] || "Untitled Chat",
@@ -36,7 +37,7 @@ const AskAiPage = () => {
     const fetchMessages = async () => {
       if (!selectedChatId || !actor) return;
       try {
-        const messages = await actor.get_session_messages(selectedChatId);
+        const messages = unwrap(await actor.get_session_messages(selectedChatId));
         setChats((prev) =>
           prev.map((c) =>
             c.id === selectedChatId
@@ -63,7 +64,7 @@ const AskAiPage = () => {
 
     if (!chatId) {
       try {
-        chatId = await actor.start_session(["New Chat"]);
+        chatId = unwrap(await actor.start_session(["New Chat"]));
         const newChat = {
           id: chatId,
           title: "New Chat",
@@ -95,7 +96,12 @@ const AskAiPage = () => {
 
     setLoading(true);
     try {
-      const chatResponse = await actor.chat_in_session(chatId, message);
+      const jobId = unwrap(await actor.chat_in_session(chatId, message));
+      const { ChatReply: reply } = await waitForJob(actor, jobId);
+      const [replyMessage] = unwrap(
+        await actor.get_session_messages_page(chatId, reply.seq, [1n])
+      );
+      const chatResponse = replyMessage.content;
       setChats((prev) =>
         prev
           .map((c) =>
@@ -120,7 +126,7 @@ const AskAiPage = () => {
 
   const handleNewChat = async () => {
     try {
-      const sessionId = await actor.start_session(["New Chat"]);
+      const sessionId = unwrap(await actor.start_session(["New Chat"]));
       const newChat = {
         id: sessionId,
         title: "New Chat",
@@ -136,12 +142,10 @@ const AskAiPage = () => {
 
   const handleRenameChat = async (chatId, newTitle) => {
     try {
-      const success = await actor.rename_session(chatId, newTitle);
-      if (success) {
-        setChats((prev) =>
-          prev.map((c) => (c.id === chatId ? { ...c, title: newTitle } : c))
-        );
-      }
+      unwrap(await actor.rename_session(chatId, newTitle));
+      setChats((prev) =>
+        prev.map((c) => (c.id === chatId ? { ...c, title: newTitle } : c))
+      );
     } catch (error) {
       console.error("Error renaming session:", error);
     }
@@ -149,11 +153,9 @@ const AskAiPage = () => {
 
   const handleDeleteChat = async (chatId) => {
     try {
-      const success = await actor.delete_session(chatId);
-      if (success) {
-        setChats((prev) => prev.filter((c) => c.id !== chatId));
-        if (chatId === selectedChatId) setSelectedChatId(null);
-      }
+      unwrap(await actor.delete_session(chatId));
+      setChats((prev) => prev.filter((c) => c.id !== chatId));
+      if (chatId === selectedChatId) setSelectedChatId(null);
     } catch (error) {
       console.error("Error deleting session:", error);
     }
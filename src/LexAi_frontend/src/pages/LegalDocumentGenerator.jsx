@@ -1,6 +1,7 @@
 import React, { useState, useEffect } from 'react';
 import { ChevronDown, FileText, Download, Copy, Edit3, Save, Sparkles, CheckCircle, Loader2 } from 'lucide-react';
 import { useUserStore } from '../store';
+import { JobTimeoutError, LexError, unwrap, waitForJob } from '../api';
 import jsPDF from 'jspdf';
 import autoTable from 'jspdf-autotable';
 
@@ -75,7 +76,7 @@ const LegalDocumentGenerator = () => {
             { key: 'position', label: 'Position', placeholder: 'e.g., Software Engineer', type: 'text' },
             { key: 'duration', label: 'Duration', placeholder: 'e.g., Permanent', type: 'text' },
             { key: 'jurisdiction', label: 'Jurisdiction', placeholder: 'e.g., California', type: 'text' },
-            { key: 'salary', label: 'Salary', placeholder: 'e.g., $100,000/year', type: 'text' },
+            { key: 'salary', label: 'Salary', placeholder: 'e.g., $100,000', type: 'text' },
             { key: 'startDate', label: 'Start Date', placeholder: 'e.g., 2025-07-13', type: 'date' },
             { key: 'benefits', label: 'Benefits', placeholder: 'e.g., Health insurance, 401(k)', type: 'textarea' },
             { key: 'termination', label: 'Termination Conditions', placeholder: 'e.g., 30 days notice', type: 'text' }
@@ -108,7 +109,7 @@ const LegalDocumentGenerator = () => {
             { key: 'propertyAddress', label: 'Property Address', placeholder: 'e.g., 123 Main St, CA', type: 'text' },
             { key: 'duration', label: 'Duration', placeholder: 'e.g., 1 year', type: 'text' },
             { key: 'jurisdiction', label: 'Jurisdiction', placeholder: 'e.g., California', type: 'text' },
-            { key: 'rentAmount', label: 'Rent Amount', placeholder: 'e.g., $2000/month', type: 'text' },
+            { key: 'rentAmount', label: 'Rent Amount', placeholder: 'e.g., $2,000', type: 'text' },
             { key: 'startDate', label: 'Start Date', placeholder: 'e.g., 2025-07-13', type: 'date' },
             { key: 'securityDeposit', label: 'Security Deposit', placeholder: 'e.g., $4000', type: 'text' },
             { key: 'maintenance', label: 'Maintenance Terms', placeholder: 'e.g., Tenant responsible for utilities', type: 'textarea' }
@@ -160,7 +161,7 @@ const LegalDocumentGenerator = () => {
             try {
                 console.log('Fetching templates...');
                 const templates = await Promise.race([
-                    actor.list_templates(),
+                    actor.list_templates().then(unwrap),
                     new Promise((_, reject) => setTimeout(() => reject(new Error('Template fetch timeout')), 5000))
                 ]);
                 console.log('Templates received:', templates);
@@ -241,16 +242,15 @@ const LegalDocumentGenerator = () => {
 
             const fields = requiredFields.map(field => [field.key, formData[field.key]]);
             console.log('Generating document with template:', formData.documentType, 'fields:', fields);
-            const documentId = await actor.generate_document(formData.documentType, fields);
+            const jobId = unwrap(await actor.generate_document(formData.documentType, fields));
+            console.log('Generation job queued:', jobId);
+            const { Document: { document_id: documentId } } = await waitForJob(actor, jobId);
             console.log('Document ID received:', documentId);
 
             if (documentId && typeof documentId === 'string' && documentId.startsWith('doc_')) {
-                const documentText = await actor.get_document(documentId);
+                const documentText = unwrap(await actor.get_document(documentId));
                 console.log('Document text received:', documentText);
-                let text = documentText;
-                if (Array.isArray(documentText)) {
-                    text = documentText[0] || '';
-                }
+                const text = documentText;
                 if (text && typeof text === 'string') {
                     if (text.includes('[Specify]') || text.includes('Error')) {
                         setErrorMessage('Generated document is incomplete. Please try again or contact support.');
@@ -269,7 +269,9 @@ const LegalDocumentGenerator = () => {
             }
         } catch (error) {
             console.error('Error generating document:', error);
-            setErrorMessage('An error occurred while generating the document. Please try again.');
+            setErrorMessage(error instanceof LexError || error instanceof JobTimeoutError
+                ? error.message
+                : 'An error occurred while generating the document. Please try again.');
         }
         setIsGenerating(false);
     };
@@ -4,6 +4,7 @@ import UserDetails from "../components/UserDetails";
 import SessionList from "../components/SessionList";
 import DocumentList from "../components/DocumentList";
 import { useUserStore } from "../store";
+import { unwrap } from "../api";
 import dayjs from "dayjs";
 
 const ProfilePage = () => {
@@ -37,7 +38,7 @@ const ProfilePage = () => {
 
       try {
         // Fetch user details
-        const userData = await actor.get_or_register_user();
+        const userData = unwrap(await actor.get_or_register_user());
         setUser({
           principal: userData.principal.toString(),
           username: userData.username?.[0] || "",
@@ -48,7 +49,7 @@ const ProfilePage = () => {
         });
 
         // Fetch user sessions
-        const sessionData = await actor.list_sessions();
+        const sessionData = unwrap(await actor.list_sessions());
         setSessions(
           sessionData.map(([id, title, created_at]) => ({
             id,
@@ -61,12 +62,11 @@ const ProfilePage = () => {
         );
 
         // Fetch documents
-        const documentIds = await actor.list_documents();
+        const documentIds = unwrap(await actor.list_documents());
         const fetchedDocuments = [];
         for (const docId of documentIds) {
           const docContentResult = await actor.get_document(docId);
-          // Handle opt text (Candid returns an array: [string] or [])
-          const docContent = Array.isArray(docContentResult) && docContentResult.length > 0 ? docContentResult[0] : null;
+          const docContent = "Ok" in docContentResult ? docContentResult.Ok : null;
           if (docContent && typeof docContent === "string") {
             fetchedDocuments.push({
               id: docId,
@@ -95,7 +95,7 @@ const ProfilePage = () => {
         ? [updatedFields.username.trim()]
         : [];
       const email = updatedFields.email.trim() ? [updatedFields.email.trim()] : [];
-      await actor.update_profile(username, email);
+      unwrap(await actor.update_profile(username, email));
       setUser((prev) => ({
         ...prev,
         username: updatedFields.username,